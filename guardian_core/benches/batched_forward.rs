@@ -0,0 +1,48 @@
+//! Batched versus per-row forward passes with GuardianSettings::default()
+//!
+//! Per row is how the process stages ran the models before batching
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ndarray::{Array1, Array2, Axis};
+use ndarray_rand::RandomExt;
+use ndarray_rand::rand_distr::Uniform;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+use glib::GuardianSettings;
+use glib::cpu::interface::Genome;
+
+// Inputs of neuron_state_update
+const NEURON_STATE: usize = 0;
+const NODE: usize = 1;
+
+fn bench_batched_forward(c: &mut Criterion) {
+    let g_settings = GuardianSettings::default();
+    let mut rng = StdRng::seed_from_u64(1);
+    let genome = Genome::new(&g_settings, Some(rng.clone()));
+    let model = &genome.neuron_state_update;
+
+    let neuron_state = Array1::random_using(g_settings.neuron_state_size, Uniform::new(0.0, 1.0), &mut rng);
+    let precalculated = model.precalculate(NEURON_STATE, neuron_state.view());
+
+    let mut group = c.benchmark_group("neuron_state_update");
+    for batch_size in [g_settings.n_nodes_per_neuron, 64, 256] {
+        let nodes = Array2::random_using((batch_size, g_settings.node_size), Uniform::new(0.0, 1.0), &mut rng);
+        group.throughput(Throughput::Elements(batch_size as u64));
+        group.bench_with_input(BenchmarkId::new("per_row", batch_size), &nodes, |b, nodes| {
+            b.iter(|| {
+                nodes
+                    .outer_iter()
+                    .map(|node| model.forward_from_precalc(&[(NODE, node.insert_axis(Axis(0)))], &precalculated))
+                    .collect::<Vec<_>>()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("batched", batch_size), &nodes, |b, nodes| {
+            b.iter(|| model.forward_from_precalc(&[(NODE, nodes.view())], &precalculated))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_batched_forward);
+criterion_main!(benches);
@@ -0,0 +1,138 @@
+//! Each stage of cpu::process and Model::forward_from_precalc, per preset and thread count
+//! The quantized forward runs on the same inputs packed to u8
+//! zero_inputs runs the interconnected node model with a share of disconnected rows, where the other side is zero
+//!
+//! The stages run on a clone of a network that has already been updated a few steps, so connections are
+//! in every state and not only searching. Each run gets an empty PrecalcCache, as a step in process::update does.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use ndarray::Array2;
+use ndarray_rand::RandomExt;
+use ndarray_rand::rand_distr::Uniform;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use glib::cpu::interface::Network;
+use glib::cpu::pack_array;
+use glib::cpu::precalc::PrecalcCache;
+use glib::cpu::quantized::{PackedFormat, PackedInput};
+use glib::cpu::process::{self, interconnection_plasticity, interconnection_state, intraconnection_plasticity, intraconnection_state, neuron_state};
+use glib::cpu::stats::Stats;
+use glib::settings::{Settings, PRESETS};
+
+const THREAD_COUNTS: [usize; 2] = [1, 4];
+const WARMUP_STEPS: usize = 8;
+
+// Inputs of neuron_state_update
+const NEURON_STATE: usize = 0;
+const NODE: usize = 1;
+const FORMATS: [PackedFormat; 2] = [PackedFormat::Signed, PackedFormat::Signed];  // Deltas of the neuron state and the node
+
+// Inputs of interconnected_node_state_update
+const NEURON_STATE_OTHER: usize = 1;
+const NODE_STATE_SELF: usize = 2;
+const NODE_STATE_OTHER: usize = 3;
+
+type Stage = fn(&mut Network, &ThreadPool, &Stats, &PrecalcCache);
+
+fn warmed_up_network(preset: &str, pool: &ThreadPool) -> Network {
+    let settings = Settings::preset(preset).unwrap();
+    let mut network = Network::new(&settings.g_settings, &settings.n_settings, 1);
+    for _ in 0..WARMUP_STEPS {
+        process::update(&mut network, pool);
+    }
+    network
+}
+
+fn bench_stages(c: &mut Criterion) {
+    let stages: [(&str, Stage); 5] = [
+        ("interconnection_state", interconnection_state::update),
+        ("intraconnection_state", intraconnection_state::update),
+        ("neuron_state", neuron_state::update),
+        ("interconnection_plasticity", interconnection_plasticity::update),
+        ("intraconnection_plasticity", intraconnection_plasticity::update),
+    ];
+    for thread_count in THREAD_COUNTS {
+        let pool = ThreadPoolBuilder::new().num_threads(thread_count).build().unwrap();
+        for preset in PRESETS {
+            let network = warmed_up_network(preset, &pool);
+            for (name, stage) in stages {
+                let mut group = c.benchmark_group(name);
+                group.throughput(Throughput::Elements(network.n_settings.n_neurons as u64));
+                group.bench_function(BenchmarkId::new(preset, format!("{thread_count}_threads")), |b| {
+                    b.iter_batched_ref(
+                        || network.clone(),
+                        |network| {
+                            let cache = PrecalcCache::new(network.n_settings.n_neurons, network.g_settings.n_nodes_per_neuron);
+                            stage(network, &pool, &Stats::new(), &cache)
+                        },
+                        BatchSize::LargeInput,
+                    )
+                });
+                group.finish();
+            }
+        }
+    }
+}
+
+fn bench_forward_from_precalc(c: &mut Criterion) {
+    let mut group = c.benchmark_group("forward_from_precalc");
+    for preset in PRESETS {
+        let settings = Settings::preset(preset).unwrap();
+        let g_settings = &settings.g_settings;
+        let network = Network::new(g_settings, &settings.n_settings, 1);
+        let model = &network.genome.neuron_state_update;
+        let mut rng = StdRng::seed_from_u64(1);
+
+        let neuron_state = Array2::random_using((1, g_settings.neuron_state_size), Uniform::new(0.0, 1.0), &mut rng);
+        let precalculated = model.precalculate(NEURON_STATE, neuron_state.row(0));
+        let nodes = Array2::random_using((g_settings.n_nodes_per_neuron, g_settings.node_size), Uniform::new(0.0, 1.0), &mut rng);
+        group.throughput(Throughput::Elements(g_settings.n_nodes_per_neuron as u64));
+        group.bench_with_input(BenchmarkId::new("f32", preset), &nodes, |b, nodes| {
+            b.iter(|| model.forward_from_precalc(&[(NODE, nodes.view())], &precalculated))
+        });
+
+        let quantized = model.quantize();
+        let precalculated = quantized.precalculate(NEURON_STATE, pack_array(neuron_state.clone()).row(0));
+        let nodes = pack_array(nodes);
+        group.bench_with_input(BenchmarkId::new("int8", preset), &nodes, |b, nodes| {
+            b.iter(|| quantized.forward_from_precalc(&[(NODE, PackedInput::Unsigned(nodes.view()))], &precalculated, &FORMATS))
+        });
+    }
+    group.finish();
+}
+
+fn bench_zero_inputs(c: &mut Criterion) {
+    let settings = Settings::preset("default").unwrap();
+    let g_settings = &settings.g_settings;
+    let network = Network::new(g_settings, &settings.n_settings, 1);
+    let model = &network.genome.interconnected_node_state_update;
+    let mut rng = StdRng::seed_from_u64(1);
+
+    let batch_size = g_settings.n_nodes_per_neuron;
+    let precalculated = ndarray::Array1::zeros(g_settings.hidden_sizes[0]);
+    let nodes_self = Array2::random_using((batch_size, g_settings.node_size), Uniform::new(0.0, 1.0), &mut rng);
+    let mut group = c.benchmark_group("zero_inputs");
+    for percent_disconnected in [0, 50, 100] {
+        let mut neuron_states_other = Array2::random_using((batch_size, g_settings.neuron_state_size), Uniform::new(0.0, 1.0), &mut rng);
+        let mut nodes_other = Array2::random_using((batch_size, g_settings.node_size), Uniform::new(0.0, 1.0), &mut rng);
+        let n_disconnected = batch_size * percent_disconnected / 100;
+        neuron_states_other.slice_mut(ndarray::s![..n_disconnected, ..]).fill(0.0);
+        nodes_other.slice_mut(ndarray::s![..n_disconnected, ..]).fill(0.0);
+        group.bench_function(BenchmarkId::from_parameter(format!("{percent_disconnected}%")), |b| {
+            b.iter(|| {
+                let inputs = [
+                    (NEURON_STATE_OTHER, neuron_states_other.view()),
+                    (NODE_STATE_SELF, nodes_self.view()),
+                    (NODE_STATE_OTHER, nodes_other.view()),
+                ];
+                model.forward_from_precalc(&inputs, &precalculated)
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_stages, bench_forward_from_precalc, bench_zero_inputs);
+criterion_main!(benches);
@@ -0,0 +1,222 @@
+//! Binary checkpoint of a full network
+//!
+//! Layout:
+//! * Magic (4 bytes): "GRDN"
+//! * Version (u32, little endian)
+//! * Header length (u32, little endian)
+//! * Header (JSON): Version and settings. Human readable, can be inspected without loading the network
+//! * Body (bincode): Genome and state
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{GuardianSettings, NetworkSettings};
+use crate::cpu::compatibility::check_compatibility;
+use crate::cpu::interface::{Genome, Network, State};
+use crate::topology::NeighbourhoodCache;
+
+pub const CHECKPOINT_MAGIC: &[u8; 4] = b"GRDN";
+pub const CHECKPOINT_VERSION: u32 = 5;
+/// The header is only settings. Anything larger is not a checkpoint, and is refused before allocating
+pub const MAX_HEADER_LENGTH: usize = 1 << 20;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckpointHeader {
+    pub version: u32,
+    pub crate_version: String,
+    pub g_settings: GuardianSettings,
+    pub n_settings: NetworkSettings,
+}
+
+#[derive(Serialize)]
+struct CheckpointBodyRef<'a> {
+    genome: &'a Genome,
+    state: &'a State,
+}
+
+#[derive(Deserialize)]
+struct CheckpointBody {
+    genome: Genome,
+    state: State,
+}
+
+impl Network {
+    pub fn save_checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let file = File::create(path).with_context(|| format!("Unable to create checkpoint {path:?}"))?;
+        let mut writer = BufWriter::new(file);
+        write_checkpoint(self, &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// If g_settings is given, the checkpoint is refused if the genome is incompatible with it
+    pub fn load_checkpoint<P: AsRef<Path>>(path: P, g_settings: Option<&GuardianSettings>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Unable to open checkpoint {path:?}"))?;
+        read_checkpoint(&mut BufReader::new(file), g_settings)
+    }
+}
+
+pub fn write_checkpoint<W: Write>(network: &Network, writer: &mut W) -> Result<()> {
+    let header = CheckpointHeader {
+        version: CHECKPOINT_VERSION,
+        crate_version: env!("CARGO_PKG_VERSION").to_string(),
+        g_settings: network.g_settings.clone(),
+        n_settings: network.n_settings.clone(),
+    };
+    let header = serde_json::to_vec(&header)?;
+    writer.write_all(CHECKPOINT_MAGIC)?;
+    writer.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+    writer.write_all(&(header.len() as u32).to_le_bytes())?;
+    writer.write_all(&header)?;
+
+    let body = CheckpointBodyRef {
+        genome: &network.genome,
+        state: &network.state,
+    };
+    bincode::serialize_into(writer, &body)?;
+    Ok(())
+}
+
+/// Only reads the header. Useful for inspecting checkpoints without loading the whole network
+pub fn read_header<R: Read>(reader: &mut R) -> Result<CheckpointHeader> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).context("Checkpoint is too short")?;
+    ensure!(&magic == CHECKPOINT_MAGIC, "Not a checkpoint, magic bytes are {magic:?}");
+
+    let mut buffer = [0u8; 4];
+    reader.read_exact(&mut buffer)?;
+    let version = u32::from_le_bytes(buffer);
+    if version != CHECKPOINT_VERSION {
+        bail!("Unsupported checkpoint version {version}, expected {CHECKPOINT_VERSION}");
+    }
+
+    reader.read_exact(&mut buffer)?;
+    let header_length = u32::from_le_bytes(buffer) as usize;
+    ensure!(header_length <= MAX_HEADER_LENGTH, "Header length {header_length} is above the maximum {MAX_HEADER_LENGTH}");
+    let mut header = vec![0u8; header_length];
+    reader.read_exact(&mut header)?;
+    let header: CheckpointHeader = serde_json::from_slice(&header).context("Invalid checkpoint header")?;
+    ensure!(header.version == version, "Header version {} does not match file version {version}", header.version);
+    Ok(header)
+}
+
+pub fn read_checkpoint<R: Read>(reader: &mut R, g_settings: Option<&GuardianSettings>) -> Result<Network> {
+    let header = read_header(reader)?;
+
+    let body: CheckpointBody = bincode::deserialize_from(reader).context("Invalid checkpoint body")?;
+    check_state_shape(&body.state, &header.g_settings, &header.n_settings)?;
+    // Against the settings of the checkpoint if none are given
+    check_compatibility(&body.genome, g_settings.unwrap_or(&header.g_settings))?;
+    Ok(Network {
+        state: body.state,
+        genome: body.genome,
+        g_settings: header.g_settings,
+        n_settings: header.n_settings,
+        neighbourhood: NeighbourhoodCache::default(),
+    })
+}
+
+fn check_state_shape(state: &State, g_settings: &GuardianSettings, n_settings: &NetworkSettings) -> Result<()> {
+    let n_neurons = n_settings.n_neurons;
+    let n_nodes = g_settings.n_nodes_per_neuron;
+    ensure!(state.nodes.dim() == (n_neurons, n_nodes, g_settings.node_size), "Nodes have the wrong shape");
+    ensure!(state.neuron_states.dim() == (n_neurons, g_settings.neuron_state_size), "Neuron states have the wrong shape");
+    ensure!(state.inter_connections.dim() == (n_neurons, n_nodes), "Interconnections have the wrong shape");
+    ensure!(state.inter_connection_counters.dim() == (n_neurons, n_nodes), "Interconnection counters have the wrong shape");
+    let intra_shape = (n_neurons, n_nodes, g_settings.n_intraconnections_per_node);
+    ensure!(state.intra_connections.dim() == intra_shape, "Intraconnections have the wrong shape");
+    ensure!(state.intra_connection_counters.dim() == intra_shape, "Intraconnection counters have the wrong shape");
+    // An index outside of the network would panic in the first update
+    let n_nodes_total = n_neurons * n_nodes;
+    ensure!(
+        state.inter_connections.iter().all(|connection| connection.get_index() < n_nodes_total && connection.get_pending_index() < n_nodes_total),
+        "Interconnection to a node outside of the network"
+    );
+    ensure!(
+        state.intra_connections.iter().all(|connection| connection.get_index() < n_nodes && connection.get_pending_index() < n_nodes),
+        "Intraconnection to a node outside of the neuron"
+    );
+    let io_shape = (n_settings.n_io_ports, g_settings.io_size);
+    ensure!(state.io_ports.len() == n_settings.n_io_ports, "IO ports have the wrong shape");
+    ensure!(state.io_ports.iter().all(|node_global_index| *node_global_index < n_neurons * n_nodes), "IO port attached outside of the network");
+    ensure!(state.io_inputs.dim() == io_shape, "IO inputs have the wrong shape");
+    ensure!(state.io_outputs.dim() == io_shape, "IO outputs have the wrong shape");
+    let nexus_shape = (n_settings.n_network_ports, g_settings.nexus_size);
+    ensure!(state.nexus_ports.len() == n_settings.n_network_ports, "Network ports have the wrong shape");
+    ensure!(state.nexus_ports.iter().all(|neuron_index| *neuron_index < n_neurons), "Network port attached outside of the network");
+    ensure!(state.nexus_read.dim() == nexus_shape, "Nexus read has the wrong shape");
+    ensure!(state.nexus_write.dim() == nexus_shape, "Nexus write has the wrong shape");
+    ensure!(state.nexus_received.dim() == nexus_shape, "Nexus received has the wrong shape");
+    ensure!(state.modulators.len() == g_settings.modulator_size, "Modulators have the wrong shape");
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use crate::cpu::compatibility::IncompatibleGenome;
+    use super::*;
+
+    #[test]
+    pub fn test_checkpoint() {
+        let g_settings = GuardianSettings::downlevel_default();
+        let n_settings = NetworkSettings::downlevel_default();
+        let rng = StdRng::seed_from_u64(1);
+        let mut state = State::new(&g_settings, &n_settings);
+        state.randomize(&g_settings, &n_settings, Some(rng.clone()));
+        state.inter_connection_counters[[0, 1]].inc();
+        let network = Network {
+            state,
+            genome: Genome::new(&g_settings, Some(rng)),
+            g_settings: g_settings.clone(),
+            n_settings,
+            neighbourhood: NeighbourhoodCache::default(),
+        };
+
+        let mut buffer = vec![];
+        write_checkpoint(&network, &mut buffer).unwrap();
+        let loaded = read_checkpoint(&mut buffer.as_slice(), Some(&g_settings)).unwrap();
+        assert!(loaded.state == network.state);
+        assert!(loaded.genome == network.genome);
+        assert_eq!(loaded.g_settings, network.g_settings);
+        assert_eq!(loaded.n_settings, network.n_settings);
+
+        // Incompatible genome
+        let mut other_settings = g_settings.clone();
+        other_settings.node_size *= 2;
+        let error = read_checkpoint(&mut buffer.as_slice(), Some(&other_settings)).err().unwrap();
+        assert!(error.downcast_ref::<IncompatibleGenome>().is_some());
+
+        // Header length above the maximum
+        let mut too_long = buffer.clone();
+        too_long[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let error = read_checkpoint(&mut too_long.as_slice(), None).err().unwrap();
+        assert!(error.to_string().contains("maximum"), "{error}");
+
+        // Connections outside of the network
+        let corrupt = network.clone();
+        corrupt.state.inter_connections[[1, 2]].store_pending_index(network.n_settings.n_neurons * g_settings.n_nodes_per_neuron);
+        let mut corrupt_buffer = vec![];
+        write_checkpoint(&corrupt, &mut corrupt_buffer).unwrap();
+        let error = read_checkpoint(&mut corrupt_buffer.as_slice(), None).err().unwrap();
+        assert!(error.to_string().contains("outside of the network"), "{error}");
+        let mut corrupt = network.clone();
+        corrupt.state.intra_connections[[0, 1, 2]].index = g_settings.n_nodes_per_neuron as u16;
+        let mut corrupt_buffer = vec![];
+        write_checkpoint(&corrupt, &mut corrupt_buffer).unwrap();
+        let error = read_checkpoint(&mut corrupt_buffer.as_slice(), None).err().unwrap();
+        assert!(error.to_string().contains("outside of the neuron"), "{error}");
+
+        // Wrong version
+        buffer[4] += 1;
+        assert!(read_checkpoint(&mut buffer.as_slice(), None).is_err());
+    }
+}
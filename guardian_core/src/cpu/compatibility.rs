@@ -0,0 +1,176 @@
+//! Checks if a genome fits a GuardianSettings, and adapts it if it does not
+//!
+//! The input and output sizes of every model follow from the settings, see Genome::model_sizes. The hidden sizes
+//! are free, and io models that the settings do not know about are allowed.
+//!
+//! adapt_genome transfers a genome to other sizes, such as a larger node_size or neuron_state_size. Weights of new
+//! values are 0 and weights of removed values are dropped, so the adapted genome starts out behaving like the old one
+//! on the values both have.
+
+use std::fmt;
+
+use anyhow::{ensure, Context, Result};
+
+use crate::GuardianSettings;
+use crate::cpu::interface::Genome;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    MissingModel { model: String },
+    InputCount { model: String, expected: usize, actual: usize },
+    InputSize { model: String, input_index: usize, expected: usize, actual: usize },
+    OutputCount { model: String, expected: usize, actual: usize },
+    OutputSize { model: String, output_index: usize, expected: usize, actual: usize },
+}
+
+impl Mismatch {
+    /// Only the sizes differ, which adapt_genome can fix
+    pub fn is_adaptable(&self) -> bool {
+        matches!(self, Mismatch::InputSize { .. } | Mismatch::OutputSize { .. })
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::MissingModel { model } => write!(f, "{model} is missing"),
+            Mismatch::InputCount { model, expected, actual } => write!(f, "{model} has {actual} inputs, expected {expected}"),
+            Mismatch::InputSize { model, input_index, expected, actual } => {
+                write!(f, "{model} input {input_index} has size {actual}, expected {expected}")
+            },
+            Mismatch::OutputCount { model, expected, actual } => write!(f, "{model} has {actual} outputs, expected {expected}"),
+            Mismatch::OutputSize { model, output_index, expected, actual } => {
+                write!(f, "{model} output {output_index} has size {actual}, expected {expected}")
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncompatibleGenome(pub Vec<Mismatch>);
+
+impl fmt::Display for IncompatibleGenome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Incompatible genome:")?;
+        for mismatch in self.0.iter() {
+            writeln!(f, "* {mismatch}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for IncompatibleGenome {}
+
+pub fn check_compatibility(genome: &Genome, g_settings: &GuardianSettings) -> Result<(), IncompatibleGenome> {
+    let models = genome.models();
+    let mut mismatches = vec![];
+    for sizes in Genome::model_sizes(g_settings) {
+        let model = sizes.name;
+        let Some((_, found)) = models.iter().find(|(name, _)| *name == model) else {
+            mismatches.push(Mismatch::MissingModel { model });
+            continue;
+        };
+        let settings = found.settings();
+
+        if settings.input_sizes.len() != sizes.input_sizes.len() {
+            mismatches.push(Mismatch::InputCount { model: model.clone(), expected: sizes.input_sizes.len(), actual: settings.input_sizes.len() });
+        } else {
+            for (input_index, (expected, actual)) in sizes.input_sizes.iter().zip(settings.input_sizes.iter()).enumerate() {
+                if expected != actual {
+                    mismatches.push(Mismatch::InputSize { model: model.clone(), input_index, expected: *expected, actual: *actual });
+                }
+            }
+        }
+
+        if settings.output_sizes.len() != sizes.output_sizes.len() {
+            mismatches.push(Mismatch::OutputCount { model, expected: sizes.output_sizes.len(), actual: settings.output_sizes.len() });
+        } else {
+            for (output_index, (expected, actual)) in sizes.output_sizes.iter().zip(settings.output_sizes.iter()).enumerate() {
+                if expected != actual {
+                    mismatches.push(Mismatch::OutputSize { model: model.clone(), output_index, expected: *expected, actual: *actual });
+                }
+            }
+        }
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(IncompatibleGenome(mismatches))
+    }
+}
+
+/// Pads or truncates the models to the sizes of the settings. Fails if a model is missing or has another number of
+/// inputs or outputs, since then it is not known which input is which
+pub fn adapt_genome(genome: &Genome, g_settings: &GuardianSettings) -> Result<Genome> {
+    if let Err(incompatible) = check_compatibility(genome, g_settings) {
+        let unadaptable = incompatible.0.into_iter().filter(|mismatch| !mismatch.is_adaptable()).collect::<Vec<_>>();
+        ensure!(unadaptable.is_empty(), IncompatibleGenome(unadaptable));
+    }
+
+    let mut adapted = genome.clone();
+    let model_sizes = Genome::model_sizes(g_settings);
+    for (name, model) in adapted.models_mut() {
+        // Extra io models are kept as they are
+        let Some(sizes) = model_sizes.iter().find(|sizes| sizes.name == name) else { continue };
+        *model = model.resize(&sizes.input_sizes, &sizes.output_sizes).with_context(|| format!("Unable to resize {name}"))?;
+    }
+    Ok(adapted)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use rayon::ThreadPoolBuilder;
+
+    use crate::NetworkSettings;
+    use crate::cpu::interface::{Network, State, NEURON_STATE_MODEL};
+    use crate::cpu::process::update;
+    use crate::topology::NeighbourhoodCache;
+    use super::*;
+
+    #[test]
+    pub fn test_compatibility() {
+        let mut g_settings = GuardianSettings::downlevel_default();
+        g_settings.hidden_sizes = vec![8];
+        let genome = Genome::new(&g_settings, Some(StdRng::seed_from_u64(1)));
+        assert!(check_compatibility(&genome, &g_settings).is_ok());
+
+        // Other hidden sizes are fine
+        let mut other_settings = g_settings.clone();
+        other_settings.hidden_sizes = vec![4, 4];
+        assert!(check_compatibility(&genome, &other_settings).is_ok());
+
+        let mut larger_settings = g_settings.clone();
+        larger_settings.node_size *= 2;
+        larger_settings.neuron_state_size *= 2;
+        let incompatible = check_compatibility(&genome, &larger_settings).unwrap_err();
+        assert!(incompatible.0.iter().all(Mismatch::is_adaptable));
+        assert!(incompatible.0.contains(&Mismatch::InputSize {
+            model: NEURON_STATE_MODEL.to_string(),
+            input_index: 1,
+            expected: larger_settings.node_size,
+            actual: g_settings.node_size
+        }));
+
+        // Grows to the larger network and runs there
+        let adapted = adapt_genome(&genome, &larger_settings).unwrap();
+        assert!(check_compatibility(&adapted, &larger_settings).is_ok());
+        let mut n_settings = NetworkSettings::downlevel_default();
+        n_settings.n_neurons = 4;
+        let mut state = State::new(&larger_settings, &n_settings);
+        state.randomize(&larger_settings, &n_settings, Some(StdRng::seed_from_u64(1)));
+        let mut network = Network { state, genome: adapted.clone(), g_settings: larger_settings.clone(), n_settings, neighbourhood: NeighbourhoodCache::default() };
+        update(&mut network, &ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+
+        // Shrinking back gives the original
+        assert!(adapt_genome(&adapted, &g_settings).unwrap() == genome);
+
+        // Another number of inputs can not be adapted
+        let mut broken = genome.clone();
+        broken.neuron_state_update = broken.interconnected_node_state_update.clone();
+        let error = adapt_genome(&broken, &larger_settings).err().unwrap();
+        assert!(error.downcast_ref::<IncompatibleGenome>().unwrap().0.iter().all(|mismatch| !mismatch.is_adaptable()));
+    }
+}
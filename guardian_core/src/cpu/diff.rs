@@ -0,0 +1,198 @@
+//! Differences between two states
+//!
+//! Each array is compared element by element in logical order. Changed elements are stored as runs of
+//! consecutive values, where each run starts at an offset from the end of the previous run. `to_bytes` uses varints,
+//! so most offsets take one byte.
+//!
+//! The diff of one step is not small. An update changes most node and neuron states and the forces of most
+//! connections, so the runs cover most of these arrays and the diff is more than half the size of the state. Only
+//! the arrays that rarely change, such as the ports and the modulators, are left out.
+
+use anyhow::{ensure, Context, Result};
+use bincode::Options;
+use ndarray::{Array, Dimension};
+use serde::{Deserialize, Serialize};
+
+use super::interface::{CounterInterConnection, CounterIntraConnection, InterConnection, IntraConnection, State};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Run<T> {
+    pub offset: usize,  // Elements skipped since the end of the previous run
+    pub values: Vec<T>,
+}
+
+/// Changed elements of one array
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArrayDiff<T> {
+    pub len: usize,  // Number of elements in the array, checked when applying
+    pub runs: Vec<Run<T>>,
+}
+
+impl<T: Clone + PartialEq> ArrayDiff<T> {
+    pub fn compute<D: Dimension>(from: &Array<T, D>, to: &Array<T, D>) -> Result<Self> {
+        ensure!(from.shape() == to.shape(), "Shapes differ: {:?} and {:?}", from.shape(), to.shape());
+        let mut runs: Vec<Run<T>> = vec![];
+        let mut end = 0;  // End of the previous run
+        let mut in_run = false;
+        for (index, (value_from, value_to)) in from.iter().zip(to.iter()).enumerate() {
+            if value_from == value_to {
+                in_run = false;
+                continue;
+            }
+            if in_run {
+                runs.last_mut().unwrap().values.push(value_to.clone());
+            } else {
+                runs.push(Run { offset: index - end, values: vec![value_to.clone()] });
+                in_run = true;
+            }
+            end = index + 1;
+        }
+        Ok(Self { len: from.len(), runs })
+    }
+
+    pub fn apply<D: Dimension>(&self, array: &mut Array<T, D>) -> Result<()> {
+        ensure!(array.len() == self.len, "Expected {} elements, got {}", self.len, array.len());
+        let values = array.as_slice_mut().context("The array is not in standard layout")?;
+        let mut position = 0;
+        for run in self.runs.iter() {
+            let start = position + run.offset;
+            let end = start + run.values.len();
+            ensure!(end <= values.len(), "Run {start}..{end} is out of bounds for {} elements", values.len());
+            values[start..end].clone_from_slice(&run.values);
+            position = end;
+        }
+        Ok(())
+    }
+
+    pub fn n_changed(&self) -> usize {
+        self.runs.iter().map(|run| run.values.len()).sum()
+    }
+}
+
+/// Everything that changed from one state to another
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StateDiff {
+    pub nodes: ArrayDiff<u8>,
+    pub neuron_states: ArrayDiff<u8>,
+    pub inter_connections: ArrayDiff<InterConnection>,
+    pub intra_connections: ArrayDiff<IntraConnection>,
+    pub intra_connection_counters: ArrayDiff<CounterIntraConnection>,
+    pub inter_connection_counters: ArrayDiff<CounterInterConnection>,
+
+    pub io_ports: ArrayDiff<usize>,
+    pub io_inputs: ArrayDiff<u8>,
+    pub io_outputs: ArrayDiff<u8>,
+
+    pub nexus_ports: ArrayDiff<usize>,
+    pub nexus_read: ArrayDiff<u8>,
+    pub nexus_write: ArrayDiff<u8>,
+    pub nexus_received: ArrayDiff<u8>,
+
+    pub modulators: ArrayDiff<i8>,
+}
+
+impl StateDiff {
+    /// Both states must be from the same settings
+    pub fn compute(from: &State, to: &State) -> Result<Self> {
+        Ok(Self {
+            nodes: ArrayDiff::compute(&from.nodes, &to.nodes).context("nodes")?,
+            neuron_states: ArrayDiff::compute(&from.neuron_states, &to.neuron_states).context("neuron_states")?,
+            inter_connections: ArrayDiff::compute(&from.inter_connections, &to.inter_connections).context("inter_connections")?,
+            intra_connections: ArrayDiff::compute(&from.intra_connections, &to.intra_connections).context("intra_connections")?,
+            intra_connection_counters: ArrayDiff::compute(&from.intra_connection_counters, &to.intra_connection_counters)
+                .context("intra_connection_counters")?,
+            inter_connection_counters: ArrayDiff::compute(&from.inter_connection_counters, &to.inter_connection_counters)
+                .context("inter_connection_counters")?,
+            io_ports: ArrayDiff::compute(&from.io_ports, &to.io_ports).context("io_ports")?,
+            io_inputs: ArrayDiff::compute(&from.io_inputs, &to.io_inputs).context("io_inputs")?,
+            io_outputs: ArrayDiff::compute(&from.io_outputs, &to.io_outputs).context("io_outputs")?,
+            nexus_ports: ArrayDiff::compute(&from.nexus_ports, &to.nexus_ports).context("nexus_ports")?,
+            nexus_read: ArrayDiff::compute(&from.nexus_read, &to.nexus_read).context("nexus_read")?,
+            nexus_write: ArrayDiff::compute(&from.nexus_write, &to.nexus_write).context("nexus_write")?,
+            nexus_received: ArrayDiff::compute(&from.nexus_received, &to.nexus_received).context("nexus_received")?,
+            modulators: ArrayDiff::compute(&from.modulators, &to.modulators).context("modulators")?,
+        })
+    }
+
+    /// Turns the state the diff was computed from into the state it was computed to
+    pub fn apply(&self, state: &mut State) -> Result<()> {
+        self.nodes.apply(&mut state.nodes).context("nodes")?;
+        self.neuron_states.apply(&mut state.neuron_states).context("neuron_states")?;
+        self.inter_connections.apply(&mut state.inter_connections).context("inter_connections")?;
+        self.intra_connections.apply(&mut state.intra_connections).context("intra_connections")?;
+        self.intra_connection_counters.apply(&mut state.intra_connection_counters).context("intra_connection_counters")?;
+        self.inter_connection_counters.apply(&mut state.inter_connection_counters).context("inter_connection_counters")?;
+        self.io_ports.apply(&mut state.io_ports).context("io_ports")?;
+        self.io_inputs.apply(&mut state.io_inputs).context("io_inputs")?;
+        self.io_outputs.apply(&mut state.io_outputs).context("io_outputs")?;
+        self.nexus_ports.apply(&mut state.nexus_ports).context("nexus_ports")?;
+        self.nexus_read.apply(&mut state.nexus_read).context("nexus_read")?;
+        self.nexus_write.apply(&mut state.nexus_write).context("nexus_write")?;
+        self.nexus_received.apply(&mut state.nexus_received).context("nexus_received")?;
+        self.modulators.apply(&mut state.modulators).context("modulators")?;
+        Ok(())
+    }
+
+    /// Number of changed elements over all arrays
+    pub fn n_changed(&self) -> usize {
+        self.nodes.n_changed()
+        + self.neuron_states.n_changed()
+        + self.inter_connections.n_changed()
+        + self.intra_connections.n_changed()
+        + self.intra_connection_counters.n_changed()
+        + self.inter_connection_counters.n_changed()
+        + self.io_ports.n_changed()
+        + self.io_inputs.n_changed()
+        + self.io_outputs.n_changed()
+        + self.nexus_ports.n_changed()
+        + self.nexus_read.n_changed()
+        + self.nexus_write.n_changed()
+        + self.nexus_received.n_changed()
+        + self.modulators.n_changed()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n_changed() == 0
+    }
+
+    /// Bincode with varint encoding, offsets and small indices take one byte
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::DefaultOptions::new().serialize(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(bincode::DefaultOptions::new().deserialize(bytes)?)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rayon::ThreadPoolBuilder;
+
+    use crate::cpu::interface::Network;
+    use crate::cpu::process::update;
+    use crate::settings::Settings;
+    use super::*;
+
+    #[test]
+    pub fn test_state_diff() {
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let settings = Settings::preset("tiny").unwrap();
+        let mut network = Network::new(&settings.g_settings, &settings.n_settings, 1);
+        assert!(StateDiff::compute(&network.state, &network.state).unwrap().is_empty());
+
+        let mut replay = network.state.clone();
+        for _ in 0..4 {
+            let previous = network.state.clone();
+            update(&mut network, &pool);
+            let diff = StateDiff::compute(&previous, &network.state).unwrap();
+            let bytes = diff.to_bytes().unwrap();
+            // Most of the states and connections change, so the diff is smaller than the state but not by much
+            let state_size = bincode::serialize(&network.state).unwrap().len();
+            assert!(bytes.len() < state_size);
+            assert!(2 * bytes.len() > state_size, "{} bytes for a state of {state_size}", bytes.len());
+            StateDiff::from_bytes(&bytes).unwrap().apply(&mut replay).unwrap();
+            assert_eq!(replay, network.state);
+        }
+    }
+}
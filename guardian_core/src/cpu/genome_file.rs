@@ -0,0 +1,172 @@
+//! Genomes saved on their own, without a state, so they can be shared and loaded into any network of the same sizes
+//!
+//! Two formats, detected when reading:
+//! * Binary: Magic "GRDG", version (u32, little endian), header length (u32, little endian), JSON header and the
+//!   genome as bincode. Same layout as a checkpoint
+//! * JSON: One object with the header fields and the genome. Human readable, but several times larger
+//!
+//! The header has the GuardianSettings the genome was built for, since the sizes of the models depend on them.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::GuardianSettings;
+use crate::cpu::checkpoint::MAX_HEADER_LENGTH;
+use crate::cpu::interface::Genome;
+
+pub const GENOME_MAGIC: &[u8; 4] = b"GRDG";
+pub const GENOME_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenomeFormat {
+    Binary,
+    Json,
+}
+
+impl GenomeFormat {
+    /// JSON for .json, otherwise binary
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => GenomeFormat::Json,
+            _ => GenomeFormat::Binary,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenomeHeader {
+    pub version: u32,
+    pub crate_version: String,
+    pub g_settings: GuardianSettings,
+}
+
+#[derive(Serialize)]
+struct GenomeFileRef<'a> {
+    #[serde(flatten)]
+    header: &'a GenomeHeader,
+    genome: &'a Genome,
+}
+
+#[derive(Deserialize)]
+struct GenomeFile {
+    #[serde(flatten)]
+    header: GenomeHeader,
+    genome: Genome,
+}
+
+impl Genome {
+    /// The format is decided by the extension, see GenomeFormat::from_path
+    pub fn save<P: AsRef<Path>>(&self, path: P, g_settings: &GuardianSettings) -> Result<()> {
+        let path = path.as_ref();
+        let file = File::create(path).with_context(|| format!("Unable to create genome {path:?}"))?;
+        let mut writer = BufWriter::new(file);
+        write_genome(self, g_settings, &mut writer, GenomeFormat::from_path(path))?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Either format. Returns the settings the genome was built for
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<(Self, GuardianSettings)> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Unable to open genome {path:?}"))?;
+        let (header, genome) = read_genome(&mut BufReader::new(file)).with_context(|| format!("Unable to load genome {path:?}"))?;
+        Ok((genome, header.g_settings))
+    }
+}
+
+pub fn write_genome<W: Write>(genome: &Genome, g_settings: &GuardianSettings, writer: &mut W, format: GenomeFormat) -> Result<()> {
+    let header = GenomeHeader {
+        version: GENOME_VERSION,
+        crate_version: env!("CARGO_PKG_VERSION").to_string(),
+        g_settings: g_settings.clone(),
+    };
+    match format {
+        GenomeFormat::Binary => {
+            let header = serde_json::to_vec(&header)?;
+            writer.write_all(GENOME_MAGIC)?;
+            writer.write_all(&GENOME_VERSION.to_le_bytes())?;
+            writer.write_all(&(header.len() as u32).to_le_bytes())?;
+            writer.write_all(&header)?;
+            bincode::serialize_into(writer, genome)?;
+        },
+        GenomeFormat::Json => {
+            serde_json::to_writer_pretty(writer, &GenomeFileRef { header: &header, genome })?;
+        },
+    }
+    Ok(())
+}
+
+/// Either format, decided by the first bytes
+pub fn read_genome<R: Read>(reader: &mut R) -> Result<(GenomeHeader, Genome)> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic).context("Genome is too short")?;
+    if &magic != GENOME_MAGIC {
+        let file: GenomeFile = serde_json::from_reader(magic.as_slice().chain(reader)).context("Neither a binary nor a JSON genome")?;
+        check_version(file.header.version)?;
+        return Ok((file.header, file.genome));
+    }
+
+    let mut buffer = [0u8; 4];
+    reader.read_exact(&mut buffer)?;
+    let version = u32::from_le_bytes(buffer);
+    check_version(version)?;
+    reader.read_exact(&mut buffer)?;
+    let header_length = u32::from_le_bytes(buffer) as usize;
+    ensure!(header_length <= MAX_HEADER_LENGTH, "Header length {header_length} is above the maximum {MAX_HEADER_LENGTH}");
+    let mut header = vec![0u8; header_length];
+    reader.read_exact(&mut header)?;
+    let header: GenomeHeader = serde_json::from_slice(&header).context("Invalid genome header")?;
+    ensure!(header.version == version, "Header version {} does not match file version {version}", header.version);
+    let genome = bincode::deserialize_from(reader).context("Invalid genome body")?;
+    Ok((header, genome))
+}
+
+fn check_version(version: u32) -> Result<()> {
+    if version != GENOME_VERSION {
+        bail!("Unsupported genome version {version}, expected {GENOME_VERSION}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::*;
+
+    #[test]
+    pub fn test_genome_file() {
+        let mut g_settings = GuardianSettings::downlevel_default();
+        g_settings.hidden_sizes = vec![8];
+        let genome = Genome::new(&g_settings, Some(StdRng::seed_from_u64(1)));
+
+        let mut sizes = vec![];
+        for format in [GenomeFormat::Binary, GenomeFormat::Json] {
+            let mut buffer = vec![];
+            write_genome(&genome, &g_settings, &mut buffer, format).unwrap();
+            let (header, loaded) = read_genome(&mut buffer.as_slice()).unwrap();
+            assert!(loaded == genome);
+            assert_eq!(header.g_settings, g_settings);
+            sizes.push(buffer.len());
+        }
+        assert!(sizes[0] < sizes[1]);
+
+        // Header length above the maximum
+        let mut buffer = vec![];
+        write_genome(&genome, &g_settings, &mut buffer, GenomeFormat::Binary).unwrap();
+        let mut too_long = buffer.clone();
+        too_long[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let error = read_genome(&mut too_long.as_slice()).err().unwrap();
+        assert!(error.to_string().contains("maximum"), "{error}");
+
+        // Wrong version
+        buffer[4] += 1;
+        assert!(read_genome(&mut buffer.as_slice()).is_err());
+        assert!(read_genome(&mut b"not a genome".as_slice()).is_err());
+    }
+}
@@ -0,0 +1,250 @@
+//! Adding and removing neurons at runtime
+//!
+//! New neurons are appended at the end, so no index changes. Their nodes start unconnected: the interconnections
+//! point to the node itself with the lowest forces, so they search around themselves.
+//!
+//! Removing neurons moves the remaining neurons down, and every global node index is remapped:
+//! * A main interconnection to a removed node is disconnected, as above, and its counter is reset
+//! * A pending interconnection to a removed node is reset to the main one, and its counter is reset
+//! * Io ports and network ports on a removed neuron move to the next remaining neuron, on the same local node
+//!
+//! Intraconnections are local to a neuron and are kept as they are.
+
+use std::ops::Range;
+
+use anyhow::{ensure, Result};
+use ndarray::{concatenate, Array1, Array2, Array3, Axis};
+
+use crate::GuardianSettings;
+use crate::cpu::interface::{CounterInterConnection, CounterIntraConnection, InterConnection, IntraConnection, Network, State};
+use crate::cpu::{node_global_to_local_index, node_local_to_global_index};
+
+impl State {
+    pub fn n_neurons(&self) -> usize {
+        self.neuron_states.shape()[0]
+    }
+
+    /// Appends unconnected neurons. Returns the indices of the new neurons
+    pub fn add_neurons(&mut self, n_new: usize, g_settings: &GuardianSettings) -> Range<usize> {
+        let n_neurons = self.n_neurons();
+        let n_nodes = g_settings.n_nodes_per_neuron;
+        let n_intra = g_settings.n_intraconnections_per_node;
+
+        // Same start as State::new
+        let nodes = Array3::ones((n_new, n_nodes, g_settings.node_size));
+        let neuron_states = Array2::ones((n_new, g_settings.neuron_state_size));
+        let inter_connections = Array2::from_shape_fn((n_new, n_nodes), |(new_index, node_local_index)| {
+            let connection = InterConnection::default();
+            disconnect(&connection, node_local_to_global_index(n_neurons + new_index, node_local_index, g_settings));
+            connection
+        });
+        let intra_connections = Array3::from_elem((n_new, n_nodes, n_intra), IntraConnection::default());
+        let inter_connection_counters = Array2::from_shape_fn((n_new, n_nodes), |_| CounterInterConnection::new());
+        let intra_connection_counters = Array3::from_shape_fn((n_new, n_nodes, n_intra), |_| CounterIntraConnection::new());
+
+        self.nodes = concatenate![Axis(0), self.nodes, nodes];
+        self.neuron_states = concatenate![Axis(0), self.neuron_states, neuron_states];
+        self.inter_connections = concatenate![Axis(0), self.inter_connections, inter_connections];
+        self.intra_connections = concatenate![Axis(0), self.intra_connections, intra_connections];
+        self.inter_connection_counters = concatenate![Axis(0), self.inter_connection_counters, inter_connection_counters];
+        self.intra_connection_counters = concatenate![Axis(0), self.intra_connection_counters, intra_connection_counters];
+        n_neurons..n_neurons + n_new
+    }
+
+    /// Removes the neurons and remaps the indices of the rest. Returns the new index of every old neuron, None if removed
+    pub fn remove_neurons(&mut self, neuron_indices: &[usize], g_settings: &GuardianSettings) -> Result<Vec<Option<usize>>> {
+        let n_neurons = self.n_neurons();
+        let n_nodes = g_settings.n_nodes_per_neuron;
+        let mut removed = vec![false; n_neurons];
+        for neuron_index in neuron_indices {
+            ensure!(*neuron_index < n_neurons, "Neuron {neuron_index} does not exist, the network has {n_neurons} neurons");
+            removed[*neuron_index] = true;
+        }
+        let kept: Vec<usize> = (0..n_neurons).filter(|neuron_index| !removed[*neuron_index]).collect();
+        ensure!(!kept.is_empty(), "At least one neuron must be kept");
+
+        let mut remap = vec![None; n_neurons];
+        for (new_index, old_index) in kept.iter().enumerate() {
+            remap[*old_index] = Some(new_index);
+        }
+        let remap_node = |node_global_index: usize| -> Option<usize> {
+            let (neuron_index, node_local_index) = node_global_to_local_index(node_global_index, g_settings);
+            remap[neuron_index].map(|new_index| node_local_to_global_index(new_index, node_local_index, g_settings))
+        };
+
+        self.nodes = self.nodes.select(Axis(0), &kept);
+        self.neuron_states = self.neuron_states.select(Axis(0), &kept);
+        self.inter_connections = self.inter_connections.select(Axis(0), &kept);
+        self.intra_connections = self.intra_connections.select(Axis(0), &kept);
+        self.inter_connection_counters = self.inter_connection_counters.select(Axis(0), &kept);
+        self.intra_connection_counters = self.intra_connection_counters.select(Axis(0), &kept);
+
+        for ((neuron_index, node_local_index), connection) in self.inter_connections.indexed_iter() {
+            match remap_node(connection.get_index()) {
+                Some(index) => connection.store_index(index),
+                None => {
+                    disconnect(connection, node_local_to_global_index(neuron_index, node_local_index, g_settings));
+                    self.inter_connection_counters[[neuron_index, node_local_index]].reset();
+                    continue;
+                }
+            }
+            match remap_node(connection.get_pending_index()) {
+                Some(index) => connection.store_pending_index(index),
+                None => {
+                    connection.reset_pending();
+                    self.inter_connection_counters[[neuron_index, node_local_index]].reset();
+                },
+            }
+        }
+
+        // Ports on a removed neuron go to the next one that is kept
+        let next_kept = |neuron_index: usize| -> usize {
+            (0..n_neurons)
+                .map(|offset| (neuron_index + offset) % n_neurons)
+                .find_map(|neuron_index| remap[neuron_index])
+                .unwrap()
+        };
+        self.io_ports = Array1::from_iter(self.io_ports.iter().map(|node_global_index| {
+            let (neuron_index, node_local_index) = node_global_to_local_index(*node_global_index, g_settings);
+            node_local_to_global_index(next_kept(neuron_index), node_local_index, g_settings)
+        }));
+        self.nexus_ports = self.nexus_ports.mapv(next_kept);
+
+        debug_assert!(self.inter_connections.iter().all(|connection| connection.get_index() < kept.len() * n_nodes));
+        Ok(remap)
+    }
+
+    /// Neurons where no node has a mutual interconnection with another neuron, and no port is attached
+    pub fn dead_neurons(&self, g_settings: &GuardianSettings) -> Vec<usize> {
+        let mut alive = vec![false; self.n_neurons()];
+        for ((neuron_index, node_local_index), connection) in self.inter_connections.indexed_iter() {
+            let node_global_index_self = node_local_to_global_index(neuron_index, node_local_index, g_settings);
+            let (neuron_index_other, node_local_index_other) = node_global_to_local_index(connection.get_index(), g_settings);
+            if neuron_index_other != neuron_index
+                && self.inter_connections[[neuron_index_other, node_local_index_other]].get_index() == node_global_index_self
+            {
+                alive[neuron_index] = true;
+            }
+        }
+        for node_global_index in self.io_ports.iter() {
+            alive[node_global_to_local_index(*node_global_index, g_settings).0] = true;
+        }
+        for neuron_index in self.nexus_ports.iter() {
+            alive[*neuron_index] = true;
+        }
+        (0..self.n_neurons()).filter(|neuron_index| !alive[*neuron_index]).collect()
+    }
+}
+
+impl Network {
+    /// Fails if the settings would be invalid with the new number of neurons, such as a grid topology
+    pub fn add_neurons(&mut self, n_new: usize) -> Result<Range<usize>> {
+        self.resized_settings(self.n_settings.n_neurons + n_new)?;
+        let neuron_indices = self.state.add_neurons(n_new, &self.g_settings);
+        self.n_settings.n_neurons = self.state.n_neurons();
+        Ok(neuron_indices)
+    }
+
+    /// See State::remove_neurons
+    pub fn remove_neurons(&mut self, neuron_indices: &[usize]) -> Result<Vec<Option<usize>>> {
+        let mut unique = neuron_indices.to_vec();
+        unique.sort();
+        unique.dedup();
+        self.resized_settings(self.n_settings.n_neurons.saturating_sub(unique.len()))?;
+        let remap = self.state.remove_neurons(&unique, &self.g_settings)?;
+        self.n_settings.n_neurons = self.state.n_neurons();
+        Ok(remap)
+    }
+
+    /// Removes the dead neurons, see State::dead_neurons. Returns how many were removed
+    pub fn prune_dead_neurons(&mut self) -> Result<usize> {
+        let dead = self.state.dead_neurons(&self.g_settings);
+        if dead.is_empty() || dead.len() == self.n_settings.n_neurons {
+            return Ok(0);  // Nothing to prune, or nothing would be left
+        }
+        self.remove_neurons(&dead)?;
+        Ok(dead.len())
+    }
+
+    fn resized_settings(&self, n_neurons: usize) -> Result<()> {
+        let mut n_settings = self.n_settings.clone();
+        n_settings.n_neurons = n_neurons;
+        n_settings.validate(&self.g_settings)?;
+        Ok(())
+    }
+}
+
+/// Points the interconnection to its own node with the lowest forces, which counts as not connected
+fn disconnect(connection: &InterConnection, node_global_index: usize) {
+    connection.store_index(node_global_index);
+    connection.reset_main();
+    connection.reset_pending();
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rayon::ThreadPoolBuilder;
+
+    use crate::cpu::interface::NodeState;
+    use crate::cpu::process::update;
+    use crate::settings::Settings;
+    use crate::topology::SearchTopology;
+    use super::*;
+
+    #[test]
+    pub fn test_growth() {
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let mut settings = Settings::preset("tiny").unwrap();
+        settings.n_settings.n_io_ports = 2;
+        settings.n_settings.n_network_ports = 1;
+        let mut network = Network::new(&settings.g_settings, &settings.n_settings, 1);
+        let n_nodes = network.g_settings.n_nodes_per_neuron;
+        update(&mut network, &pool);
+        let mut unchanged = network.clone();
+
+        assert_eq!(network.add_neurons(3).unwrap(), 4..7);
+        assert_eq!(network.n_settings.n_neurons, 7);
+        assert_eq!(network.state.nodes.shape()[0], 7);
+        assert_eq!(network.state.inter_connections[[5, 2]].get_index(), 5 * n_nodes + 2);
+
+        // Every new node takes the disconnected path, the old nodes update as without them
+        let expected = update(&mut unchanged, &pool);
+        let stats = update(&mut network, &pool);
+        assert_eq!(stats.connected_node_updates.get(), expected.connected_node_updates.get());
+        assert_eq!(stats.disconnected_node_updates.get() as usize, expected.disconnected_node_updates.get() as usize + 3 * n_nodes);
+
+        // Neuron 2 points to neuron 1, which is removed. Neuron 3 points to neuron 6, which moves to 4
+        network.state.inter_connections[[2, 0]].store_index(n_nodes + 3);
+        network.state.inter_connections[[3, 0]].store_index(6 * n_nodes + 1);
+        network.state.inter_connections[[3, 0]].store_pending_index(n_nodes);
+        network.state.inter_connection_counters[[3, 0]].inc();
+        assert_ne!(network.state.inter_connection_counters[[3, 0]].get_state(&network.g_settings), NodeState::Searching);
+        network.state.io_ports[0] = n_nodes + 5;
+        network.state.nexus_ports[0] = 1;
+        let remap = network.remove_neurons(&[1, 5, 1]).unwrap();
+        assert_eq!(remap, vec![Some(0), None, Some(1), Some(2), Some(3), None, Some(4)]);
+        assert_eq!(network.n_settings.n_neurons, 5);
+        let connection = &network.state.inter_connections[[1, 0]];
+        assert_eq!(connection.get_index(), n_nodes);
+        assert_eq!(connection.get_raw_force_values(), (-127, -127));
+        let connection = &network.state.inter_connections[[2, 0]];
+        assert_eq!(connection.get_index(), 4 * n_nodes + 1);
+        assert_eq!(connection.get_pending_index(), connection.get_index());
+        assert_eq!(network.state.inter_connection_counters[[2, 0]].get_state(&network.g_settings), NodeState::Searching);
+        assert_eq!(network.state.io_ports[0], n_nodes + 5);
+        assert_eq!(network.state.nexus_ports[0], 1);
+        let n_nodes_total = 5 * n_nodes;
+        assert!(network.state.inter_connections.iter().all(|connection| connection.get_pending_index() < n_nodes_total));
+        update(&mut network, &pool);
+
+        // Unconnected new neurons are dead
+        let new_neurons = network.add_neurons(2).unwrap();
+        assert!(new_neurons.clone().all(|neuron_index| network.state.dead_neurons(&network.g_settings).contains(&neuron_index)));
+        assert!(network.prune_dead_neurons().unwrap() >= 2);
+        assert!(network.remove_neurons(&(0..network.n_settings.n_neurons).collect::<Vec<_>>()).is_err());
+
+        // A grid can not grow by one
+        network.g_settings.topology = SearchTopology::Grid2d { width: network.n_settings.n_neurons, height: 1 };
+        assert!(network.add_neurons(1).is_err());
+    }
+}
@@ -1,23 +1,38 @@
 use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicI8, AtomicU32, AtomicU8, Ordering};
 
+use anyhow::{ensure, Result};
 use ndarray::prelude::*;
 use rand::distributions::{Distribution, Uniform};
 use rand::rngs::StdRng;
 use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
 
 use crate::{NetworkSettings, GuardianSettings};
+use crate::topology::NeighbourhoodCache;
 
-use super::{pack_with_negative, unpack_with_negative};
+use super::{node_local_to_global_index, pack_array, pack_with_negative, unpack_array, unpack_array_with_negative, unpack_with_negative};
 use super::model::{Model, ModelSettings};
 
-#[derive(Debug)]
+// Names of the models in Genome, see Genome::models
+pub const INTERCONNECTED_NODE_STATE_MODEL: &str = "interconnected_node_state_update";
+pub const INTRACONNECTED_NODE_STATE_MODEL: &str = "intraconnected_node_state_update";
+pub const NEURON_STATE_MODEL: &str = "neuron_state_update";
+pub const INTERCONNECTIONS_PLASTICITY_MODEL: &str = "interconnections_plasticity_update";
+pub const INTRACONNECTIONS_PLASTICITY_MODEL: &str = "intraconnections_plasticity_update";
+
+// Keys in Genome::io_models
+pub const NEXUS_MODEL: &str = "nexus";
+pub const IO_INPUT_MODEL: &str = "io_input";
+pub const IO_OUTPUT_MODEL: &str = "io_output";
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct CounterInterConnection(AtomicU8);
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CounterIntraConnection(u8);
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum NodeState {
     Searching,
     Connecting,
@@ -27,19 +42,32 @@ pub enum NodeState {
 
 /// Interconnection = nodes between neurons
 /// Intraconnections = nodes within neurons
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct State {
     pub nodes: Array3<u8>,
     pub neuron_states: Array2<u8>,
     pub inter_connections: Array2<InterConnection>,
     pub intra_connections: Array3<IntraConnection>,
     pub intra_connection_counters: Array3<CounterIntraConnection>,
-    pub inter_connection_counters: Array2<CounterInterConnection>
+    pub inter_connection_counters: Array2<CounterInterConnection>,
+
+    // IO ports
+    pub io_ports: Array1<usize>,  // Global node index each port is attached to
+    pub io_inputs: Array2<u8>,  // Written by sensors, read by the network
+    pub io_outputs: Array2<u8>,  // Written by the network, read by actuators
 
+    // Network ports
+    pub nexus_ports: Array1<usize>,  // Neuron index each network port is attached to
+    pub nexus_read: Array2<u8>,  // Read by the network. One step behind nexus_received
+    pub nexus_write: Array2<u8>,  // Written by the network, sent to the other side
+    pub nexus_received: Array2<u8>,  // Received from the other side during the step
+
+    // Neuromodulation
+    pub modulators: Array1<i8>,  // Reward or other global signals, input to the neuron state and plasticity models. Empty if disabled
 }
 
-#[derive(Clone)]
-// TODO: What about IO ports and network ports?
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+// IO ports and network ports use the models in io_models
 pub struct Genome {
     // Guardian
     pub interconnected_node_state_update: Model,
@@ -52,15 +80,24 @@ pub struct Genome {
     pub io_models: BTreeMap<String, Model>,
 }
 
+/// Sizes of the inputs and outputs of one model in Genome
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelSizes {
+    pub name: String,
+    pub input_sizes: Vec<usize>,
+    pub output_sizes: Vec<usize>,
+}
+
 #[derive(Clone)]
 pub struct Network {
     pub state: State,
     pub genome: Genome,
     pub g_settings: GuardianSettings,
     pub n_settings: NetworkSettings,
+    pub neighbourhood: NeighbourhoodCache,  // Of the interconnection search, see topology
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct InterConnection {
     pub index: AtomicU32,
     pub pending_index: AtomicU32,  // The one with the highest index "wins"
@@ -76,7 +113,7 @@ pub struct InterConnection {
     pub pending_force_other: AtomicI8,
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 /// Reduced index -> limits nodes per neuron, but it saves 4 bytes per internal connection which adds up to quite a lot
 pub struct IntraConnection {
     pub index: u16,
@@ -93,11 +130,22 @@ impl Clone for CounterInterConnection {
     }
 }
 
+impl PartialEq for CounterInterConnection {
+    fn eq(&self, other: &Self) -> bool {
+        self.get_value() == other.get_value()
+    }
+}
+
 impl CounterInterConnection {
     pub fn new() -> Self {
         Self(AtomicU8::new(0))
     }
 
+    /// Such as a recorded value
+    pub fn from_value(value: u8) -> Self {
+        Self(AtomicU8::new(value))
+    }
+
     pub fn get_value(&self) -> u8 {
         self.0.load(Ordering::Relaxed)
     }
@@ -142,6 +190,11 @@ impl CounterIntraConnection {
         Self(0)
     }
 
+    /// Such as a recorded value
+    pub fn from_value(value: u8) -> Self {
+        Self(value)
+    }
+
     pub fn get_value(&self) -> u8 {
         self.0
     }
@@ -198,6 +251,15 @@ impl Clone for InterConnection {
     }
 }
 
+impl PartialEq for InterConnection {
+    fn eq(&self, other: &Self) -> bool {
+        self.get_index() == other.get_index()
+        && self.get_pending_index() == other.get_pending_index()
+        && self.get_raw_force_values() == other.get_raw_force_values()
+        && self.get_raw_pending_force_values() == other.get_raw_pending_force_values()
+    }
+}
+
 impl InterConnection {
 
     // Get values
@@ -440,16 +502,97 @@ impl State {
             |_| { CounterIntraConnection::new() }
         );
 
+        // Spread the ports evenly over all nodes
+        let n_nodes_total = n_settings.n_neurons * g_settings.n_nodes_per_neuron;
+        let io_ports = Array1::from_shape_fn(
+            n_settings.n_io_ports,
+            |port| { port * n_nodes_total / n_settings.n_io_ports }
+        );
+        let io_inputs = Array2::zeros((n_settings.n_io_ports, g_settings.io_size));
+        let io_outputs = Array2::zeros((n_settings.n_io_ports, g_settings.io_size));
+
+        let nexus_ports = Array1::from_shape_fn(
+            n_settings.n_network_ports,
+            |port| { port * n_settings.n_neurons / n_settings.n_network_ports }
+        );
+        let nexus_read = Array2::zeros((n_settings.n_network_ports, g_settings.nexus_size));
+        let nexus_write = Array2::zeros((n_settings.n_network_ports, g_settings.nexus_size));
+        let nexus_received = Array2::zeros((n_settings.n_network_ports, g_settings.nexus_size));
+        let modulators = Array1::zeros(g_settings.modulator_size);
+
         Self {
             nodes,
             neuron_states,
             inter_connections,
             intra_connections,
             inter_connection_counters,
-            intra_connection_counters
+            intra_connection_counters,
+            io_ports,
+            io_inputs,
+            io_outputs,
+            nexus_ports,
+            nexus_read,
+            nexus_write,
+            nexus_received,
+            modulators
         }
     }
 
+    /// Moves an io port to another node
+    pub fn set_io_port(&mut self, port: usize, neuron_index: usize, node_local_index: usize, g_settings: &GuardianSettings) -> Result<()> {
+        self.check_io_port(port)?;
+        let n_nodes = g_settings.n_nodes_per_neuron;
+        ensure!(node_local_index < n_nodes, "Node {node_local_index} does not exist, a neuron has {n_nodes} nodes");
+        ensure!(neuron_index < self.n_neurons(), "Neuron {neuron_index} does not exist, the network has {} neurons", self.n_neurons());
+        self.io_ports[port] = node_local_to_global_index(neuron_index, node_local_index, g_settings);
+        Ok(())
+    }
+
+    pub fn check_io_port(&self, port: usize) -> Result<()> {
+        ensure!(port < self.io_ports.len(), "IO port {port} does not exist, the network has {} io ports", self.io_ports.len());
+        Ok(())
+    }
+
+    /// Values are clamped between 0 and 1
+    pub fn write_io_input(&mut self, port: usize, values: ArrayView1<f32>) {
+        self.io_inputs.row_mut(port).assign(&pack_array(values.to_owned()));
+    }
+
+    pub fn read_io_input(&self, port: usize) -> Array1<f32> {
+        unpack_array(self.io_inputs.row(port))
+    }
+
+    pub fn read_io_output(&self, port: usize) -> Array1<f32> {
+        unpack_array(self.io_outputs.row(port))
+    }
+
+    /// Values are clamped between -1 and 1. Kept until written again
+    pub fn write_modulators(&mut self, values: ArrayView1<f32>) {
+        self.modulators.assign(&values.mapv(pack_with_negative));
+    }
+
+    pub fn read_modulators(&self) -> Array1<f32> {
+        unpack_array_with_negative(self.modulators.view())
+    }
+
+    /// Moves a network port to another neuron
+    pub fn set_nexus_port(&mut self, port: usize, neuron_index: usize) -> Result<()> {
+        ensure!(port < self.nexus_ports.len(), "Network port {port} does not exist, the network has {} network ports", self.nexus_ports.len());
+        ensure!(neuron_index < self.n_neurons(), "Neuron {neuron_index} does not exist, the network has {} neurons", self.n_neurons());
+        self.nexus_ports[port] = neuron_index;
+        Ok(())
+    }
+
+    /// Data from the other side. Will be read by the network in the next step, and only in that step
+    pub fn receive_nexus(&mut self, port: usize, values: ArrayView1<f32>) {
+        self.nexus_received.row_mut(port).assign(&pack_array(values.to_owned()));
+    }
+
+    /// Data to send to the other side
+    pub fn read_nexus_write(&self, port: usize) -> Array1<f32> {
+        unpack_array(self.nexus_write.row(port))
+    }
+
     pub fn randomize(
         &mut self,
         g_settings: &GuardianSettings,
@@ -486,6 +629,31 @@ impl State {
     }
 }
 
+impl Network {
+    /// Random genome and state from the seed
+    pub fn new(g_settings: &GuardianSettings, n_settings: &NetworkSettings, seed: u64) -> Self {
+        let rng = StdRng::seed_from_u64(seed);
+        let genome = Genome::new(g_settings, Some(rng.clone()));
+        let mut state = State::new(g_settings, n_settings);
+        state.randomize(g_settings, n_settings, Some(rng));
+        Self {
+            state,
+            genome,
+            g_settings: g_settings.clone(),
+            n_settings: n_settings.clone(),
+            neighbourhood: NeighbourhoodCache::default(),
+        }
+    }
+}
+
+/// Appends the modulators as the last input, unless modulator_size is 0
+fn with_modulators(mut input_sizes: Vec<usize>, g_settings: &GuardianSettings) -> Vec<usize> {
+    if g_settings.modulator_size > 0 {
+        input_sizes.push(g_settings.modulator_size);
+    }
+    input_sizes
+}
+
 impl Genome {
     pub fn new(
         g_settings: &GuardianSettings,
@@ -496,120 +664,177 @@ impl Genome {
         }
         let mut rng = rng.unwrap();
 
-        // Interconnected
-        let settings = ModelSettings::new(
-            vec![
-                g_settings.neuron_state_size,  // neuron_state_self
-                g_settings.neuron_state_size,  // neuron_state_other
-                g_settings.node_size,  // node_state_self
-                g_settings.node_size,  // node_state_other
-                1,  // force_self,
-                1,  // force_other,
-                1,  // is main
-                1,  // is pending
-            ],
-            g_settings.hidden_sizes.clone(),
-            vec![
-                g_settings.node_size,  // delta_node_state_self,
-                1  // delta_force_self
-            ],
-        ).unwrap();
-        let interconnected_node_state_update = Model::new(settings, &mut rng).unwrap();
-
-        // Intraconnected
-        let settings = ModelSettings::new(
-            vec![
-                g_settings.neuron_state_size,  // neuron_state
-                g_settings.node_size,  // node_state_self
-                g_settings.node_size,  // node_state_other,
-                1,  // force_self (needed?)
-                1,  // force_other (needed?)
-            ],
-            g_settings.hidden_sizes.clone(),
-            vec![
-                g_settings.node_size,  // delta_node_self
-                g_settings.node_size,  // delta_node_other
-            ],
-        ).unwrap();
-        let intraconnected_node_state_update = Model::new(settings, &mut rng).unwrap();
-
-        // Neuron state
-        let settings = ModelSettings::new(
-            vec![
-                g_settings.neuron_state_size,
-                g_settings.node_size,
-            ],
-            g_settings.hidden_sizes.clone(),
-            vec![
-                g_settings.neuron_state_size,
-                g_settings.node_size
-            ],
-        ).unwrap();
-        let neuron_state_update = Model::new(settings, &mut rng).unwrap();
-
-        // Interconnections plasticity
-        let settings = ModelSettings::new(
-            vec![
-                g_settings.neuron_state_size,
-                g_settings.neuron_state_size,
-                g_settings.node_size,
-                g_settings.node_size,
-                1, // force_self
-                1, // force_other
-                1,  // is main
-                1,  // is pending
-            ],
-            g_settings.hidden_sizes.clone(),
-            vec![
-                1  // delta_force_self
-            ],
-        ).unwrap();
-        let interconnections_plasticity_update = Model::new(settings, &mut rng).unwrap();
-
-        // Intraconnections plasticity
-        let settings = ModelSettings::new(
-            vec![
-                g_settings.neuron_state_size,
-                g_settings.node_size,
-                g_settings.node_size,
-                1, // force_self
-                1, // force_other
-                1,  // is main
-                1,  // is pending
-            ],
-            g_settings.hidden_sizes.clone(),
-            vec![
-                1,  // delta_force_self
-                1,  // delta_force_other
-            ],
-        ).unwrap();
-        let intraconnections_plasticity_update = Model::new(settings, &mut rng).unwrap();
-
-        // Base for IO models. More can be added later
-        let mut io_models = BTreeMap::new();
-        let settings = ModelSettings::new(
-            vec![
-                g_settings.neuron_state_size,
-                g_settings.nexus_size,  // nexus_read
-                g_settings.nexus_size,  // nexus_write
-            ],
-            g_settings.hidden_sizes.clone(),
-            vec![
-                g_settings.nexus_size,  // delta_nexus
-                g_settings.neuron_state_size,  // delta_neuron_write
-            ],
-        ).unwrap();
-        let nexus_update = Model::new(settings, &mut rng).unwrap();
-        io_models.insert("nexus".to_string(), nexus_update.clone());
-
-
+        // Created in the order of model_sizes, so the same rng gives the same models
+        let mut models: BTreeMap<String, Model> = Self::model_sizes(g_settings)
+            .into_iter()
+            .map(|sizes| {
+                let settings = ModelSettings::new(sizes.input_sizes, g_settings.hidden_sizes.clone(), sizes.output_sizes).unwrap();
+                (sizes.name, Model::new(settings, &mut rng).unwrap())
+            })
+            .collect();
+        let mut take = |name: &str| models.remove(name).unwrap();
         Self {
-            interconnected_node_state_update,
-            intraconnected_node_state_update,
-            interconnections_plasticity_update,
-            intraconnections_plasticity_update,
-            neuron_state_update,
-            io_models
+            interconnected_node_state_update: take(INTERCONNECTED_NODE_STATE_MODEL),
+            intraconnected_node_state_update: take(INTRACONNECTED_NODE_STATE_MODEL),
+            neuron_state_update: take(NEURON_STATE_MODEL),
+            interconnections_plasticity_update: take(INTERCONNECTIONS_PLASTICITY_MODEL),
+            intraconnections_plasticity_update: take(INTRACONNECTIONS_PLASTICITY_MODEL),
+            io_models: models,  // The rest
         }
     }
+
+    /// Input and output sizes of every model for the settings. The hidden sizes can be anything
+    pub fn model_sizes(g_settings: &GuardianSettings) -> Vec<ModelSizes> {
+        vec![
+            // Interconnected
+            ModelSizes::new(
+                INTERCONNECTED_NODE_STATE_MODEL,
+                vec![
+                    g_settings.neuron_state_size,  // neuron_state_self
+                    g_settings.neuron_state_size,  // neuron_state_other
+                    g_settings.node_size,  // node_state_self
+                    g_settings.node_size,  // node_state_other
+                    1,  // force_self,
+                    1,  // force_other,
+                    1,  // is main
+                    1,  // is pending
+                ],
+                vec![
+                    g_settings.node_size,  // delta_node_state_self,
+                    1  // delta_force_self
+                ],
+            ),
+            // Intraconnected
+            ModelSizes::new(
+                INTRACONNECTED_NODE_STATE_MODEL,
+                vec![
+                    g_settings.neuron_state_size,  // neuron_state
+                    g_settings.node_size,  // node_state_self
+                    g_settings.node_size,  // node_state_other,
+                    1,  // force_self (needed?)
+                    1,  // force_other (needed?)
+                ],
+                vec![
+                    g_settings.node_size,  // delta_node_self
+                    g_settings.node_size,  // delta_node_other
+                ],
+            ),
+            // Neuron state
+            ModelSizes::new(
+                NEURON_STATE_MODEL,
+                with_modulators(vec![
+                    g_settings.neuron_state_size,
+                    g_settings.node_size,
+                ], g_settings),
+                vec![
+                    g_settings.neuron_state_size,
+                    g_settings.node_size
+                ],
+            ),
+            // Interconnections plasticity
+            ModelSizes::new(
+                INTERCONNECTIONS_PLASTICITY_MODEL,
+                with_modulators(vec![
+                    g_settings.neuron_state_size,
+                    g_settings.neuron_state_size,
+                    g_settings.node_size,
+                    g_settings.node_size,
+                    1, // force_self
+                    1, // force_other
+                    1,  // is main
+                    1,  // is pending
+                ], g_settings),
+                vec![
+                    1  // delta_force_self
+                ],
+            ),
+            // Intraconnections plasticity
+            ModelSizes::new(
+                INTRACONNECTIONS_PLASTICITY_MODEL,
+                with_modulators(vec![
+                    g_settings.neuron_state_size,
+                    g_settings.node_size,
+                    g_settings.node_size,
+                    1, // force_self
+                    1, // force_other
+                    1,  // is main
+                    1,  // is pending
+                ], g_settings),
+                vec![
+                    1,  // delta_force_self
+                    1,  // delta_force_other
+                ],
+            ),
+
+            // Base for IO models. More can be added later
+            ModelSizes::new(
+                NEXUS_MODEL,
+                vec![
+                    g_settings.neuron_state_size,
+                    g_settings.nexus_size,  // nexus_read
+                    g_settings.nexus_size,  // nexus_write
+                ],
+                vec![
+                    g_settings.nexus_size,  // delta_nexus
+                    g_settings.neuron_state_size,  // delta_neuron_write
+                ],
+            ),
+            // Input ports
+            ModelSizes::new(
+                IO_INPUT_MODEL,
+                vec![
+                    g_settings.neuron_state_size,
+                    g_settings.node_size,
+                    g_settings.io_size,  // io_input
+                ],
+                vec![
+                    g_settings.neuron_state_size,  // delta_neuron_state
+                    g_settings.node_size,  // delta_node
+                ],
+            ),
+            // Output ports
+            ModelSizes::new(
+                IO_OUTPUT_MODEL,
+                vec![
+                    g_settings.neuron_state_size,
+                    g_settings.node_size,
+                    g_settings.io_size,  // io_output
+                ],
+                vec![
+                    g_settings.io_size,  // delta_io_output
+                ],
+            ),
+        ]
+    }
+
+    /// Every model by name, the io models by their key
+    pub fn models(&self) -> Vec<(&str, &Model)> {
+        let mut models = vec![
+            (INTERCONNECTED_NODE_STATE_MODEL, &self.interconnected_node_state_update),
+            (INTRACONNECTED_NODE_STATE_MODEL, &self.intraconnected_node_state_update),
+            (NEURON_STATE_MODEL, &self.neuron_state_update),
+            (INTERCONNECTIONS_PLASTICITY_MODEL, &self.interconnections_plasticity_update),
+            (INTRACONNECTIONS_PLASTICITY_MODEL, &self.intraconnections_plasticity_update),
+        ];
+        models.extend(self.io_models.iter().map(|(name, model)| (name.as_str(), model)));
+        models
+    }
+
+    pub fn models_mut(&mut self) -> Vec<(&str, &mut Model)> {
+        let mut models = vec![
+            (INTERCONNECTED_NODE_STATE_MODEL, &mut self.interconnected_node_state_update),
+            (INTRACONNECTED_NODE_STATE_MODEL, &mut self.intraconnected_node_state_update),
+            (NEURON_STATE_MODEL, &mut self.neuron_state_update),
+            (INTERCONNECTIONS_PLASTICITY_MODEL, &mut self.interconnections_plasticity_update),
+            (INTRACONNECTIONS_PLASTICITY_MODEL, &mut self.intraconnections_plasticity_update),
+        ];
+        models.extend(self.io_models.iter_mut().map(|(name, model)| (name.as_str(), model)));
+        models
+    }
+}
+
+impl ModelSizes {
+    fn new(name: &str, input_sizes: Vec<usize>, output_sizes: Vec<usize>) -> Self {
+        Self { name: name.to_string(), input_sizes, output_sizes }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,131 @@
+//! Matmul kernels that write into preallocated arrays
+//!
+//! Batches go to the gemm of matrixmultiply (through ndarray), which is already blocked and vectorized.
+//! Its packing dominates for a single row, such as precalculate, so rows use a vector-matrix kernel instead.
+//! The stages bench (`cargo bench --bench stages`) measures the stages that use them.
+//!
+//! Inputs are often zero, such as the other side of a disconnected node or the forces of a node that is
+//! searching. Zero rows and zero values of a row are skipped.
+
+use ndarray::linalg::general_mat_mul;
+use ndarray::{Array2, ArrayView1, ArrayView2, ArrayViewMut1, ArrayViewMut2, Axis};
+
+/// Reused buffers for the hidden layers of Model::forward_from_precalc
+/// Each thread has its own, see Model::forward_from_precalc
+#[derive(Default)]
+pub struct Workspace {
+    pub(crate) current: Vec<f32>,
+    pub(crate) next: Vec<f32>,
+}
+
+/// Resizes the buffer and gives it the shape, without allocating if it has been as large before
+pub(crate) fn buffer_view(buffer: &mut Vec<f32>, shape: (usize, usize)) -> ArrayViewMut2<'_, f32> {
+    let len = shape.0 * shape.1;
+    if buffer.len() < len {
+        buffer.resize(len, 0.0);
+    }
+    ArrayViewMut2::from_shape(shape, &mut buffer[..len]).unwrap()
+}
+
+/// out += x · weight
+pub fn matmul_add(x: ArrayView2<f32>, weight: ArrayView2<f32>, mut out: ArrayViewMut2<f32>) {
+    if x.nrows() == 1 {
+        vector_matrix_add(x.row(0), weight, out.row_mut(0));
+    } else {
+        general_mat_mul(1.0, &x, &weight, 1.0, &mut out);
+    }
+}
+
+/// Up to this many nonzero rows, they are done one by one with the row kernel instead of gemm
+const MAX_SPARSE_ROWS: usize = 2;
+
+/// out += x · weight, rows of x that are all zero are skipped
+/// A few nonzero rows use the row kernel. Otherwise the nonzero rows are gathered into one gemm, and the results
+/// are added back to their rows
+pub fn sparse_matmul_add(x: ArrayView2<f32>, weight: ArrayView2<f32>, mut out: ArrayViewMut2<f32>) {
+    let is_nonzero = |row: &ArrayView1<f32>| row.iter().any(|v| *v != 0.0);
+    let nonzero_rows: Vec<usize> = x.outer_iter()
+        .enumerate()
+        .filter(|(_, row)| is_nonzero(row))
+        .map(|(row_index, _)| row_index)
+        .collect();
+    if nonzero_rows.len() == x.nrows() {
+        matmul_add(x, weight, out);
+    } else if nonzero_rows.len() <= MAX_SPARSE_ROWS {
+        for row_index in nonzero_rows {
+            vector_matrix_add(x.row(row_index), weight, out.row_mut(row_index));
+        }
+    } else {
+        let compact_x = x.select(Axis(0), &nonzero_rows);
+        let mut compact_out = Array2::zeros((nonzero_rows.len(), out.ncols()));
+        general_mat_mul(1.0, &compact_x, &weight, 0.0, &mut compact_out);
+        for (row_index, compact_row) in nonzero_rows.iter().zip(compact_out.outer_iter()) {
+            let mut out_row = out.row_mut(*row_index);
+            out_row += &compact_row;
+        }
+    }
+}
+
+/// out += x · weight
+pub fn vector_matrix_add(x: ArrayView1<f32>, weight: ArrayView2<f32>, mut out: ArrayViewMut1<f32>) {
+    let (Some(x_values), Some(weight_values), Some(out_values)) = (x.as_slice(), weight.as_slice(), out.as_slice_mut()) else {
+        out += &x.dot(&weight);
+        return;
+    };
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+        // SAFETY: avx2 and fma are available
+        unsafe { vector_matrix_add_avx2(x_values, weight_values, out_values) };
+        return;
+    }
+    vector_matrix_add_slices(x_values, weight_values, out_values);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,fma")]
+unsafe fn vector_matrix_add_avx2(x: &[f32], weight: &[f32], out: &mut [f32]) {
+    vector_matrix_add_slices(x, weight, out)
+}
+
+/// Adds each weight row scaled by its input, the inner loop is vectorized
+#[inline(always)]
+fn vector_matrix_add_slices(x: &[f32], weight: &[f32], out: &mut [f32]) {
+    for (x_value, weight_row) in x.iter().zip(weight.chunks_exact(out.len())) {
+        if *x_value == 0.0 {
+            continue;
+        }
+        for (acc, w) in out.iter_mut().zip(weight_row) {
+            *acc += x_value * w;
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use ndarray_rand::RandomExt;
+    use ndarray_rand::rand_distr::Uniform;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use ndarray::Array1;
+
+    use super::*;
+
+    #[test]
+    pub fn test_sparse_matmul_add() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let weight = Array2::random_using((16, 24), Uniform::new(-1.0, 1.0), &mut rng);
+        let start = Array2::random_using((8, 24), Uniform::new(-1.0, 1.0), &mut rng);
+        // None, a few and most rows nonzero, where the most go through the gathered gemm
+        for nonzero_rows in [vec![], vec![3], vec![0, 6], vec![1, 2, 4, 7], vec![0, 1, 2, 3, 4, 5, 6], (0..8).collect()] {
+            let mut x = Array2::zeros((8, 16));
+            for row_index in nonzero_rows.iter() {
+                x.row_mut(*row_index).assign(&Array1::random_using(16, Uniform::new(-1.0, 1.0), &mut rng));
+            }
+            let expected = &start + &x.dot(&weight);
+            let mut out = start.clone();
+            sparse_matmul_add(x.view(), weight.view(), out.view_mut());
+            assert!(out.iter().zip(expected.iter()).all(|(a, b)| (a - b).abs() < 1e-5), "{nonzero_rows:?}");
+        }
+    }
+}
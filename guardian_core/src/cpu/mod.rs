@@ -5,7 +5,16 @@ use crate::GuardianSettings;
 
 pub mod interface;
 pub mod process;
+pub mod kernels;
 pub mod model;
+pub mod precalc;
+pub mod quantized;
+pub mod checkpoint;
+pub mod genome_file;
+pub mod compatibility;
+pub mod growth;
+pub mod diff;
+pub mod stats;
 
 
 pub fn wrap_index(local_index: usize, offset: isize, max_index: usize) -> usize {
@@ -47,6 +56,12 @@ pub fn unpack_array<D>(arr: ArrayView<u8, D>) -> Array<f32, D>
     arr.map(|v| unpack(*v))
 }
 
+pub fn unpack_array_with_negative<D>(arr: ArrayView<i8, D>) -> Array<f32, D>
+    where D: ndarray::Dimension
+{
+    arr.map(|v| unpack_with_negative(*v))
+}
+
 pub fn pack_array<D>(arr: Array<f32, D>) -> Array<u8, D>
     where D: ndarray::Dimension
 {
@@ -67,18 +82,37 @@ pub fn clip<D>(arr: Array<f32, D>, min_val: f32, max_val: f32) -> Array<f32, D>
     arr.mapv(|x| x.min(max_val).max(min_val))
 }
 
-pub fn min_array_inplace<'a>(arr1: &mut ArrayViewMut1<'a, f32>, arr2: ArrayView1<'a, f32>) {
+pub fn min_array_inplace(arr1: &mut ArrayViewMut1<f32>, arr2: ArrayView1<f32>) {
     arr1.zip_mut_with(&arr2, |v1, v2| {
         *v1 = v1.min(*v2);
     });
 }
 
-pub fn max_array_inplace<'a>(arr1: &mut ArrayViewMut1<'a, f32>, arr2: ArrayView1<'a, f32>) {
+pub fn max_array_inplace(arr1: &mut ArrayViewMut1<f32>, arr2: ArrayView1<f32>) {
     arr1.zip_mut_with(&arr2, |v1, v2| {
         *v1 = v1.max(*v2);
     });
 }
 
+/// Per column, the highest positive plus the lowest negative value of the rows.
+/// Same as applying min_array_inplace and max_array_inplace on zeros for each row
+pub fn min_max_rows(arr: ArrayView2<f32>) -> Array1<f32> {
+    let min = arr.fold_axis(Axis(0), 0.0, |v1: &f32, v2| v1.min(*v2));
+    let max = arr.fold_axis(Axis(0), 0.0, |v1: &f32, v2| v1.max(*v2));
+    max + min
+}
+
+/// One value per row, for batched forwards
+pub fn values_to_array(values: &[f32]) -> Array2<f32> {
+    Array1::from_vec(values.to_vec()).insert_axis(Axis(1))
+}
+
+/// Stacks rows into a batch. Panics if empty
+pub fn stack_rows(rows: &[Array1<f32>]) -> Array2<f32> {
+    let views: Vec<ArrayView1<f32>> = rows.iter().map(|row| row.view()).collect();
+    ndarray::stack(Axis(0), &views).unwrap()
+}
+
 fn get_node(neuron_index: usize, node_local_index: usize, nodes: &Array3<u8>) -> Array1<f32> {
     unpack_array(nodes.slice(s![neuron_index, node_local_index, ..]))
 }
@@ -95,8 +129,17 @@ fn get_inter_connection_counter<'a>(neuron_index: usize, node_local_index: usize
     inter_connection_counters.get((neuron_index, node_local_index)).unwrap()
 }
 
-pub fn check_is_connected(node_a_index: usize, connection_b: &InterConnection) -> bool {
-    connection_b.get_index() == node_a_index
+/// The modulators through the input weights of the model, zero if modulator_size is 0 and the model has no such input
+pub fn precalculate_modulators(model: &model::Model, input_index: usize, modulators: ArrayView1<i8>) -> model::Row {
+    if modulators.is_empty() {
+        return model::Row::zeros(model.settings().hidden_sizes[0]);
+    }
+    model.precalculate(input_index, unpack_array_with_negative(modulators).view())
+}
+
+/// Node a is connected to node b if b points back to a. A node that points to itself is not connected
+pub fn check_is_connected(node_a_index: usize, node_b_index: usize, connection_b: &InterConnection) -> bool {
+    node_a_index != node_b_index && connection_b.get_index() == node_a_index
 }
 
 pub fn value_to_array(value: f32) -> Array2<f32> {
@@ -115,21 +158,15 @@ pub fn node_local_to_global_index(neuron_index: usize, node_local_index: usize,
     neuron_index * g_settings.n_nodes_per_neuron + node_local_index
 }
 
-
-/// Allows for multiple process to work with the same vector without locking the whole vector
-/// Is unsafe. Only use this if you know what you are doing.
-unsafe fn non_locking_write(ptr: *mut u8, offset: usize, size: usize, data: &[u8]) {
-    let dest_ptr = ptr.add(offset);  // what if 32 bit computer?
-    let dest_slice = std::slice::from_raw_parts_mut(dest_ptr, size);
-    dest_slice.copy_from_slice(data);
-}
-
 #[cfg(test)]
 pub mod test {
     use crate::{get_network_size, GuardianSettings, NetworkSettings};
     use crate::cpu::interface::{Genome, State, Network};
     use crate::cpu::process::update;
+    use crate::settings::Settings;
+    use crate::topology::NeighbourhoodCache;
 
+    use ndarray::Array1;
     use rayon::ThreadPoolBuilder;
     use tracing::debug;
     use tracing::Level;
@@ -166,10 +203,71 @@ pub mod test {
             state,
             genome,
             g_settings,
-            n_settings
+            n_settings,
+            neighbourhood: NeighbourhoodCache::default()
         };
         //loop {
-            update(&mut network, &pool);
+            let stats = update(&mut network, &pool);
         //}
+        assert_eq!(stats.neuron_updates.get() as usize, network.n_settings.n_neurons);
+        assert_eq!(stats.intra_node_updates.get() as usize, network.n_settings.n_neurons * network.g_settings.n_nodes_per_neuron);
+    }
+
+    #[test]
+    fn test_deterministic_update() {
+        let mut g_settings = GuardianSettings::downlevel_default();
+        g_settings.hidden_sizes = vec![8];
+        let mut n_settings = NetworkSettings::downlevel_default();
+        n_settings.n_neurons = 8;
+        n_settings.deterministic = true;
+        let rng = rand::rngs::StdRng::seed_from_u64(1);
+        let genome = Genome::new(&g_settings, Some(rng.clone()));
+        let mut state = State::new(&g_settings, &n_settings);
+        state.randomize(&g_settings, &n_settings, Some(rng));
+
+        let states = [1, 4].map(|thread_count| {
+            let pool = ThreadPoolBuilder::new().num_threads(thread_count).build().unwrap();
+            let mut network = Network {
+                state: state.clone(),
+                genome: genome.clone(),
+                g_settings: g_settings.clone(),
+                n_settings: n_settings.clone(),
+                neighbourhood: NeighbourhoodCache::default()
+            };
+            for _ in 0..5 {
+                update(&mut network, &pool);
+            }
+            network.state
+        });
+        assert!(states[0] == states[1]);
+    }
+
+    #[test]
+    fn test_modulators() {
+        let pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let mut settings = Settings::preset("tiny").unwrap();
+        settings.n_settings.deterministic = true;
+        let network = Network::new(&settings.g_settings, &settings.n_settings, 1);
+
+        let mut modulated = network.clone();
+        modulated.state.write_modulators(Array1::from_elem(settings.g_settings.modulator_size, -1.0).view());
+        assert!(modulated.state.read_modulators().iter().all(|v| *v == -1.0));
+        let mut unmodulated = network;
+        update(&mut modulated, &pool);
+        update(&mut unmodulated, &pool);
+        assert!(modulated.state.neuron_states != unmodulated.state.neuron_states);
+
+        // Disabled, the models have no modulator input
+        settings.g_settings.modulator_size = 0;
+        settings.validate().unwrap();
+        let mut network = Network::new(&settings.g_settings, &settings.n_settings, 1);
+        assert_eq!(network.genome.neuron_state_update.settings().input_sizes.len(), 2);
+        network.state.write_modulators(Array1::zeros(0).view());
+        update(&mut network, &pool);
+
+        // Settings written before the modulators have none
+        let mut json = serde_json::to_value(&settings.g_settings).unwrap();
+        json.as_object_mut().unwrap().remove("modulator_size");
+        assert_eq!(serde_json::from_value::<GuardianSettings>(json).unwrap().modulator_size, 0);
     }
 }
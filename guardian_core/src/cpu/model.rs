@@ -1,23 +1,32 @@
 use ndarray_rand::RandomExt;
 use ndarray_rand::rand_distr::Normal;
-use ndarray::{Array1, Array2, ArrayView1, ArrayView2};
+use std::cell::RefCell;
+
+use ndarray::{Array1, Array2, ArrayView1, ArrayView2, ArrayViewMut2, Axis, Dimension, Zip};
 use anyhow::{ensure, Result};
 use rand::rngs::StdRng;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 
-use super::clip;
+use super::kernels::{buffer_view, matmul_add, sparse_matmul_add, vector_matrix_add, Workspace};
+use super::quantized::{QuantizedLayer, QuantizedModel, QuantizedWeight};
 
 pub type Row = Array1<f32>;
 pub type Array = Array2<f32>;
 pub type Weight = Array2<f32>;
 pub type Bias = Array1<f32>;
 
-#[derive(Clone)]
+thread_local! {
+    static WORKSPACE: RefCell<Workspace> = RefCell::new(Workspace::default());
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Layer {
     weight: Weight,
     bias: Bias
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Model {
     settings: ModelSettings,
@@ -27,7 +36,7 @@ pub struct Model {
     output_layers: Vec<Layer>
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct ModelSettings {
     // Easier for GPU calculations
@@ -36,10 +45,64 @@ pub struct ModelSettings {
     pub n_outputs: usize,
     pub input_sizes: Vec<usize>,
     pub hidden_sizes: Vec<usize>,
-    pub output_sizes: Vec<usize>
+    pub output_sizes: Vec<usize>,
+
+    // Architecture
+    pub hidden_activations: Vec<Activation>,  // One per hidden size
+    pub output_activations: Vec<Activation>,  // One per output size
+    pub output_bias: bool
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Activation {
+    Relu,
+    Tanh,
+    Sigmoid,
+    Clamp { min: f32, max: f32 },
+    Identity
+}
+
+impl Activation {
+    /// Used by the hidden layers if nothing else is chosen
+    pub const HIDDEN_DEFAULT: Activation = Activation::Clamp { min: 0.0, max: 1.0 };
+
+    /// Used by the outputs if nothing else is chosen. Outputs are deltas, thus can be negative
+    pub const OUTPUT_DEFAULT: Activation = Activation::Clamp { min: -0.1, max: 0.1 };
+
+    /// Does it inplace
+    pub fn apply(&self, mut arr: Array) -> Array {
+        self.apply_inplace(arr.view_mut());
+        arr
+    }
+
+    pub fn apply_inplace(&self, mut arr: ArrayViewMut2<f32>) {
+        match *self {
+            Activation::Relu => arr.mapv_inplace(|x| x.max(0.0)),
+            Activation::Tanh => arr.mapv_inplace(f32::tanh),
+            Activation::Sigmoid => arr.mapv_inplace(|x| 1.0 / (1.0 + (-x).exp())),
+            Activation::Clamp { min, max } => arr.mapv_inplace(|x| x.min(max).max(min)),
+            Activation::Identity => {}
+        }
+    }
+
+    /// Picks one at random. Clamp keeps the bounds of `current` if it already is a clamp
+    pub fn random(current: Activation, default_clamp: Activation, rng: &mut StdRng) -> Activation {
+        let clamp = match current {
+            Activation::Clamp { .. } => current,
+            _ => default_clamp
+        };
+        match rng.gen_range(0..5) {
+            0 => Activation::Relu,
+            1 => Activation::Tanh,
+            2 => Activation::Sigmoid,
+            3 => clamp,
+            _ => Activation::Identity
+        }
+    }
 }
 
 impl ModelSettings {
+    /// Uses the default activations and no output bias
     pub fn new(input_sizes: Vec<usize>, hidden_sizes: Vec<usize>, output_sizes: Vec<usize>) -> Result<Self> {
         ensure!(!input_sizes.is_empty());
         ensure!(!hidden_sizes.is_empty());
@@ -49,12 +112,29 @@ impl ModelSettings {
                 n_inputs: input_sizes.len(),
                 n_hidden: hidden_sizes.len(),
                 n_outputs: output_sizes.len(),
+                hidden_activations: vec![Activation::HIDDEN_DEFAULT; hidden_sizes.len()],
+                output_activations: vec![Activation::OUTPUT_DEFAULT; output_sizes.len()],
+                output_bias: false,
                 input_sizes,
                 hidden_sizes,
                 output_sizes
             }
         )
     }
+
+    pub fn with_activations(mut self, hidden_activations: Vec<Activation>, output_activations: Vec<Activation>) -> Result<Self> {
+        ensure!(hidden_activations.len() == self.hidden_sizes.len(), "Expected {} hidden activations", self.hidden_sizes.len());
+        ensure!(output_activations.len() == self.output_sizes.len(), "Expected {} output activations", self.output_sizes.len());
+        check_clamps(hidden_activations.iter().chain(output_activations.iter()))?;
+        self.hidden_activations = hidden_activations;
+        self.output_activations = output_activations;
+        Ok(self)
+    }
+
+    pub fn with_output_bias(mut self, output_bias: bool) -> Self {
+        self.output_bias = output_bias;
+        self
+    }
 }
 
 impl Model {
@@ -62,6 +142,9 @@ impl Model {
         ensure!(!settings.input_sizes.is_empty());
         ensure!(!settings.hidden_sizes.is_empty());
         ensure!(!settings.output_sizes.is_empty());
+        ensure!(settings.hidden_activations.len() == settings.hidden_sizes.len(), "Expected one activation per hidden layer");
+        ensure!(settings.output_activations.len() == settings.output_sizes.len(), "Expected one activation per output");
+        check_clamps(settings.hidden_activations.iter().chain(settings.output_activations.iter()))?;
 
         // Handle input layers
         let next_size = settings.hidden_sizes.first().unwrap();
@@ -81,48 +164,81 @@ impl Model {
             prev_size = hidden_size;
         }
 
-        // Handle outputs
+        // Handle outputs. The bias starts at zero, so enabling output_bias later does not change the outputs
         let last_hidden_size = prev_size;
         let mut output_layers = vec![];
         for output_size in settings.output_sizes.iter() {
-            let layer = Layer::new(*last_hidden_size, *output_size, rng);
+            let layer = Layer::new_without_bias(*last_hidden_size, *output_size, rng);
             output_layers.push(layer);
         }
         Ok(Self { settings, input_weights, input_bias, hidden_layers, output_layers })
     }
 
     /// Apply the full model on the input arrays
+    /// The hidden layers are computed in the workspace of the current thread
     pub fn forward_from_precalc(
         &self,
         inputs: &[(usize, ArrayView2<f32>)],
         precalculated: &Row,
     ) -> Vec<Array> {
-        let batch_size = inputs.first().unwrap().1.shape()[0];
-        let mut x: Array = Array2::zeros((batch_size, self.input_bias.len()));
+        self.forward_from_precalc_rows(inputs, precalculated.view().insert_axis(Axis(0)))
+    }
 
-        // Add precalc
-        x = x + precalculated;
+    /// Same as forward_from_precalc, but with one precalculated row per input row
+    pub fn forward_from_precalc_rows(
+        &self,
+        inputs: &[(usize, ArrayView2<f32>)],
+        precalculated: ArrayView2<f32>,
+    ) -> Vec<Array> {
+        WORKSPACE.with(|workspace| match workspace.try_borrow_mut() {
+            Ok(mut workspace) => self.forward_with_workspace(inputs, precalculated, &mut workspace),
+            Err(_) => self.forward_with_workspace(inputs, precalculated, &mut Workspace::default()),
+        })
+    }
+
+    /// The precalculated rows are broadcast if there is only one
+    pub fn forward_with_workspace(
+        &self,
+        inputs: &[(usize, ArrayView2<f32>)],
+        precalculated: ArrayView2<f32>,
+        workspace: &mut Workspace,
+    ) -> Vec<Array> {
+        let batch_size = inputs.first().map(|(_, input)| input.nrows()).unwrap_or(precalculated.nrows());
+        let mut current = &mut workspace.current;
+        let mut next = &mut workspace.next;
 
-        // Add inputs not precalculated
+        // Precalc and inputs not precalculated. Zero rows do not contribute
+        let mut x = buffer_view(current, (batch_size, self.input_bias.len()));
+        x.assign(&precalculated);
         for (i, input) in inputs {
-            let weight = &self.input_weights[*i];
-            x = x + input.dot(weight);
+            sparse_matmul_add(input.view(), self.input_weights[*i].view(), x.view_mut());
         }
+        x += &self.input_bias;
+        let mut hidden_activations = self.settings.hidden_activations.iter();
+        hidden_activations.next().unwrap().apply_inplace(x);
 
-        x = x + &self.input_bias;
-        x = activation_fn(x);
         // Done with inputs, now go through all hidden
-        for layer in self.hidden_layers.iter() {
-            x = layer.forward_with_bias(&x);
-            x = activation_fn(x);
-            //x = activation_fn_output(x);
+        let mut size = self.input_bias.len();
+        for (layer, activation) in self.hidden_layers.iter().zip(hidden_activations) {
+            let x = buffer_view(current, (batch_size, size));
+            let mut y = buffer_view(next, (batch_size, layer.bias.len()));
+            y.assign(&layer.bias);
+            matmul_add(x.view(), layer.weight.view(), y.view_mut());
+            activation.apply_inplace(y);
+            size = layer.bias.len();
+            std::mem::swap(&mut current, &mut next);
         }
 
         // Now, we can calculate the outputs
+        let x = buffer_view(current, (batch_size, size));
         let mut outputs = vec![];
-        for layer in &self.output_layers {
-            let mut res = layer.forward_weight(&x);  // TODO: No bias on output?
-            res = activation_fn_output(res);
+        for (layer, activation) in self.output_layers.iter().zip(self.settings.output_activations.iter()) {
+            let mut res = Array2::zeros((batch_size, layer.bias.len()));
+            if self.settings.output_bias {
+                res.assign(&layer.bias);
+            }
+            matmul_add(x.view(), layer.weight.view(), res.view_mut());
+            activation.apply_inplace(res.view_mut());
             outputs.push(res);
         }
         outputs
@@ -132,9 +248,120 @@ impl Model {
     /// NOTE: Bias is NOT added here!
     pub fn precalculate(&self, input_index: usize, x: ArrayView1<f32>) -> Row {
         let weight = &self.input_weights[input_index];
-        let hidden = x.dot(weight);
+        let mut hidden = Row::zeros(weight.ncols());
+        vector_matrix_add(x, weight.view(), hidden.view_mut());
         hidden
     }
+
+    /// Adds gaussian noise to every weight and bias with the probability `rate`
+    pub fn mutate(&mut self, rate: f64, strength: f32, rng: &mut StdRng) {
+        let dist = Normal::new(0.0, strength).unwrap();
+        let mut mutate_fn = |v: &mut f32| {
+            if rng.gen_bool(rate) {
+                *v += rng.sample(dist);
+            }
+        };
+        for weight in self.input_weights.iter_mut() {
+            weight.map_inplace(&mut mutate_fn);
+        }
+        self.input_bias.map_inplace(&mut mutate_fn);
+        for layer in self.hidden_layers.iter_mut() {
+            layer.weight.map_inplace(&mut mutate_fn);
+            layer.bias.map_inplace(&mut mutate_fn);
+        }
+        // An unused output bias stays as it is
+        for layer in self.output_layers.iter_mut() {
+            layer.weight.map_inplace(&mut mutate_fn);
+            if self.settings.output_bias {
+                layer.bias.map_inplace(&mut mutate_fn);
+            }
+        }
+    }
+
+    /// Changes each activation with the probability `rate`, and toggles the output bias with the same probability
+    pub fn mutate_architecture(&mut self, rate: f64, rng: &mut StdRng) {
+        for activation in self.settings.hidden_activations.iter_mut() {
+            if rng.gen_bool(rate) {
+                *activation = Activation::random(*activation, Activation::HIDDEN_DEFAULT, rng);
+            }
+        }
+        for activation in self.settings.output_activations.iter_mut() {
+            if rng.gen_bool(rate) {
+                *activation = Activation::random(*activation, Activation::OUTPUT_DEFAULT, rng);
+            }
+        }
+        if rng.gen_bool(rate) {
+            self.settings.output_bias = !self.settings.output_bias;
+        }
+    }
+
+    pub fn settings(&self) -> &ModelSettings {
+        &self.settings
+    }
+
+    /// Int8 copy of the model for the packed state
+    pub fn quantize(&self) -> QuantizedModel {
+        let quantize_layers = |layers: &[Layer]| -> Vec<QuantizedLayer> {
+            layers.iter().map(|layer| QuantizedLayer::new(&layer.weight, &layer.bias)).collect()
+        };
+        QuantizedModel::new(
+            self.settings.clone(),
+            self.input_weights.iter().map(QuantizedWeight::new).collect(),
+            self.input_bias.clone(),
+            quantize_layers(&self.hidden_layers),
+            quantize_layers(&self.output_layers),
+        )
+    }
+
+    /// Uniform crossover. Each weight and bias is taken from either self or other
+    /// Both models must have been created with the same sizes
+    pub fn crossover(&self, other: &Model, rng: &mut StdRng) -> Result<Self> {
+        ensure!(self.settings.input_sizes == other.settings.input_sizes, "Input sizes differ");
+        ensure!(self.settings.hidden_sizes == other.settings.hidden_sizes, "Hidden sizes differ");
+        ensure!(self.settings.output_sizes == other.settings.output_sizes, "Output sizes differ");
+
+        let mut child = self.clone();
+        for (weight, weight_other) in child.input_weights.iter_mut().zip(other.input_weights.iter()) {
+            crossover_inplace(weight, weight_other, rng);
+        }
+        crossover_inplace(&mut child.input_bias, &other.input_bias, rng);
+        let activations = child.settings.hidden_activations.iter_mut().chain(child.settings.output_activations.iter_mut());
+        let activations_other = other.settings.hidden_activations.iter().chain(other.settings.output_activations.iter());
+        for (activation, activation_other) in activations.zip(activations_other) {
+            if rng.gen_bool(0.5) {
+                *activation = *activation_other;
+            }
+        }
+        if rng.gen_bool(0.5) {
+            child.settings.output_bias = other.settings.output_bias;
+        }
+        let layers = child.hidden_layers.iter_mut().chain(child.output_layers.iter_mut());
+        let layers_other = other.hidden_layers.iter().chain(other.output_layers.iter());
+        for (layer, layer_other) in layers.zip(layers_other) {
+            crossover_inplace(&mut layer.weight, &layer_other.weight, rng);
+            crossover_inplace(&mut layer.bias, &layer_other.bias, rng);
+        }
+        Ok(child)
+    }
+
+    /// Copy with other input and output sizes, the hidden layers are kept. New weights are 0, so a new input has
+    /// no effect and a new output is only its bias (0 as well). Removed inputs and outputs are truncated
+    pub fn resize(&self, input_sizes: &[usize], output_sizes: &[usize]) -> Result<Self> {
+        ensure!(input_sizes.len() == self.settings.n_inputs, "Expected {} input sizes", self.settings.n_inputs);
+        ensure!(output_sizes.len() == self.settings.n_outputs, "Expected {} output sizes", self.settings.n_outputs);
+
+        let mut model = self.clone();
+        for (weight, size) in model.input_weights.iter_mut().zip(input_sizes.iter()) {
+            *weight = resize_axis(weight, Axis(0), *size);
+        }
+        for (layer, size) in model.output_layers.iter_mut().zip(output_sizes.iter()) {
+            layer.weight = resize_axis(&layer.weight, Axis(1), *size);
+            layer.bias = resize_axis(&layer.bias, Axis(0), *size);
+        }
+        model.settings.input_sizes = input_sizes.to_vec();
+        model.settings.output_sizes = output_sizes.to_vec();
+        Ok(model)
+    }
 }
 
 
@@ -148,6 +375,14 @@ impl Layer {
         }
     }
 
+    /// The bias is zero
+    pub fn new_without_bias(input_size: usize, output_size: usize, rng: &mut StdRng) -> Self {
+        Self {
+            weight: new_weight(input_size, output_size, rng),
+            bias: Array1::zeros(output_size)
+        }
+    }
+
     // Apply the forward computation on the input array
     pub fn forward_with_bias(&self, x: &Array) -> Array {
         let mut y = x.dot(&self.weight);
@@ -167,20 +402,15 @@ impl Layer {
     }
 }
 
-/// Clamping
-/// Does it inplace
-fn activation_fn(arr: Array) -> Array {
-    clip(arr, 0.0, 1.0)
-}
-
-/// Delta
-/// Can be negative
-fn activation_fn_output(arr: Array) -> Array {
-    clip(arr, -0.1, 0.1)
+fn check_clamps<'a>(activations: impl Iterator<Item = &'a Activation>) -> Result<()> {
+    for activation in activations {
+        if let Activation::Clamp { min, max } = *activation {
+            ensure!(min <= max, "Clamp min {min} is above its max {max}");
+        }
+    }
+    Ok(())
 }
 
-
-
 fn new_weight(
     input_size: usize,
     output_size: usize,
@@ -195,6 +425,28 @@ fn new_bias(size: usize, rng: &mut StdRng) -> Bias {
     Array1::random_using(size, dist, rng)
 }
 
+fn crossover_inplace<D>(arr: &mut ndarray::Array<f32, D>, other: &ndarray::Array<f32, D>, rng: &mut StdRng)
+    where D: Dimension
+{
+    Zip::from(arr).and(other).for_each(|v, v_other| {
+        if rng.gen_bool(0.5) {
+            *v = *v_other;
+        }
+    });
+}
+
+/// Pads with zeros or truncates along the axis
+fn resize_axis<D>(arr: &ndarray::Array<f32, D>, axis: Axis, size: usize) -> ndarray::Array<f32, D>
+    where D: Dimension
+{
+    let mut shape = arr.raw_dim();
+    shape[axis.index()] = size;
+    let mut resized = ndarray::Array::zeros(shape);
+    let kept = size.min(arr.len_of(axis));
+    resized.slice_axis_mut(axis, (0..kept).into()).assign(&arr.slice_axis(axis, (0..kept).into()));
+    resized
+}
+
 #[cfg(test)]
 pub mod tests {
     use rand::{distributions::Uniform, SeedableRng};
@@ -216,4 +468,76 @@ pub mod tests {
         );
         println!("OUTPUT:\n{res:#?}");
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn test_activations() {
+        let settings = ModelSettings::new(vec![4], vec![8], vec![2, 3]).unwrap()
+            .with_activations(vec![Activation::Relu], vec![Activation::Sigmoid, Activation::Clamp { min: -0.5, max: 0.0 }]).unwrap()
+            .with_output_bias(true);
+        let mut rng = StdRng::seed_from_u64(1);
+        let model = Model::new(settings.clone(), &mut rng).unwrap();
+        let x = Array2::random_using((4, 4), Uniform::new(0.0, 1.0), &mut rng);
+        let res = model.forward_from_precalc(&[(0, x.view())], &Array1::zeros(8));
+        assert!(res[0].iter().all(|v| *v > 0.0 && *v < 1.0));
+        assert!(res[1].iter().all(|v| (-0.5..=0.0).contains(v)));
+
+        // Wrong number of activations
+        let wrong = settings.clone().with_activations(vec![Activation::Relu; 2], vec![Activation::Identity; 2]);
+        assert!(wrong.is_err());
+
+        // Inverted clamp
+        let inverted = vec![Activation::Identity, Activation::Clamp { min: 1.0, max: -1.0 }];
+        assert!(settings.clone().with_activations(vec![Activation::Relu], inverted.clone()).is_err());
+        let mut inverted_settings = settings.clone();
+        inverted_settings.output_activations = inverted;
+        assert!(Model::new(inverted_settings, &mut rng).is_err());
+
+        // The output bias starts at zero and only mutates once enabled
+        let mut model = Model::new(settings.with_output_bias(false), &mut rng).unwrap();
+        model.mutate(1.0, 0.1, &mut rng);
+        assert!(model.output_layers.iter().all(|layer| layer.bias.iter().all(|v| *v == 0.0)));
+        model.settings.output_bias = true;
+        model.mutate(1.0, 0.1, &mut rng);
+        assert!(model.output_layers.iter().all(|layer| layer.bias.iter().any(|v| *v != 0.0)));
+    }
+
+    /// The ndarray path forward_from_precalc had before the workspace
+    fn reference_forward(model: &Model, inputs: &[(usize, ArrayView2<f32>)], precalculated: &Row) -> Vec<Array> {
+        let mut x = precalculated.view().insert_axis(ndarray::Axis(0)).to_owned();
+        for (i, input) in inputs {
+            x = x + input.dot(&model.input_weights[*i]);
+        }
+        x += &model.input_bias;
+        let mut activations = model.settings.hidden_activations.iter();
+        x = activations.next().unwrap().apply(x);
+        for (layer, activation) in model.hidden_layers.iter().zip(activations) {
+            x = activation.apply(layer.forward_with_bias(&x));
+        }
+        model.output_layers.iter().zip(model.settings.output_activations.iter())
+            .map(|(layer, activation)| activation.apply(layer.forward_weight(&x)))
+            .collect()
+    }
+
+    #[test]
+    pub fn test_workspace() {
+        let settings = ModelSettings::new(vec![16, 32], vec![64, 48, 64], vec![16, 32]).unwrap()
+            .with_activations(vec![Activation::Relu, Activation::Tanh, Activation::HIDDEN_DEFAULT], vec![Activation::Identity; 2]).unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let model = Model::new(settings, &mut rng).unwrap();
+        let state = Array1::random_using(32, Uniform::new(0.0, 1.0), &mut rng);
+        let precalculated = model.precalculate(1, state.view());
+        let expected = state.dot(&model.input_weights[1]);
+        assert!(precalculated.iter().zip(expected.iter()).all(|(a, b)| (a - b).abs() < 1e-5));
+
+        // A single row uses the row kernel, the batch uses gemm
+        for batch_size in [1, 5] {
+            let x = Array2::random_using((batch_size, 16), Uniform::new(0.0, 1.0), &mut rng);
+            let res = model.forward_from_precalc(&[(0, x.view())], &precalculated);
+            let expected = reference_forward(&model, &[(0, x.view())], &precalculated);
+            for (res, expected) in res.iter().zip(expected.iter()) {
+                assert_eq!(res.shape(), expected.shape());
+                assert!(res.iter().zip(expected.iter()).all(|(a, b)| (a - b).abs() < 1e-5));
+            }
+        }
+    }
+}
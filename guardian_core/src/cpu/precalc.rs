@@ -0,0 +1,141 @@
+//! Precalculated contributions of neuron states and nodes, shared by the stages of a step
+//!
+//! A contribution is keyed by (model, input slot, neuron or global node index). Each entry keeps the packed
+//! input it was calculated from, so entries calculated before a stage changed the state are recalculated.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+use ndarray::ArrayView1;
+
+use super::model::{Model, Row};
+use super::stats::Stats;
+use super::unpack_array;
+
+/// The models of Genome that are cached
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CachedModel {
+    InterconnectedNodeStateUpdate,
+    IntraconnectedNodeStateUpdate,
+    NeuronStateUpdate,
+    InterconnectionsPlasticityUpdate,
+    IntraconnectionsPlasticityUpdate,
+}
+
+struct Entry {
+    input: Vec<u8>,
+    precalculated: Row,
+}
+
+type Table = Vec<Mutex<Option<Entry>>>;
+
+pub struct PrecalcCache {
+    n_neurons: usize,
+    n_nodes: usize,
+    tables: RwLock<HashMap<(CachedModel, usize), Arc<Table>>>,  // One entry per neuron or node
+}
+
+impl PrecalcCache {
+    pub fn new(n_neurons: usize, n_nodes_per_neuron: usize) -> Self {
+        Self {
+            n_neurons,
+            n_nodes: n_neurons * n_nodes_per_neuron,
+            tables: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// model.precalculate of a neuron state
+    pub fn neuron(
+        &self,
+        cached_model: CachedModel,
+        model: &Model,
+        input_index: usize,
+        neuron_index: usize,
+        neuron_state: ArrayView1<u8>,
+        stats: &Stats,
+    ) -> Row {
+        self.get(cached_model, model, input_index, neuron_index, self.n_neurons, neuron_state, stats)
+    }
+
+    /// model.precalculate of a node
+    pub fn node(
+        &self,
+        cached_model: CachedModel,
+        model: &Model,
+        input_index: usize,
+        node_global_index: usize,
+        node: ArrayView1<u8>,
+        stats: &Stats,
+    ) -> Row {
+        self.get(cached_model, model, input_index, node_global_index, self.n_nodes, node, stats)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn get(
+        &self,
+        cached_model: CachedModel,
+        model: &Model,
+        input_index: usize,
+        index: usize,
+        n_entries: usize,
+        input: ArrayView1<u8>,
+        stats: &Stats,
+    ) -> Row {
+        let table = self.table((cached_model, input_index), n_entries);
+        let mut entry = table[index].lock().unwrap();
+        if let Some(entry) = entry.as_ref() {
+            if input.iter().eq(entry.input.iter()) {
+                stats.precalc_hits.inc();
+                return entry.precalculated.clone();
+            }
+        }
+        stats.precalc_misses.inc();
+        let precalculated = model.precalculate(input_index, unpack_array(input).view());
+        *entry = Some(Entry { input: input.to_vec(), precalculated: precalculated.clone() });
+        precalculated
+    }
+
+    fn table(&self, key: (CachedModel, usize), n_entries: usize) -> Arc<Table> {
+        if let Some(table) = self.tables.read().unwrap().get(&key) {
+            return table.clone();
+        }
+        let mut tables = self.tables.write().unwrap();
+        tables
+            .entry(key)
+            .or_insert_with(|| Arc::new((0..n_entries).map(|_| Mutex::new(None)).collect()))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rayon::ThreadPoolBuilder;
+
+    use crate::cpu::interface::Network;
+    use crate::cpu::process::update;
+    use crate::settings::Settings;
+    use super::*;
+
+    #[test]
+    pub fn test_precalc_cache() {
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let settings = Settings::preset("tiny").unwrap();
+        let mut network = Network::new(&settings.g_settings, &settings.n_settings, 1);
+        let stats = update(&mut network, &pool);
+        assert!(stats.precalc_hits.get() > 0);
+
+        let cache = PrecalcCache::new(network.n_settings.n_neurons, network.g_settings.n_nodes_per_neuron);
+        let model = &network.genome.neuron_state_update;
+        let neuron_state = network.state.neuron_states.row(1);
+        let expected = model.precalculate(0, unpack_array(neuron_state).view());
+        let miss = cache.neuron(CachedModel::NeuronStateUpdate, model, 0, 1, neuron_state, &stats);
+        let hit = cache.neuron(CachedModel::NeuronStateUpdate, model, 0, 1, neuron_state, &stats);
+        assert_eq!(miss, expected);
+        assert_eq!(hit, expected);
+
+        // A changed state is recalculated
+        let changed = neuron_state.mapv(|v| v.wrapping_add(1));
+        let expected = model.precalculate(0, unpack_array(changed.view()).view());
+        assert_eq!(cache.neuron(CachedModel::NeuronStateUpdate, model, 0, 1, changed.view(), &stats), expected);
+    }
+}
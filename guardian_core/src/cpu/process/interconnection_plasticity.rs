@@ -3,15 +3,18 @@ use std::time::Instant;
 
 use interface::NodeState;
 use tracing::trace;
-use ndarray::{Array1, Array2, Array3, Axis};
+use ndarray::{s, Array1, Array2, Array3, Axis};
 use itertools::multizip;
 use ndarray::parallel::prelude::*;
 use rayon::iter::ParallelBridge;
 use rayon::ThreadPool;
 
 use crate::cpu::model::Model;
+use crate::cpu::precalc::{CachedModel, PrecalcCache};
+use crate::cpu::stats::Stats;
 use crate::cpu::interface::{InterConnection, Network};
-use crate::{GuardianSettings, NetworkSettings};
+use crate::GuardianSettings;
+use crate::topology::Neighbourhood;
 use crate::cpu::*;
 
 // Input
@@ -21,16 +24,36 @@ const NODE_SELF: usize = 2;
 const NODE_OTHER: usize = 3;
 const FORCE_SELF: usize = 4;
 const FORCE_OTHER: usize = 5;
+const MODULATORS: usize = 8;  // After is main and is pending
 
 // Output
 const DELTA_FORCE_SELF: usize = 0;
 
-pub fn update(network: &mut Network, pool: &ThreadPool) {
-    update_connections(network, pool);
-    attempt_connection(network, pool);
+/// Read by every node update of update_connections
+struct Context<'a> {
+    model: &'a Model,
+    nodes: &'a Array3<u8>,
+    neuron_states: &'a Array2<u8>,
+    inter_connections: &'a Array2<InterConnection>,  // Main connections are written here
+    inter_connections_read: &'a Array2<InterConnection>,  // The snapshot if deterministic, otherwise the same as above
+    cache: &'a PrecalcCache,
+    neighbourhood: &'a Neighbourhood,
+    g_settings: &'a GuardianSettings,
+    stats: &'a Stats,
 }
 
-fn update_connections(network: &mut Network, pool: &ThreadPool) {
+/// Precalculated inputs of self, for both directions
+struct Precalculated {
+    forward: Array1<f32>,  // self -> other
+    backward: Array1<f32>,  // other -> self
+}
+
+pub fn update(network: &mut Network, pool: &ThreadPool, stats: &Stats, cache: &PrecalcCache) {
+    update_connections(network, pool, stats, cache);
+    attempt_connection(network, pool, stats);
+}
+
+fn update_connections(network: &mut Network, pool: &ThreadPool, stats: &Stats, cache: &PrecalcCache) {
     let nodes = &network.state.nodes;
     let neuron_states = &network.state.neuron_states;
     let counters = &network.state.inter_connection_counters;
@@ -40,6 +63,30 @@ fn update_connections(network: &mut Network, pool: &ThreadPool) {
     let g_settings = &network.g_settings;
     let n_settings = &network.n_settings;
     let model = &genome.interconnections_plasticity_update;
+    let precalculated_modulators = precalculate_modulators(model, MODULATORS, network.state.modulators.view());
+    let neighbourhood = network.neighbourhood.get(&g_settings.topology, n_settings.n_neurons, g_settings.n_interconnected_neuron_search);
+
+    // Deterministic: The main forces are compared against a copy made before the stage.
+    // Otherwise, they might be compared before or after the main connection has been updated, depending on the threads
+    let inter_connections_snapshot;
+    let inter_connections_read = if n_settings.deterministic {
+        inter_connections_snapshot = inter_connections_source.clone();
+        &inter_connections_snapshot
+    } else {
+        inter_connections_source
+    };
+
+    let context = Context {
+        model,
+        nodes,
+        neuron_states,
+        inter_connections: inter_connections_source,
+        inter_connections_read,
+        cache,
+        neighbourhood: &neighbourhood,
+        g_settings,
+        stats,
+    };
 
     let zipped = multizip(
         (
@@ -51,7 +98,7 @@ fn update_connections(network: &mut Network, pool: &ThreadPool) {
     );
 
     let now = Instant::now();
-    let operation = zipped
+    pool.install(|| zipped
     .into_iter()
     .enumerate()
     .par_bridge()
@@ -59,70 +106,48 @@ fn update_connections(network: &mut Network, pool: &ThreadPool) {
         let node_index_offset = neuron_index_self * g_settings.n_nodes_per_neuron;
 
         // Done here, so it can be used for all nodes in the neuron
-        let neuron_state = unpack_array(neuron_state);
-        let precalculated_neuron_forward = model.precalculate(NEURON_STATE_SELF, neuron_state.view());
-        let precalculated_neuron_backward = model.precalculate(NEURON_STATE_OTHER, neuron_state.view());
+        let cached_model = CachedModel::InterconnectionsPlasticityUpdate;
+        // Both directions get the same modulators
+        let precalculated_neuron_forward = cache.neuron(cached_model, model, NEURON_STATE_SELF, neuron_index_self, neuron_state, stats)
+            + &precalculated_modulators;
+        let precalculated_neuron_backward = cache.neuron(cached_model, model, NEURON_STATE_OTHER, neuron_index_self, neuron_state, stats)
+            + &precalculated_modulators;
 
         for (node_local_index_self, node_self) in node_states.outer_iter().enumerate() {
             let node_global_index_self = node_local_index_self + node_index_offset;
             let connection_self = inter_connections.get(node_local_index_self).unwrap();
-            let node_self = unpack_array(node_self);
             let counter_self = counters.get(node_local_index_self).unwrap();
 
-            let precalculated_node_forward = model.precalculate(NODE_SELF, node_self.view());
-            let precalculated_node_backward = model.precalculate(NODE_OTHER, node_self.view());
-
-            let precalculated_forward = &precalculated_neuron_forward + precalculated_node_forward;
-            let precalculated_backward = &precalculated_neuron_backward + precalculated_node_backward;
-
-            update_main_connection(
-                node_global_index_self,
-                connection_self,
-                model,
-                &precalculated_forward,
-                &precalculated_backward,
-                nodes,
-                neuron_states,
-                inter_connections_source,
-                g_settings
-            );
-            update_pending_connection(
-                node_global_index_self,
-                connection_self,
-                counter_self,
-                model,
-                &precalculated_forward,
-                &precalculated_backward,
-                nodes,
-                neuron_states,
-                inter_connections_source,
-                g_settings,
-                n_settings
-            );
+            let precalculated_node_forward = cache.node(cached_model, model, NODE_SELF, node_global_index_self, node_self, stats);
+            let precalculated_node_backward = cache.node(cached_model, model, NODE_OTHER, node_global_index_self, node_self, stats);
+
+            let precalculated = Precalculated {
+                forward: &precalculated_neuron_forward + precalculated_node_forward,
+                backward: &precalculated_neuron_backward + precalculated_node_backward,
+            };
+
+            update_main_connection(&context, node_global_index_self, connection_self, &precalculated);
+            update_pending_connection(&context, node_global_index_self, connection_self, counter_self, &precalculated);
         }
-    });
-    pool.install(|| operation);
+    }));
     trace!("It took {:?} to update interconnections", now.elapsed());
 }
 
 
 fn update_main_connection(
+    context: &Context,
     node_global_index_self: usize,
     connection_self: &InterConnection,
-    model: &Model,
-    precalculated_forward: &Array1<f32>,
-    precalculated_backward: &Array1<f32>,
-    nodes: &Array3<u8>,
-    neuron_states: &Array2<u8>,
-    inter_connections: &Array2<InterConnection>,
-    g_settings: &GuardianSettings,
+    precalculated: &Precalculated,
 ) {
+    let g_settings = context.g_settings;
+    let inter_connections = context.inter_connections;
     let node_global_index_other = connection_self.get_index();
     let (neuron_index_other, node_local_index_other) = node_global_to_local_index(node_global_index_other, g_settings);
     let connection_other = get_inter_connection(neuron_index_other, node_local_index_other, inter_connections);
 
     // Not connected anymore!
-    if !check_is_connected(node_global_index_self, connection_other) {
+    if !check_is_connected(node_global_index_self, node_global_index_other, connection_other) {
         connection_self.reset_main();
         return;
     } else if node_global_index_other > node_global_index_self {
@@ -132,16 +157,12 @@ fn update_main_connection(
 
     let (force_self, force_other) = connection_self.get_forces();
     let (delta_force_self, delta_force_other) = get_delta_forces(
-        neuron_index_other,
-        node_local_index_other,
-        force_self,
-        force_other,
-        model,
-        precalculated_forward,
-        precalculated_backward,
-        nodes,
-        neuron_states
-    );
+        context,
+        &[(neuron_index_other, node_local_index_other)],
+        &[force_self],
+        &[force_other],
+        precalculated,
+    )[0];
     let updated_force_self = force_self + delta_force_self;
     let updated_force_other = force_other + delta_force_other;
     connection_self.store_forces(updated_force_self, updated_force_other);
@@ -149,22 +170,18 @@ fn update_main_connection(
 }
 
 /// Search neurons side-by-side and the connecting neuron. Same there
-/// TODO: Split function? Very big input
+/// Only the pending part of connection_self is written, everything else is read from inter_connections_read
 fn update_pending_connection(
-    _node_global_index_self: usize,
+    context: &Context,
+    node_global_index_self: usize,
     connection_self: &InterConnection,
     counter: &CounterInterConnection,
-    model: &Model,
-    precalculated_forward: &Array1<f32>,
-    precalculated_backward: &Array1<f32>,
-    nodes: &Array3<u8>,
-    neuron_states: &Array2<u8>,
-    inter_connections: &Array2<InterConnection>,
-    g_settings: &GuardianSettings,
-    n_settings: &NetworkSettings
+    precalculated: &Precalculated,
 ) {
-    // TODO: Remove later, useful when debugging
-    //let (neuron_index_self, node_local_index_self) = node_global_to_local_index(_node_global_index_self, g_settings);
+    let g_settings = context.g_settings;
+    let inter_connections = context.inter_connections_read;
+    let stats = context.stats;
+    let (neuron_index_self, node_local_index_self) = node_global_to_local_index(node_global_index_self, g_settings);
 
     // Failed -> Searching. Will try one time, otherwise reset
     let failed_previous = match counter.get_state(g_settings) {
@@ -177,22 +194,14 @@ fn update_pending_connection(
     };
     match counter.get_state(g_settings) {
         NodeState::Searching => {
+            stats.searching_nodes.inc();
             let mut highest_net_force = f32::MIN;
             let mut forces = (f32::MIN, f32::MIN);
             let mut neuron_node_index = (0, 0);
-            let search = get_area_to_search(connection_self, inter_connections, g_settings, n_settings);
-            for (neuron_index, node_local_index) in search {
-                let (force_self, force_other) = get_delta_forces(
-                    neuron_index,
-                    node_local_index,
-                    0.0,
-                    0.0,
-                    model,
-                    precalculated_forward,
-                    precalculated_backward,
-                    nodes,
-                    neuron_states
-                );
+            let search = get_area_to_search(connection_self, inter_connections, context.neighbourhood, g_settings);
+            let zero_forces = vec![0.0; search.len()];
+            let delta_forces = get_delta_forces(context, &search, &zero_forces, &zero_forces, precalculated);
+            for ((neuron_index, node_local_index), (force_self, force_other)) in search.into_iter().zip(delta_forces) {
                 let net_force = force_self + force_other;
                 if net_force > highest_net_force {
                     forces = (force_self, force_other);
@@ -206,27 +215,25 @@ fn update_pending_connection(
             if failed_previous && highest_index == pending_index {
                 // Stuck in a local maxima. Force reset
                 connection_self.reset_pending();
+                stats.reset_pending_index.inc();
             } else if pending_index == highest_index {  // found local maximum, nothing higher around. Attempt connection
                 counter.inc();
                 connection_self.store_pending_forces(forces.0, forces.1);
             }
         }
         NodeState::Connecting => {
+            stats.connecting_nodes.inc();
             let node_global_index_other = connection_self.get_pending_index();
             let (neuron_index_other, node_local_index_other) = node_global_to_local_index(node_global_index_other, g_settings);
             let connection_other = get_inter_connection(neuron_index_other, node_local_index_other, inter_connections);
             let (force_self, force_other) = connection_self.get_pending_forces();
             let (delta_force_self, delta_force_other) = get_delta_forces(
-                neuron_index_other,
-                node_local_index_other,
-                force_self,
-                force_other,
-                model,
-                precalculated_forward,
-                precalculated_backward,
-                nodes,
-                neuron_states
-            );
+                context,
+                &[(neuron_index_other, node_local_index_other)],
+                &[force_self],
+                &[force_other],
+                precalculated,
+            )[0];
             let updated_force_self = force_self + delta_force_self;
             let updated_force_other = force_other + delta_force_other;
             connection_self.store_pending_forces(
@@ -236,9 +243,10 @@ fn update_pending_connection(
             let net_force = connection_self.get_net_pending_force();
             let net_force_other_to_beat = connection_other.get_net_force();
             // TODO: Think about this one! Maybe just enough to beat force_self?
-            let net_force_self_to_beat = connection_self.get_net_force();
+            let net_force_self_to_beat = get_inter_connection(neuron_index_self, node_local_index_self, inter_connections).get_net_force();
             if net_force > net_force_other_to_beat && net_force > net_force_self_to_beat {
                 counter.saturate();
+                stats.takeover_attempts.inc();
             } else {
                 counter.inc();
             }
@@ -250,8 +258,8 @@ fn update_pending_connection(
 fn get_area_to_search(
     connection_self: &InterConnection,
     inter_connections: &Array2<InterConnection>,
+    neighbourhood: &Neighbourhood,
     g_settings: &GuardianSettings,
-    n_settings: &NetworkSettings
 ) -> Vec<(usize, usize)> {
     // Skip main
     let (main_neuron_index, main_node_index) = node_global_to_local_index(connection_self.get_index(), g_settings);
@@ -262,7 +270,6 @@ fn get_area_to_search(
     let (neuron_index_other, node_index_other) = node_global_to_local_index(connection_other.get_index(), g_settings);
 
     // NOTE: Needs to be inclusive! Otherwise, if 1, it will be -1..1 -> -1 and 0
-    let neuron_range: Vec<isize> = (-(g_settings.n_interconnected_neuron_search as isize)..=(g_settings.n_interconnected_neuron_search as isize)).collect();
     let node_range: Vec<isize> = (-(g_settings.n_interconnected_nodes_search as isize)..=(g_settings.n_interconnected_nodes_search as isize)).collect();
 
     // This could be optimized to skip the vector
@@ -272,8 +279,7 @@ fn get_area_to_search(
         (neuron_index_other, node_index_other)
     ];
     for (start_neuron_index, start_node_index) in start_points {
-        for neuron_offset in neuron_range.iter() {
-            let neuron_index = wrap_index(start_neuron_index, *neuron_offset, n_settings.n_neurons);
+        for neuron_index in neighbourhood.neighbours(start_neuron_index).iter().copied() {
             for node_offset in node_range.iter() {
                 let node_local_index = wrap_index(start_node_index, *node_offset, g_settings.n_nodes_per_neuron);
                 if neuron_index == main_neuron_index && main_node_index == node_local_index {
@@ -287,49 +293,57 @@ fn get_area_to_search(
 }
 
 
+/// Forces between self and each of the other nodes, in one batch
+/// The neuron states and nodes of the others come from the cache, only the forces are passed to the model
 fn get_delta_forces(
-    neuron_index: usize,
-    node_local_index: usize,
-    force_self: f32,
-    force_other: f32,
-    model: &Model,
-    precalculated_forward: &Array1<f32>,
-    precalculated_backward: &Array1<f32>,
-    nodes: &Array3<u8>,
-    neuron_states: &Array2<u8>,
-) -> (f32, f32) {
-    // Could optimize this, so it reuses the neuron state if it already exist
-    let neuron_state_other = get_neuron_state(neuron_index, neuron_states);
-    let node_other = get_node(neuron_index, node_local_index, nodes);
-
-    let force_self = value_to_array(force_self);
-    let force_other = value_to_array(force_other);
-
-    // self -> other
-    let inputs = [
-        (NEURON_STATE_OTHER, expand(neuron_state_other.view())),
-        (NODE_OTHER, expand(node_other.view())),
-        (FORCE_SELF, force_self.view()),
-        (FORCE_OTHER, force_other.view())
-    ];
-    let output = model.forward_from_precalc(&inputs, precalculated_forward);  // neuron_self, node_self
-    let delta_force_self = *output[DELTA_FORCE_SELF].first().unwrap();
-
-    // other -> self
-    let inputs = [
-        (NEURON_STATE_SELF, expand(neuron_state_other.view())),
-        (NODE_SELF, expand(node_other.view())),
-        (FORCE_SELF, force_other.view()),
-        (FORCE_OTHER, force_self.view())
-    ];
-    let output = model.forward_from_precalc(&inputs, precalculated_backward);  // neuron_other, node_other
-    let delta_force_other = *output[DELTA_FORCE_SELF].first().unwrap();
+    context: &Context,
+    others: &[(usize, usize)],
+    forces_self: &[f32],
+    forces_other: &[f32],
+    precalculated: &Precalculated,
+) -> Vec<(f32, f32)> {
+    if others.is_empty() {
+        return vec![];
+    }
+    let Context { model, nodes, neuron_states, cache, g_settings, stats, .. } = *context;
+    let (precalculated_forward, precalculated_backward) = (&precalculated.forward, &precalculated.backward);
+
+    let cached_model = CachedModel::InterconnectionsPlasticityUpdate;
+    let mut precalculated_rows_forward = Array2::zeros((others.len(), precalculated_forward.len()));
+    let mut precalculated_rows_backward = Array2::zeros((others.len(), precalculated_backward.len()));
+    let rows = multizip((others, precalculated_rows_forward.rows_mut(), precalculated_rows_backward.rows_mut()));
+    for ((neuron_index, node_local_index), mut row_forward, mut row_backward) in rows {
+        let neuron_state_other = neuron_states.row(*neuron_index);
+        let node_other = nodes.slice(s![*neuron_index, *node_local_index, ..]);
+        let node_global_index = node_local_to_global_index(*neuron_index, *node_local_index, g_settings);
+
+        // self -> other
+        row_forward.assign(precalculated_forward);
+        row_forward += &cache.neuron(cached_model, model, NEURON_STATE_OTHER, *neuron_index, neuron_state_other, stats);
+        row_forward += &cache.node(cached_model, model, NODE_OTHER, node_global_index, node_other, stats);
+
+        // other -> self
+        row_backward.assign(precalculated_backward);
+        row_backward += &cache.neuron(cached_model, model, NEURON_STATE_SELF, *neuron_index, neuron_state_other, stats);
+        row_backward += &cache.node(cached_model, model, NODE_SELF, node_global_index, node_other, stats);
+    }
+
+    let forces_self = values_to_array(forces_self);
+    let forces_other = values_to_array(forces_other);
+
+    let inputs = [(FORCE_SELF, forces_self.view()), (FORCE_OTHER, forces_other.view())];
+    let output_forward = model.forward_from_precalc_rows(&inputs, precalculated_rows_forward.view());  // neuron_self, node_self
+
+    let inputs = [(FORCE_SELF, forces_other.view()), (FORCE_OTHER, forces_self.view())];
+    let output_backward = model.forward_from_precalc_rows(&inputs, precalculated_rows_backward.view());  // neuron_other, node_other
 
-    (delta_force_self, delta_force_other)
+    let delta_forces_self = output_forward[DELTA_FORCE_SELF].column(0);
+    let delta_forces_other = output_backward[DELTA_FORCE_SELF].column(0);
+    delta_forces_self.iter().copied().zip(delta_forces_other.iter().copied()).collect()
 }
 
 
-pub fn attempt_connection(network: &mut Network, pool: &ThreadPool) {
+pub fn attempt_connection(network: &mut Network, pool: &ThreadPool, stats: &Stats) {
     let inter_connection_counters = &network.state.inter_connection_counters;
     let inter_connections_source = &network.state.inter_connections;
     let g_settings = &network.g_settings;
@@ -346,8 +360,10 @@ pub fn attempt_connection(network: &mut Network, pool: &ThreadPool) {
         .par_bridge();
 
     // Step 1: Check if other is also connecting, otherwise, try to connect
+    // NOTE: The other counter can change from AttemptingTakeover to Failed while being read.
+    // Both lead to the same outcome, and fetch_max does not depend on the order, so this is deterministic
     let now = Instant::now();
-    let operation = zipped_iter.clone()
+    pool.install(|| zipped_iter.clone()
     .for_each(|(_neuron_index_self, (inter_connections, counters))| {
         let iter = inter_connections.iter().zip(counters);
         for (_node_local_index_self, (connection_self, counter_self)) in iter.enumerate() {
@@ -378,13 +394,12 @@ pub fn attempt_connection(network: &mut Network, pool: &ThreadPool) {
                 _ => {}
             }
         }
-    });
-    pool.install(|| operation);
+    }));
     trace!("It took {:?} to check the counters", now.elapsed());
 
     // Step 2: Could be multiple "winners". If multiple that have the exact same value, the highest index wins
     let now = Instant::now();
-    let operation = zipped_iter.clone()
+    pool.install(|| zipped_iter.clone()
     .for_each(|(neuron_index_self, (inter_connections, counters))| {
         let iter = inter_connections.iter().zip(counters);
         for (node_local_index_self, (connection_self, counter_self)) in iter.enumerate() {
@@ -405,22 +420,23 @@ pub fn attempt_connection(network: &mut Network, pool: &ThreadPool) {
                         // It failed, did not win the competition. Go back to searching
                         connection_self.reset_pending();
                         counter_self.reset();
+                        stats.failed_connections.inc();
                     }
                 },
                 NodeState::Failed => {
                     connection_self.reset_pending();
                     counter_self.reset();
+                    stats.failed_connections.inc();
                 },
                 _ => {}
             }
         }
-    });
-    pool.install(|| operation);
+    }));
     trace!("It took {:?} to check competition", now.elapsed());
 
     // Step 3: Check if it won, in that case, establish the connection
     let now = Instant::now();
-    let operation = zipped_iter.clone()
+    pool.install(|| zipped_iter.clone()
     .for_each(|(neuron_index_self, (inter_connections, counters))| {
         let iter = inter_connections.iter().zip(counters).enumerate();
         for (node_local_index_self, (connection_self, counter_self)) in iter {
@@ -437,20 +453,21 @@ pub fn attempt_connection(network: &mut Network, pool: &ThreadPool) {
                     if connection_other.get_index() != node_global_index_self {
                         // Failed, something else with a higher index won
                         connection_self.reset_pending();
+                        stats.failed_connections.inc();
                     } else {
                         // Index has already been set, just forces left
                         // NOTE: This order should be correct, might not synced properly otherwise with atomics
                         let (force_self, force_other) = connection_self.get_pending_forces();
                         connection_self.move_pending_to_main();
                         connection_other.store_forces(force_other, force_self);  // Yes, it should be this way
+                        stats.new_connections.inc();
                     }
                     counter_self.reset();  // Always reset here, no matter what happens
                 },
                 _ => {}
             }
         }
-    });
-    pool.install(|| operation);
+    }));
     trace!("It took {:?} to check competition", now.elapsed());
 }
 
@@ -1,15 +1,17 @@
 use std::time::Instant;
 
 use tracing::trace;
-use ndarray::{Array1, Array2, Array3, Axis};
+use ndarray::{Array1, Array2, Array3, ArrayView1, Axis};
 use itertools::multizip;
 use ndarray::parallel::prelude::*;
 use rayon::iter::ParallelBridge;
 use rayon::ThreadPool;
 
 use crate::cpu::interface::Network;
+use crate::cpu::stats::Stats;
 use crate::{GuardianSettings, InterConnection};
 use crate::cpu::model::Model;
+use crate::cpu::precalc::{CachedModel, PrecalcCache};
 use crate::cpu::*;
 
 // Input
@@ -23,7 +25,9 @@ const FORCE_OTHER: usize = 5;
 // Output
 const DELTA_NODE_STATE_SELF: usize = 0;
 
-pub fn update(network: &mut Network, pool: &ThreadPool) {
+/// Nodes are read from the state and the new values are written after all have been calculated.
+/// Each node is calculated once: Connected nodes by the one with the highest index of the pair, otherwise by itself
+pub fn update(network: &mut Network, pool: &ThreadPool, stats: &Stats, cache: &PrecalcCache) {
     let nodes = &network.state.nodes;
     let neuron_states = &network.state.neuron_states;
     let inter_connections_source = &network.state.inter_connections;
@@ -32,6 +36,14 @@ pub fn update(network: &mut Network, pool: &ThreadPool) {
     let g_settings = &network.g_settings;
     let model = &genome.interconnected_node_state_update;
 
+    let context = Context {
+        nodes,
+        neuron_states,
+        inter_connections: inter_connections_source,
+        g_settings,
+        stats,
+    };
+
     let zipped = multizip(
         (
             neuron_states.outer_iter(),
@@ -41,80 +53,89 @@ pub fn update(network: &mut Network, pool: &ThreadPool) {
     );
 
     let now = Instant::now();
-    let operation = zipped
+    let node_writes: Vec<Vec<(usize, Array1<u8>)>> = pool.install(|| zipped
     .into_iter()
     .enumerate()
     .par_bridge()
-    .for_each(|(neuron_index_self, (neuron_state, node_states, inter_connections))| {
+    .map(|(neuron_index_self, (neuron_state, node_states, inter_connections))| {
         let node_index_offset = neuron_index_self * g_settings.n_nodes_per_neuron;
-        let neuron_state = unpack_array(neuron_state);
-
-        // Done here, so it can be used for all nodes in the neuron
-
-        // self -> other
-        let precalculated_forward = model.precalculate(NEURON_STATE_SELF, neuron_state.view());
-        let precalculated_forward = &precalculated_forward;
-
-        // other -> self
-        let precalculated_backward = model.precalculate(NEURON_STATE_OTHER, neuron_state.view());
-        let precalculated_backward = &precalculated_backward;
 
+        // Gather the nodes calculated by this neuron
+        let mut batch = NodeBatch::default();
         for (node_local_index_self, node_state_self) in node_states.outer_iter().enumerate() {
-
-            // Get self
             let node_state_self = unpack_array(node_state_self);
             let connection_self = inter_connections.get(node_local_index_self).unwrap();
             let node_global_index_self = node_local_index_self + node_index_offset;
 
-            update_node_state(
-                node_global_index_self,
-                node_state_self,
-                connection_self,
-                precalculated_forward,
-                precalculated_backward,
-                model,
-                nodes,
-                neuron_states,
-                inter_connections_source,
-                g_settings
-            );
+            add_node_state(&context, node_global_index_self, node_state_self, connection_self, &mut batch);
         }
-    });
+        update_node_states(&batch, neuron_index_self, neuron_state, model, cache, stats)
+    })
+    .collect());
+
+    // Every node is in the writes exactly once, so the order does not matter: A disconnected node, including one
+    // pointing to itself, is written by itself. A connected pair is written by the node with the highest index
+    let nodes = &mut network.state.nodes;
+    for (node_global_index, node_state) in node_writes.into_iter().flatten() {
+        let (neuron_index, node_local_index) = node_global_to_local_index(node_global_index, g_settings);
+        nodes.slice_mut(s![neuron_index, node_local_index, ..]).assign(&node_state);
+    }
     trace!("It took {:?} to run interconnected node state update", now.elapsed());
-    pool.install(|| operation);
 }
 
 
-/// This updates the state and main connections, thus pending cannot be done here
-fn update_node_state(
+/// Read by add_node_state
+struct Context<'a> {
+    nodes: &'a Array3<u8>,
+    neuron_states: &'a Array2<u8>,
+    inter_connections: &'a Array2<InterConnection>,
+    g_settings: &'a GuardianSettings,
+    stats: &'a Stats,
+}
+
+
+/// The nodes calculated by one neuron, one row each
+#[derive(Default)]
+struct NodeBatch {
+    node_global_indices: Vec<(usize, usize)>,  // (self, other)
+    connected_rows: Vec<usize>,  // Rows where the other node is also calculated
+    neuron_states_other: Vec<Array1<f32>>,
+    node_states_self: Vec<Array1<f32>>,
+    node_states_other: Vec<Array1<f32>>,
+    forces_self: Vec<f32>,
+    forces_other: Vec<f32>,
+}
+
+
+/// This updates the main connections, thus pending cannot be done here
+fn add_node_state(
+    context: &Context,
     node_global_index_self: usize,
-    mut node_state_self: Array1<f32>,
+    node_state_self: Array1<f32>,
     connection_self: &InterConnection,
-    precalculated_forward: &Array1<f32>,
-    precalculated_backward: &Array1<f32>,
-    model: &Model,
-    nodes: &Array3<u8>,
-    neuron_states: &Array2<u8>,
-    inter_connections: &Array2<InterConnection>,
-    g_settings: &GuardianSettings,
+    batch: &mut NodeBatch,
 ) {
+    let Context { nodes, neuron_states, inter_connections, g_settings, stats } = *context;
 
     // Get other
     let node_global_index_other = connection_self.get_index();
     let (neuron_b_index, node_b_local_index) = node_global_to_local_index(node_global_index_other, g_settings);
     let connection_other = get_inter_connection(neuron_b_index, node_b_local_index, inter_connections);
 
-    let is_connected = check_is_connected(node_global_index_self, connection_other);
-    let (neuron_state_other,  mut node_state_other) = if is_connected {
+    let is_connected = check_is_connected(node_global_index_self, node_global_index_other, connection_other);
+    let (neuron_state_other, node_state_other) = if is_connected {
         if node_global_index_other > node_global_index_self { return; }  // Only highest index calculates if connected
+        stats.connected_node_updates.inc();
+        batch.connected_rows.push(batch.node_global_indices.len());
         (
             get_neuron_state(neuron_b_index, neuron_states),
             get_node(neuron_b_index, node_b_local_index, nodes)
         )
     } else {
         // NOTE: Could skip also, but then the node would behave as a intra-node
-        // Could skip 0 input for faster processing!
+        // The model skips the zero rows
         connection_self.reset_main();
+        stats.disconnected_node_updates.inc();
         (
             Array1::zeros(g_settings.neuron_state_size),
             Array1::zeros(g_settings.node_size)
@@ -122,60 +143,113 @@ fn update_node_state(
     };
 
     let (force_self, force_other) = connection_self.get_forces();  // Copies them here
-    let (force_self_arr, force_other_arr) = (value_to_array(force_self), value_to_array(force_other));
+    batch.node_global_indices.push((node_global_index_self, node_global_index_other));
+    batch.neuron_states_other.push(neuron_state_other);
+    batch.node_states_self.push(node_state_self);
+    batch.node_states_other.push(node_state_other);
+    batch.forces_self.push(force_self);
+    batch.forces_other.push(force_other);
+}
 
-    let node_state_self_clone = node_state_self.clone();
 
-    // Calculate forward
+/// Runs the batch forward (self) and backward (other, only if connected)
+/// Returns the new node states as (global index, packed node state)
+fn update_node_states(
+    batch: &NodeBatch,
+    neuron_index: usize,
+    neuron_state: ArrayView1<u8>,
+    model: &Model,
+    cache: &PrecalcCache,
+    stats: &Stats,
+) -> Vec<(usize, Array1<u8>)> {
+    let mut node_writes = Vec::with_capacity(batch.node_global_indices.len() + batch.connected_rows.len());
+    if batch.node_global_indices.is_empty() {
+        return node_writes;
+    }
+    let neuron_states_other = stack_rows(&batch.neuron_states_other);
+    let node_states_self = stack_rows(&batch.node_states_self);
+    let node_states_other = stack_rows(&batch.node_states_other);
+    let forces_self = values_to_array(&batch.forces_self);
+    let forces_other = values_to_array(&batch.forces_other);
+
+    // Calculate forward (self -> other)
+    let cached_model = CachedModel::InterconnectedNodeStateUpdate;
+    let precalculated_forward = cache.neuron(cached_model, model, NEURON_STATE_SELF, neuron_index, neuron_state, stats);
     let inputs = [
-        (NEURON_STATE_OTHER, expand(neuron_state_other.view())),
-        (NODE_STATE_SELF, expand(node_state_self_clone.view())),
-        (NODE_STATE_OTHER, expand(node_state_other.view())),
-        (FORCE_SELF, force_self_arr.view()),
-        (FORCE_OTHER, force_other_arr.view()),
+        (NEURON_STATE_OTHER, neuron_states_other.view()),
+        (NODE_STATE_SELF, node_states_self.view()),
+        (NODE_STATE_OTHER, node_states_other.view()),
+        (FORCE_SELF, forces_self.view()),
+        (FORCE_OTHER, forces_other.view()),
     ];
-    let output = &model.forward_from_precalc(&inputs, precalculated_forward);
-    let delta_node_self = &output[DELTA_NODE_STATE_SELF];
-    node_state_self = node_state_self + squeeze(delta_node_self.view());
-
-    unsafe {
-        write_node_non_locking_write(nodes, node_state_self, node_global_index_other, g_settings);
+    let output = &model.forward_from_precalc(&inputs, &precalculated_forward);
+    let updated_node_states_self = pack_array(&node_states_self + &output[DELTA_NODE_STATE_SELF]);
+    for ((node_global_index_self, _), node_state) in batch.node_global_indices.iter().zip(updated_node_states_self.outer_iter()) {
+        node_writes.push((*node_global_index_self, node_state.to_owned()));
     }
 
     // If not connected, this could be skipped
-    if !is_connected {
-        return;
+    if batch.connected_rows.is_empty() {
+        return node_writes;
     }
 
-    // Calculate backward (flipped)
+    // Calculate backward (flipped, other -> self)
+    let rows = &batch.connected_rows;
+    let neuron_states_other = neuron_states_other.select(Axis(0), rows);
+    let node_states_other = node_states_other.select(Axis(0), rows);
+    let node_states_self = node_states_self.select(Axis(0), rows);
+    let forces_self = forces_self.select(Axis(0), rows);
+    let forces_other = forces_other.select(Axis(0), rows);
+    let precalculated_backward = cache.neuron(cached_model, model, NEURON_STATE_OTHER, neuron_index, neuron_state, stats);
     let inputs = [
-        (NEURON_STATE_SELF, expand(neuron_state_other.view())),
-        (NODE_STATE_SELF, expand(node_state_other.view())),
-        (NODE_STATE_OTHER, expand(node_state_self_clone.view())),
-        (FORCE_SELF, force_other_arr.view()),
-        (FORCE_OTHER, force_self_arr.view()),
+        (NEURON_STATE_SELF, neuron_states_other.view()),
+        (NODE_STATE_SELF, node_states_other.view()),
+        (NODE_STATE_OTHER, node_states_self.view()),
+        (FORCE_SELF, forces_other.view()),
+        (FORCE_OTHER, forces_self.view()),
     ];
-
-    let delta_node_other = &model.forward_from_precalc(&inputs, precalculated_backward)[DELTA_NODE_STATE_SELF];
-    node_state_other = node_state_other + squeeze(delta_node_other.view());
-
-    // Write backwards
-    unsafe {
-        write_node_non_locking_write(nodes, node_state_other, node_global_index_other, g_settings);
+    let output = &model.forward_from_precalc(&inputs, &precalculated_backward);
+    let updated_node_states_other = pack_array(node_states_other + &output[DELTA_NODE_STATE_SELF]);
+    for (row, node_state) in rows.iter().zip(updated_node_states_other.outer_iter()) {
+        let (_, node_global_index_other) = batch.node_global_indices[*row];
+        node_writes.push((node_global_index_other, node_state.to_owned()));
     }
+    node_writes
 }
 
-
-unsafe fn write_node_non_locking_write(
-    nodes: &Array3<u8>,
-    node_state: Array1<f32>,
-    node_global_index: usize,
-    g_settings: &GuardianSettings
-) {
-    // Write forward
-    let ptr = nodes.as_ptr() as *mut u8;
-    let node_state_packed = pack_array(node_state);
-    let offset = node_global_index * g_settings.node_size;
-    let data = node_state_packed.as_slice().unwrap();
-    unsafe { non_locking_write(ptr, offset, g_settings.node_size, data); }
-}
\ No newline at end of file
+#[cfg(test)]
+pub mod tests {
+    use rayon::ThreadPoolBuilder;
+
+    use crate::settings::Settings;
+    use super::*;
+
+    /// Every node must get its own new state. The deltas are at most 0.1, so the nodes of neuron 0 stay low and
+    /// the nodes of neuron 1 stay high, unless a node is written with the state of the node it points to
+    #[test]
+    pub fn test_node_writes() {
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let mut settings = Settings::preset("tiny").unwrap();
+        settings.n_settings.n_neurons = 2;
+        let mut network = Network::new(&settings.g_settings, &settings.n_settings, 1);
+        let n_nodes = network.g_settings.n_nodes_per_neuron;
+        network.state.nodes.slice_mut(s![0, .., ..]).fill(0);
+        network.state.nodes.slice_mut(s![1, .., ..]).fill(255);
+
+        // Only node 0 of both neurons are connected to each other. Node 1 of neuron 0 points to itself
+        for node_local_index in 0..n_nodes {
+            network.state.inter_connections[[0, node_local_index]].store_index(n_nodes + node_local_index);
+            let index_other = if node_local_index == 0 { 0 } else { (node_local_index + 1) % n_nodes };
+            network.state.inter_connections[[1, node_local_index]].store_index(index_other);
+        }
+        network.state.inter_connections[[0, 1]].store_index(1);
+
+        let stats = Stats::new();
+        let cache = PrecalcCache::new(2, n_nodes);
+        update(&mut network, &pool, &stats, &cache);
+        assert_eq!(stats.connected_node_updates.get(), 1);
+        assert_eq!(stats.disconnected_node_updates.get() as usize, 2 * n_nodes - 2);
+        assert!(network.state.nodes.slice(s![0, .., ..]).iter().all(|v| *v < 64));
+        assert!(network.state.nodes.slice(s![1, .., ..]).iter().all(|v| *v > 191));
+    }
+}
@@ -2,7 +2,7 @@ use std::time::Instant;
 use std::ops::Range;
 
 use itertools::Itertools;
-use ndarray::{Array1, Array2, Axis};
+use ndarray::{Array1, Array2, ArrayViewMut1, Axis};
 use itertools::multizip;
 use ndarray::parallel::prelude::*;
 use rayon::iter::ParallelBridge;
@@ -11,6 +11,8 @@ use tracing::trace;
 
 use interface::{CounterIntraConnection, IntraConnection, Network, NodeState};
 use crate::cpu::model::Model;
+use crate::cpu::precalc::{CachedModel, PrecalcCache};
+use crate::cpu::stats::Stats;
 use crate::GuardianSettings;
 use crate::cpu::*;
 
@@ -20,18 +22,20 @@ const NODE_STATE_SELF: usize = 1;
 const NODE_STATE_OTHER: usize = 2;
 const FORCE_SELF: usize = 3;
 const FORCE_OTHER: usize = 4;
+const MODULATORS: usize = 7;  // After is main and is pending
 
 // Output
 const DELTA_FORCE_SELF: usize = 0;
 const DELTA_FORCE_OTHER: usize = 1;
 
-pub fn update(network: &mut Network, pool: &ThreadPool) {
+pub fn update(network: &mut Network, pool: &ThreadPool, stats: &Stats, cache: &PrecalcCache) {
     let nodes = &network.state.nodes;
     let neuron_states = &network.state.neuron_states;
     let intra_connections = &mut network.state.intra_connections;
     let inter_connection_counters = &mut network.state.intra_connection_counters;
     let genome = &network.genome;
     let model = &genome.intraconnections_plasticity_update;
+    let precalculated_modulators = precalculate_modulators(model, MODULATORS, network.state.modulators.view());
     let g_settings = &network.g_settings;
 
     let zipped = multizip(
@@ -44,26 +48,30 @@ pub fn update(network: &mut Network, pool: &ThreadPool) {
     );
 
     let now = Instant::now();
-    let operation = zipped
+    pool.install(|| zipped
     .into_iter()
     .enumerate()
     .par_bridge()
-    .for_each(|(_neuron_index, (neuron_state, node_states, mut intra_connections, mut counters))| {
-        let neuron_state = unpack_array(neuron_state);
-        let node_states = unpack_array(node_states.view());
-        let precalculated_neuron_state_self = model.precalculate(NEURON_STATE, neuron_state.view());
-        for (node_local_index_self, node_state_self) in node_states.rows().into_iter().enumerate() {
-            let precalculated_node_state_self = model.precalculate(NODE_STATE_SELF, node_state_self);
+    .for_each(|(neuron_index, (neuron_state, node_states_source, mut intra_connections, mut counters))| {
+        let node_states = unpack_array(node_states_source.view());
+        let cached_model = CachedModel::IntraconnectionsPlasticityUpdate;
+        let precalculated_neuron_state_self = cache.neuron(cached_model, model, NEURON_STATE, neuron_index, neuron_state, stats)
+            + &precalculated_modulators;
+        for (node_local_index_self, node_state_self) in node_states_source.outer_iter().enumerate() {
+            let node_global_index_self = node_local_to_global_index(neuron_index, node_local_index_self, g_settings);
+            let precalculated_node_state_self = cache.node(
+                cached_model, model, NODE_STATE_SELF, node_global_index_self, node_state_self, stats
+            );
             let precalculated = &precalculated_neuron_state_self + precalculated_node_state_self;
             let mut node_intra_connections = intra_connections.row_mut(node_local_index_self);
+            update_main_connections(
+                node_intra_connections.view_mut(),
+                model,
+                &precalculated,
+                &node_states
+            );
             for (connection_index, connection) in node_intra_connections.iter_mut().enumerate() {
                 let counter = counters.get_mut((node_local_index_self, connection_index)).unwrap();
-                update_main_connection(
-                    connection,
-                    model,
-                    &precalculated,
-                    &node_states
-                );
                 update_pending_connection(
                     connection,
                     model,
@@ -71,6 +79,7 @@ pub fn update(network: &mut Network, pool: &ThreadPool) {
                     &node_states,
                     counter,
                     g_settings,
+                    stats
                 );
             }
 
@@ -117,58 +126,59 @@ pub fn update(network: &mut Network, pool: &ThreadPool) {
                     connection.reset_pending();
                     counter.reset();
                     connection.store_pending_index(new_index);
+                    stats.failed_intra_connections.inc();
                 }
             }
     }
-    });
-    pool.install(|| operation);
+    }));
     trace!("It took {:?} to update intraconnections", now.elapsed());
 }
 
 
-fn get_pending_delta_forces(
-    node_index_other: usize,
-    force_self: f32,
-    force_other: f32,
+/// Forces between self and each of the other nodes, in one batch
+fn get_delta_forces(
+    node_indices_other: &[usize],
+    forces_self: &[f32],
+    forces_other: &[f32],
     model: &Model,
     precalculated: &Array1<f32>,
     nodes: &Array2<f32>,
-) -> (f32, f32) {
-    let node_state_other = nodes.slice(s![node_index_other, ..]);
-    let force_self_arr = value_to_array(force_self);
-    let force_other_arr = value_to_array(force_other);
+) -> Vec<(f32, f32)> {
+    let node_states_other = nodes.select(Axis(0), node_indices_other);
+    let forces_self_arr = values_to_array(forces_self);
+    let forces_other_arr = values_to_array(forces_other);
     let inputs = [
-        (NODE_STATE_OTHER, expand(node_state_other.view())),
-        (FORCE_SELF, force_self_arr.view()),
-        (FORCE_OTHER, force_other_arr.view())
+        (NODE_STATE_OTHER, node_states_other.view()),
+        (FORCE_SELF, forces_self_arr.view()),
+        (FORCE_OTHER, forces_other_arr.view())
     ];
     let output = model.forward_from_precalc(&inputs, precalculated);
-    let delta_force_self = *output[DELTA_FORCE_SELF].first().unwrap();
-    let delta_force_other = *output[DELTA_FORCE_OTHER].first().unwrap();
-    (delta_force_self, delta_force_other)
+    let delta_forces_self = output[DELTA_FORCE_SELF].column(0);
+    let delta_forces_other = output[DELTA_FORCE_OTHER].column(0);
+    delta_forces_self.iter().copied().zip(delta_forces_other.iter().copied()).collect()
 }
 
 
-fn update_main_connection(
-    connection: &mut IntraConnection,
+/// All connections of a node in one batch
+fn update_main_connections(
+    mut connections: ArrayViewMut1<IntraConnection>,
     model: &Model,
     precalculated: &Array1<f32>,
     nodes: &Array2<f32>,
 ) {
-    let node_index_other = connection.get_index();
-    let node_state_other = nodes.slice(s![node_index_other, ..]);
-    let (force_self, force_other) = connection.get_forces();
-    let force_self_arr = value_to_array(force_self);
-    let force_other_arr = value_to_array(force_other);
-    let inputs = [
-        (NODE_STATE_OTHER, expand(node_state_other.view())),
-        (FORCE_SELF, force_self_arr.view()),
-        (FORCE_OTHER, force_other_arr.view())
-    ];
-    let output = model.forward_from_precalc(&inputs, precalculated);
-    let delta_force_self = *output[DELTA_FORCE_SELF].first().unwrap();
-    let delta_force_other = *output[DELTA_FORCE_OTHER].first().unwrap();
-    connection.store_forces(force_self + delta_force_self, force_other + delta_force_other);
+    let node_indices_other: Vec<usize> = connections.iter().map(|connection| connection.get_index()).collect();
+    let (forces_self, forces_other): (Vec<f32>, Vec<f32>) = connections.iter().map(|connection| connection.get_forces()).unzip();
+    let delta_forces = get_delta_forces(
+        &node_indices_other,
+        &forces_self,
+        &forces_other,
+        model,
+        precalculated,
+        nodes
+    );
+    for (i, (connection, (delta_force_self, delta_force_other))) in connections.iter_mut().zip(delta_forces).enumerate() {
+        connection.store_forces(forces_self[i] + delta_force_self, forces_other[i] + delta_force_other);
+    }
 }
 
 
@@ -213,6 +223,7 @@ fn update_pending_connection(
     nodes: &Array2<f32>,
     counter: &mut CounterIntraConnection,
     g_settings: &GuardianSettings,
+    stats: &Stats,
 ) {
     let failed_previous = match counter.get_state(g_settings) {
         NodeState::Failed => {
@@ -233,15 +244,16 @@ fn update_pending_connection(
                 connection_self.reset_pending();
                 return;
             }
-            for node_index in search {
-                let (force_self, force_other) = get_pending_delta_forces(
-                    node_index,
-                    0.0,
-                    0.0,
-                    model,
-                    precalculated,
-                    nodes,
-                );
+            let zero_forces = vec![0.0; search.len()];
+            let delta_forces = get_delta_forces(
+                &search,
+                &zero_forces,
+                &zero_forces,
+                model,
+                precalculated,
+                nodes,
+            );
+            for (node_index, (force_self, force_other)) in search.into_iter().zip(delta_forces) {
                 let net_force = force_self + force_other;
                 if net_force > strongest_net_force {
                     forces = (force_self, force_other);
@@ -254,6 +266,7 @@ fn update_pending_connection(
             if failed_previous && strongest_node_index == pending_index {
                 // Stuck in a local maxima. Force reset
                 connection_self.reset_pending();
+                stats.reset_intra_pending_index.inc();
             } else if pending_index == strongest_node_index {  // found local maximum, nothing higher around. Attempt connection
                 counter.inc();
                 connection_self.store_pending_forces(forces.0, forces.1);
@@ -262,14 +275,14 @@ fn update_pending_connection(
         NodeState::Connecting => {
             let node_index_other = connection_self.get_pending_index();
             let (force_self, force_other) = connection_self.get_pending_forces();
-            let (delta_force_self, delta_force_other) = get_pending_delta_forces(
-                node_index_other,
-                force_self,
-                force_other,
+            let (delta_force_self, delta_force_other) = get_delta_forces(
+                &[node_index_other],
+                &[force_self],
+                &[force_other],
                 model,
                 precalculated,
                 nodes,
-            );
+            )[0];
             let updated_force_self = force_self + delta_force_self;
             let updated_force_other = force_other + delta_force_other;
             connection_self.store_pending_forces(
@@ -288,10 +301,12 @@ fn update_pending_connection(
             // Nothing to compete to, just do it
             connection_self.move_pending_to_main();
             counter.reset();
+            stats.new_intra_connections.inc();
         },
         NodeState::Failed => {
             connection_self.reset_pending();
             counter.reset();
+            stats.failed_intra_connections.inc();
         }
     }
 }
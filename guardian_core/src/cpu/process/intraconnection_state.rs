@@ -1,13 +1,15 @@
 use std::time::Instant;
 
 use tracing::trace;
-use ndarray::{s, Axis};
+use ndarray::Axis;
 use itertools::multizip;
 use ndarray::parallel::prelude::*;
 use rayon::iter::ParallelBridge;
 use rayon::ThreadPool;
 
 use crate::cpu::interface::Network;
+use crate::cpu::precalc::{CachedModel, PrecalcCache};
+use crate::cpu::stats::Stats;
 use crate::cpu::*;
 
 // Input
@@ -19,7 +21,7 @@ const NODE_OTHER: usize = 2;
 const DELTA_NODE_SELF: usize = 0;
 const DELTA_NODE_OTHER: usize = 1;
 
-pub fn update(network: &mut Network, pool: &ThreadPool) {
+pub fn update(network: &mut Network, pool: &ThreadPool, stats: &Stats, cache: &PrecalcCache) {
     let nodes = &mut network.state.nodes;
     let neuron_states = &network.state.neuron_states;
     let intra_connections = &network.state.intra_connections;
@@ -36,43 +38,45 @@ pub fn update(network: &mut Network, pool: &ThreadPool) {
     );
 
     let now = Instant::now();
-    let operation = zipped
+    pool.install(|| zipped
     .into_iter()
+    .enumerate()
     .par_bridge()
-    .for_each(|(neuron_state, mut node_states_source, intra_connections)| {
-        let neuron_state = unpack_array(neuron_state);
-        let precalculated_neuron_state_self = model.precalculate(NEURON_STATE, neuron_state.view());
+    .for_each(|(neuron_index, (neuron_state, mut node_states_source, intra_connections))| {
+        let precalculated = cache.neuron(
+            CachedModel::IntraconnectedNodeStateUpdate, model, NEURON_STATE, neuron_index, neuron_state, stats
+        );
         let node_states = unpack_array(node_states_source.view());
-        let mut delta_node_states_min = Array2::from_elem((g_settings.n_nodes_per_neuron, g_settings.node_size), 0.0);
-        let mut delta_node_states_max = Array2::from_elem((g_settings.n_nodes_per_neuron, g_settings.node_size), 0.0);
-        for (node_local_index_self, node_state_self) in node_states.rows().into_iter().enumerate() {
-            let precalculated_node_state_self = model.precalculate(NODE_SELF, node_state_self);
-            let connections = intra_connections.row(node_local_index_self);
-            let precalculated = &precalculated_neuron_state_self + precalculated_node_state_self;
-            for connection in connections {
-                let node_local_index_other = connection.get_index();
-                let node_state_other = node_states.slice(s![node_local_index_other, ..]);
-
-                let inputs = [
-                    (NODE_OTHER, expand(node_state_other))
-                ];
 
-                let output = model.forward_from_precalc(&inputs, &precalculated);
+        // All intraconnections of the neuron in one batch
+        let pairs: Vec<(usize, usize)> = intra_connections
+            .indexed_iter()
+            .map(|((node_local_index_self, _), connection)| (node_local_index_self, connection.get_index()))
+            .collect();
+        let indices_self: Vec<usize> = pairs.iter().map(|(index_self, _)| *index_self).collect();
+        let indices_other: Vec<usize> = pairs.iter().map(|(_, index_other)| *index_other).collect();
+        let node_states_self = node_states.select(Axis(0), &indices_self);
+        let node_states_other = node_states.select(Axis(0), &indices_other);
+        let inputs = [
+            (NODE_SELF, node_states_self.view()),
+            (NODE_OTHER, node_states_other.view())
+        ];
+        let output = model.forward_from_precalc(&inputs, &precalculated);
+        stats.intra_node_updates.add(g_settings.n_nodes_per_neuron);
 
-                let delta_node_self = squeeze(output[DELTA_NODE_SELF].view());
-                min_array_inplace(&mut delta_node_states_min.row_mut(node_local_index_self), delta_node_self);
-                max_array_inplace(&mut delta_node_states_max.row_mut(node_local_index_self), delta_node_self);
-
-                let delta_node_other = squeeze(output[DELTA_NODE_OTHER].view());
-                min_array_inplace(&mut delta_node_states_min.row_mut(node_local_index_other), delta_node_other);
-                max_array_inplace(&mut delta_node_states_max.row_mut(node_local_index_other), delta_node_other);
-            }
+        let mut delta_node_states_min = Array2::from_elem((g_settings.n_nodes_per_neuron, g_settings.node_size), 0.0);
+        let mut delta_node_states_max = Array2::from_elem((g_settings.n_nodes_per_neuron, g_settings.node_size), 0.0);
+        let deltas = output[DELTA_NODE_SELF].rows().into_iter().zip(output[DELTA_NODE_OTHER].rows());
+        for ((node_local_index_self, node_local_index_other), (delta_node_self, delta_node_other)) in pairs.into_iter().zip(deltas) {
+            min_array_inplace(&mut delta_node_states_min.row_mut(node_local_index_self), delta_node_self);
+            max_array_inplace(&mut delta_node_states_max.row_mut(node_local_index_self), delta_node_self);
+            min_array_inplace(&mut delta_node_states_min.row_mut(node_local_index_other), delta_node_other);
+            max_array_inplace(&mut delta_node_states_max.row_mut(node_local_index_other), delta_node_other);
         }
         let delta_node_states = delta_node_states_max + delta_node_states_min;
         let updated_node_states = node_states + delta_node_states;
         let updated_node_states = pack_array(updated_node_states);
         node_states_source.assign(&updated_node_states);
-    });
-    pool.install(|| operation);
+    }));
     trace!("It took {:?} to update intraconnection states", now.elapsed());
 }
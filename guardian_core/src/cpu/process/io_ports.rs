@@ -1,7 +1,249 @@
+//! IO ports connect the network to the outside world
+//!
+//! Each port is attached to a node. Sensors write values to the input of the port, which are
+//! applied to the node and its neuron by the io_input model at the start of the step.
+//! At the end of the step, the io_output model updates the output of the port, which is read by the actuators.
+
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use anyhow::Result;
+use tracing::trace;
+use ndarray::{Array1, ArrayView1, ArrayViewMut1};
+use rayon::prelude::*;
 use rayon::ThreadPool;
 
-use crate::cpu::interface::Network;
+use crate::cpu::interface::{Network, State, IO_INPUT_MODEL, IO_OUTPUT_MODEL};
+use crate::cpu::stats::Stats;
+use crate::cpu::*;
+
+// Input
+const NEURON_STATE: usize = 0;
+const NODE: usize = 1;
+const IO_VALUES: usize = 2;
+
+// Output (io_input)
+const DELTA_NEURON_STATE: usize = 0;
+const DELTA_NODE: usize = 1;
+
+// Output (io_output)
+const DELTA_IO_VALUES: usize = 0;
+
+/// Writes into the input of a port before each step
+pub trait Sensor {
+    /// Values should be between 0 and 1
+    fn sense(&mut self, values: ArrayViewMut1<f32>);
+}
+
+/// Reads the output of a port after each step
+pub trait Actuator {
+    fn actuate(&mut self, values: ArrayView1<f32>);
+}
+
+/// Sensors and actuators attached to the io ports, by port index
+#[derive(Default)]
+pub struct IoDevices {
+    sensors: BTreeMap<usize, Box<dyn Sensor>>,
+    actuators: BTreeMap<usize, Box<dyn Actuator>>,
+}
+
+impl IoDevices {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails if the state has no such port
+    pub fn attach_sensor(&mut self, port: usize, sensor: Box<dyn Sensor>, state: &State) -> Result<()> {
+        state.check_io_port(port)?;
+        self.sensors.insert(port, sensor);
+        Ok(())
+    }
+
+    /// Fails if the state has no such port
+    pub fn attach_actuator(&mut self, port: usize, actuator: Box<dyn Actuator>, state: &State) -> Result<()> {
+        state.check_io_port(port)?;
+        self.actuators.insert(port, actuator);
+        Ok(())
+    }
+
+    pub fn sense(&mut self, state: &mut State) {
+        for (port, sensor) in self.sensors.iter_mut() {
+            let mut values = state.read_io_input(*port);
+            sensor.sense(values.view_mut());
+            state.write_io_input(*port, values.view());
+        }
+    }
+
+    pub fn actuate(&mut self, state: &State) {
+        for (port, actuator) in self.actuators.iter_mut() {
+            let values = state.read_io_output(*port);
+            actuator.actuate(values.view());
+        }
+    }
+}
+
+/// Applies the inputs of the ports to the attached nodes and neurons
+pub fn update_inputs(network: &mut Network, pool: &ThreadPool, stats: &Stats) {
+    let Some(model) = network.genome.io_models.get(IO_INPUT_MODEL) else {
+        return;  // Genome without io
+    };
+    let state = &network.state;
+    let g_settings = &network.g_settings;
+
+    let now = Instant::now();
+    let deltas: Vec<(Array1<f32>, Array1<f32>)> = pool.install(|| {
+        (0..state.io_ports.len())
+        .into_par_iter()
+        .map(|port| {
+            let (neuron_index, node_local_index) = node_global_to_local_index(state.io_ports[port], g_settings);
+            let neuron_state = get_neuron_state(neuron_index, &state.neuron_states);
+            let node = get_node(neuron_index, node_local_index, &state.nodes);
+            let io_input = unpack_array(state.io_inputs.row(port));
+            let precalculated = model.precalculate(NEURON_STATE, neuron_state.view());
+            let inputs = [
+                (NODE, expand(node.view())),
+                (IO_VALUES, expand(io_input.view())),
+            ];
+            let mut output = model.forward_from_precalc(&inputs, &precalculated);
+            let delta_node = output.remove(DELTA_NODE).remove_axis(Axis(0));
+            let delta_neuron_state = output.remove(DELTA_NEURON_STATE).remove_axis(Axis(0));
+            (delta_neuron_state, delta_node)
+        })
+        .collect()
+    });
+
+    // Applied in port order. Multiple ports can be attached to the same node
+    let state = &mut network.state;
+    for (port, (delta_neuron_state, delta_node)) in deltas.into_iter().enumerate() {
+        let (neuron_index, node_local_index) = node_global_to_local_index(state.io_ports[port], g_settings);
+        let neuron_state = get_neuron_state(neuron_index, &state.neuron_states) + delta_neuron_state;
+        state.neuron_states.row_mut(neuron_index).assign(&pack_array(neuron_state));
+        let node = get_node(neuron_index, node_local_index, &state.nodes) + delta_node;
+        state.nodes.slice_mut(s![neuron_index, node_local_index, ..]).assign(&pack_array(node));
+        stats.io_port_updates.inc();
+    }
+    trace!("It took {:?} to update io inputs", now.elapsed());
+}
+
+/// Updates the outputs of the ports from the attached nodes and neurons
+pub fn update_outputs(network: &mut Network, pool: &ThreadPool, stats: &Stats) {
+    let Some(model) = network.genome.io_models.get(IO_OUTPUT_MODEL) else {
+        return;  // Genome without io
+    };
+    let state = &network.state;
+    let g_settings = &network.g_settings;
+
+    let now = Instant::now();
+    let outputs: Vec<Array1<u8>> = pool.install(|| {
+        (0..state.io_ports.len())
+        .into_par_iter()
+        .map(|port| {
+            let (neuron_index, node_local_index) = node_global_to_local_index(state.io_ports[port], g_settings);
+            let neuron_state = get_neuron_state(neuron_index, &state.neuron_states);
+            let node = get_node(neuron_index, node_local_index, &state.nodes);
+            let io_output = unpack_array(state.io_outputs.row(port));
+            let precalculated = model.precalculate(NEURON_STATE, neuron_state.view());
+            let inputs = [
+                (NODE, expand(node.view())),
+                (IO_VALUES, expand(io_output.view())),
+            ];
+            let output = model.forward_from_precalc(&inputs, &precalculated);
+            let io_output = io_output + squeeze(output[DELTA_IO_VALUES].view());
+            pack_array(io_output)
+        })
+        .collect()
+    });
+
+    let state = &mut network.state;
+    for (port, io_output) in outputs.into_iter().enumerate() {
+        state.io_outputs.row_mut(port).assign(&io_output);
+        stats.io_port_updates.inc();
+    }
+    trace!("It took {:?} to update io outputs", now.elapsed());
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use rayon::ThreadPoolBuilder;
+
+    use crate::{GuardianSettings, NetworkSettings};
+    use crate::cpu::interface::Genome;
+    use crate::cpu::process::update_with_io;
+    use crate::topology::NeighbourhoodCache;
+    use super::*;
+
+    struct ConstantSensor(f32);
+
+    impl Sensor for ConstantSensor {
+        fn sense(&mut self, mut values: ArrayViewMut1<f32>) {
+            values.fill(self.0);
+        }
+    }
+
+    struct RecordingActuator(Arc<Mutex<Vec<Array1<f32>>>>);
+
+    impl Actuator for RecordingActuator {
+        fn actuate(&mut self, values: ArrayView1<f32>) {
+            self.0.lock().unwrap().push(values.to_owned());
+        }
+    }
+
+    #[test]
+    pub fn test_io_ports() {
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let mut g_settings = GuardianSettings::downlevel_default();
+        g_settings.hidden_sizes = vec![8];
+        let mut n_settings = NetworkSettings::downlevel_default();
+        n_settings.n_neurons = 4;
+        n_settings.n_io_ports = 2;
+        let rng = StdRng::seed_from_u64(1);
+        let mut state = State::new(&g_settings, &n_settings);
+        state.randomize(&g_settings, &n_settings, Some(rng.clone()));
+        state.set_io_port(1, 3, 2, &g_settings).unwrap();
+        assert!(state.set_io_port(2, 3, 2, &g_settings).is_err());
+        assert!(state.set_io_port(1, 4, 2, &g_settings).is_err());
+        let mut network = Network {
+            state,
+            genome: Genome::new(&g_settings, Some(rng)),
+            g_settings: g_settings.clone(),
+            n_settings,
+            neighbourhood: NeighbourhoodCache::default()
+        };
+
+        let recorded = Arc::new(Mutex::new(vec![]));
+        let mut devices = IoDevices::new();
+        devices.attach_sensor(0, Box::new(ConstantSensor(1.0)), &network.state).unwrap();
+        devices.attach_actuator(1, Box::new(RecordingActuator(recorded.clone())), &network.state).unwrap();
+        assert!(devices.attach_sensor(2, Box::new(ConstantSensor(1.0)), &network.state).is_err());
+        assert!(devices.attach_actuator(2, Box::new(RecordingActuator(recorded.clone())), &network.state).is_err());
+        for _ in 0..2 {
+            update_with_io(&mut network, &pool, &mut devices);
+        }
+
+        assert_eq!(network.state.io_ports[1], node_local_to_global_index(3, 2, &g_settings));
+        assert!(network.state.io_inputs.row(0).iter().all(|v| *v == 255));
+        let recorded = recorded.lock().unwrap();
+        assert_eq!(recorded.len(), 2);
+        assert!(recorded.iter().all(|values| values.len() == g_settings.io_size));
+        // The actuator gets the output of the last step
+        assert_eq!(recorded[1], network.state.read_io_output(1));
 
-pub fn update(_network: &mut Network, _pool: &ThreadPool) {
-    // TODO: Add update
-}
\ No newline at end of file
+        // Another input gives another node and neuron state
+        let stats = Stats::new();
+        let (neuron_index, node_local_index) = node_global_to_local_index(network.state.io_ports[0], &g_settings);
+        let updated = [0.0, 1.0].map(|value| {
+            let mut network = network.clone();
+            network.state.write_io_input(0, Array1::from_elem(g_settings.io_size, value).view());
+            update_inputs(&mut network, &pool, &stats);
+            (
+                network.state.nodes.slice(s![neuron_index, node_local_index, ..]).to_owned(),
+                network.state.neuron_states.row(neuron_index).to_owned()
+            )
+        });
+        assert!(updated[0] != updated[1]);
+    }
+}
@@ -2,6 +2,9 @@ use rayon::ThreadPool;
 use tracing::trace;
 
 use crate::cpu::interface::Network;
+use crate::cpu::precalc::PrecalcCache;
+use crate::cpu::stats::Stats;
+use io_ports::IoDevices;
 
 pub mod interconnection_state;
 pub mod intraconnection_state;
@@ -9,22 +12,39 @@ pub mod neuron_state;
 pub mod interconnection_plasticity;
 pub mod intraconnection_plasticity;
 pub mod io_ports;
+pub mod nexus;
 
 /// WIP: Starting with a naive approach
 /// TODO: Move to network as impl?
-pub fn update(network: &mut Network, pool: &ThreadPool) {
+pub fn update(network: &mut Network, pool: &ThreadPool) -> Stats {
+    let mut stats = Stats::new();
+    let cache = PrecalcCache::new(network.n_settings.n_neurons, network.g_settings.n_nodes_per_neuron);
     trace!("Stage 1: Update io ports (network + input if core)");
-    io_ports::update(network, pool);
+    io_ports::update_inputs(network, pool, &stats);
     trace!("Stage 2: Update interconnected state");
-    interconnection_state::update(network, pool);
+    interconnection_state::update(network, pool, &stats, &cache);
     trace!("Stage 3: Update intraconnected state");
-    intraconnection_state::update(network, pool);
+    intraconnection_state::update(network, pool, &stats, &cache);
     trace!("Stage 4: Update neuron state");
-    neuron_state::update(network, pool);
+    neuron_state::update(network, pool, &stats, &cache);
     trace!("Stage 5: Update interconnections (plasticity)");
-    interconnection_plasticity::update(network, pool);
+    interconnection_plasticity::update(network, pool, &stats, &cache);
     trace!("Stage 6: Update intraconnections (plasticity)");
-    intraconnection_plasticity::update(network, pool);
-    trace!("Stage 7: Update IO ports");
-    io_ports::update(network, pool);
+    intraconnection_plasticity::update(network, pool, &stats, &cache);
+    trace!("Stage 7: Update network ports");
+    nexus::update(network, pool, &stats);
+    trace!("Stage 8: Read network ports");
+    nexus::read(network);
+    trace!("Stage 9: Update IO ports");
+    io_ports::update_outputs(network, pool, &stats);
+    stats.aggregate(&network.state, &network.g_settings);
+    stats
+}
+
+/// Same as update, but the sensors are read before and the actuators are applied after the step
+pub fn update_with_io(network: &mut Network, pool: &ThreadPool, devices: &mut IoDevices) -> Stats {
+    devices.sense(&mut network.state);
+    let stats = update(network, pool);
+    devices.actuate(&network.state);
+    stats
 }
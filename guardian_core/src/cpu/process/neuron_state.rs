@@ -8,22 +8,25 @@ use rayon::iter::ParallelBridge;
 use rayon::ThreadPool;
 
 use crate::cpu::interface::Network;
+use crate::cpu::precalc::{CachedModel, PrecalcCache};
+use crate::cpu::stats::Stats;
 use crate::cpu::*;
 
 // Input
 const NEURON_STATE: usize = 0;
 const NODE: usize = 1;
+const MODULATORS: usize = 2;
 
 // Output
 const DELTA_NEURON_STATE: usize = 0;
 const DELTA_NODE: usize = 1;
 
-pub fn update(network: &mut Network, pool: &ThreadPool) {
+pub fn update(network: &mut Network, pool: &ThreadPool, stats: &Stats, cache: &PrecalcCache) {
     let nodes = &mut network.state.nodes;
     let neuron_states = &mut network.state.neuron_states;
     let genome = &network.genome;
     let model = &genome.neuron_state_update;
-    let g_settings = &network.g_settings;
+    let precalculated_modulators = precalculate_modulators(model, MODULATORS, network.state.modulators.view());
 
     let zipped = multizip(
         (
@@ -33,33 +36,29 @@ pub fn update(network: &mut Network, pool: &ThreadPool) {
     );
 
     let now = Instant::now();
-    let operation = zipped
+    pool.install(|| zipped
     .into_iter()
+    .enumerate()
     .par_bridge()
-    .for_each(|(mut neuron_state_source, mut node_states_source)| {
+    .for_each(|(neuron_index, (mut neuron_state_source, mut node_states_source))| {
         let neuron_state = unpack_array(neuron_state_source.view());
-        let precalculated = &model.precalculate(NEURON_STATE, neuron_state.view());
-        let mut delta_neuron_state_min = Array1::from_elem(g_settings.neuron_state_size, 0.0);
-        let mut delta_neuron_state_max = Array1::from_elem(g_settings.neuron_state_size, 0.0);
-        for mut node_source in node_states_source.rows_mut().into_iter() {
-            let mut node = unpack_array(node_source.view());
-            let inputs = [
-                (NODE, expand(node.view()))
-            ];
-            let output = &model.forward_from_precalc(&inputs, precalculated);
-            let delta_neuron_state = squeeze(output[DELTA_NEURON_STATE].view());
-            min_array_inplace(&mut delta_neuron_state_min.view_mut(), delta_neuron_state);
-            max_array_inplace(&mut delta_neuron_state_max.view_mut(), delta_neuron_state);
-            node = node + squeeze(output[DELTA_NODE].view());
-            let node = pack_array(node);
-            node_source.assign(&node);
-        }
-        let delta_neuron_state = delta_neuron_state_max + delta_neuron_state_min;
+        let precalculated = &(cache.neuron(
+            CachedModel::NeuronStateUpdate, model, NEURON_STATE, neuron_index, neuron_state_source.view(), stats
+        ) + &precalculated_modulators);
+        // All nodes of the neuron in one batch
+        let node_states = unpack_array(node_states_source.view());
+        let inputs = [
+            (NODE, node_states.view())
+        ];
+        let output = &model.forward_from_precalc(&inputs, precalculated);
+        let delta_neuron_state = min_max_rows(output[DELTA_NEURON_STATE].view());
+        let updated_node_states = node_states + &output[DELTA_NODE];
+        node_states_source.assign(&pack_array(updated_node_states));
         let updated_neuron_state = neuron_state + delta_neuron_state;
         let updated_neuron_state = pack_array(updated_neuron_state);
         neuron_state_source.assign(&updated_neuron_state);
-    });
-    pool.install(|| operation);
+        stats.neuron_updates.inc();
+    }));
     trace!("It took {:?} to update neuron states", now.elapsed());
 }
 
@@ -0,0 +1,122 @@
+//! Network ports (nexus) connect the network to other networks
+//!
+//! Each port is attached to a neuron. The neuron reads what the other side has sent (nexus_read)
+//! and updates what it sends (nexus_write). What is received during a step is only read by the
+//! network in the next step, so the other side does not need to be in sync. What is received is read once,
+//! a step without anything received reads zeros.
+
+use std::time::Instant;
+
+use tracing::trace;
+use ndarray::Array1;
+use rayon::prelude::*;
+use rayon::ThreadPool;
+
+use crate::cpu::interface::{Network, NEXUS_MODEL};
+use crate::cpu::stats::Stats;
+use crate::cpu::*;
+
+// Input
+const NEURON_STATE: usize = 0;
+const NEXUS_READ: usize = 1;
+const NEXUS_WRITE: usize = 2;
+
+// Output
+const DELTA_NEXUS: usize = 0;
+const DELTA_NEURON_WRITE: usize = 1;
+
+/// Updates what the network sends and applies what the network has read to the neurons
+pub fn update(network: &mut Network, pool: &ThreadPool, stats: &Stats) {
+    let Some(model) = network.genome.io_models.get(NEXUS_MODEL) else {
+        return;  // Genome without network ports
+    };
+    let state = &network.state;
+
+    let now = Instant::now();
+    let deltas: Vec<(Array1<f32>, Array1<f32>)> = pool.install(|| {
+        (0..state.nexus_ports.len())
+        .into_par_iter()
+        .map(|port| {
+            let neuron_state = get_neuron_state(state.nexus_ports[port], &state.neuron_states);
+            let nexus_read = unpack_array(state.nexus_read.row(port));
+            let nexus_write = unpack_array(state.nexus_write.row(port));
+            let precalculated = model.precalculate(NEURON_STATE, neuron_state.view());
+            let inputs = [
+                (NEXUS_READ, expand(nexus_read.view())),
+                (NEXUS_WRITE, expand(nexus_write.view())),
+            ];
+            let mut output = model.forward_from_precalc(&inputs, &precalculated);
+            let delta_neuron_state = output.remove(DELTA_NEURON_WRITE).remove_axis(Axis(0));
+            let delta_nexus = output.remove(DELTA_NEXUS).remove_axis(Axis(0));
+            (delta_nexus, delta_neuron_state)
+        })
+        .collect()
+    });
+
+    // Applied in port order. Multiple ports can be attached to the same neuron
+    let state = &mut network.state;
+    for (port, (delta_nexus, delta_neuron_state)) in deltas.into_iter().enumerate() {
+        let nexus_write = unpack_array(state.nexus_write.row(port)) + delta_nexus;
+        state.nexus_write.row_mut(port).assign(&pack_array(nexus_write));
+        let neuron_index = state.nexus_ports[port];
+        let neuron_state = get_neuron_state(neuron_index, &state.neuron_states) + delta_neuron_state;
+        state.neuron_states.row_mut(neuron_index).assign(&pack_array(neuron_state));
+        stats.nexus_updates.inc();
+    }
+    trace!("It took {:?} to update network ports", now.elapsed());
+}
+
+/// What has been received during this step will be read in the next step. Cleared after, so it is only read once
+pub fn read(network: &mut Network) {
+    let state = &mut network.state;
+    state.nexus_read.assign(&state.nexus_received);
+    state.nexus_received.fill(0);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use rayon::ThreadPoolBuilder;
+
+    use crate::{GuardianSettings, NetworkSettings};
+    use crate::cpu::interface::{Genome, State};
+    use crate::cpu::process;
+    use crate::topology::NeighbourhoodCache;
+    use super::*;
+
+    #[test]
+    pub fn test_nexus_delay() {
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let mut g_settings = GuardianSettings::downlevel_default();
+        g_settings.hidden_sizes = vec![8];
+        let mut n_settings = NetworkSettings::downlevel_default();
+        n_settings.n_neurons = 4;
+        n_settings.n_network_ports = 2;
+        let rng = StdRng::seed_from_u64(1);
+        let mut state = State::new(&g_settings, &n_settings);
+        state.randomize(&g_settings, &n_settings, Some(rng.clone()));
+        state.set_nexus_port(1, 3).unwrap();
+        assert!(state.set_nexus_port(2, 3).is_err());
+        assert!(state.set_nexus_port(1, 4).is_err());
+        let mut network = Network {
+            state,
+            genome: Genome::new(&g_settings, Some(rng)),
+            g_settings: g_settings.clone(),
+            n_settings,
+            neighbourhood: NeighbourhoodCache::default()
+        };
+
+        // Received during the step, only readable after the step
+        let received = Array1::from_elem(g_settings.nexus_size, 1.0);
+        network.state.receive_nexus(1, received.view());
+        assert!(network.state.nexus_read.iter().all(|v| *v == 0));
+        process::update(&mut network, &pool);
+        assert!(network.state.nexus_read.row(0).iter().all(|v| *v == 0));
+        assert!(network.state.nexus_read.row(1).iter().all(|v| *v == 255));
+
+        // Nothing received during the next step
+        process::update(&mut network, &pool);
+        assert!(network.state.nexus_read.iter().all(|v| *v == 0));
+    }
+}
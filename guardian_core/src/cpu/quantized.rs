@@ -0,0 +1,367 @@
+//! Int8 execution of a Model on the packed state
+//!
+//! Weights are i8 with one scale per output column, stored transposed so each output is a contiguous dot product.
+//! Inputs are the packed u8 (node and neuron states) or i8 (forces) values as they are stored in State, so they are
+//! never unpacked. Products are accumulated as i32 and scaled back to f32 once per layer. Between hidden layers the
+//! activations are requantized to i8 with one scale per row.
+//!
+//! Each output is packed to a PackedFormat. The models output deltas, which can be negative, so they are packed as
+//! Signed. Unsigned clamps at 0 and is only for outputs that are a state themselves.
+
+use ndarray::{Array1, Array2, ArrayView1, ArrayView2, Axis, Zip};
+use serde::{Deserialize, Serialize};
+
+use super::model::{Array, Bias, Model, ModelSettings, Row, Weight};
+use super::{unpack_array, unpack_array_with_negative};
+
+/// Packed values that can be fed to a QuantizedModel
+pub trait Packed: Copy {
+    const SCALE: f32;  // Real value of one step
+    fn to_i16(self) -> i16;
+}
+
+impl Packed for u8 {
+    const SCALE: f32 = 1.0 / 255.0;
+    fn to_i16(self) -> i16 {
+        self as i16
+    }
+}
+
+impl Packed for i8 {
+    const SCALE: f32 = 1.0 / 127.0;
+    fn to_i16(self) -> i16 {
+        self as i16
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum PackedInput<'a> {
+    Unsigned(ArrayView2<'a, u8>),
+    Signed(ArrayView2<'a, i8>),
+}
+
+impl PackedInput<'_> {
+    fn batch_size(&self) -> usize {
+        match self {
+            PackedInput::Unsigned(x) => x.nrows(),
+            PackedInput::Signed(x) => x.nrows(),
+        }
+    }
+}
+
+/// How an output is stored in State
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PackedFormat {
+    Unsigned,  // u8 like pack, such as node and neuron states. Negative values become 0
+    Signed,  // i8 like pack_with_negative, such as deltas and forces
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PackedOutput {
+    Unsigned(Array2<u8>),
+    Signed(Array2<i8>),
+}
+
+impl PackedOutput {
+    fn new(output: &Array, format: PackedFormat) -> Self {
+        match format {
+            PackedFormat::Unsigned => PackedOutput::Unsigned(output.mapv(pack_output_unsigned)),
+            PackedFormat::Signed => PackedOutput::Signed(output.mapv(pack_output_signed)),
+        }
+    }
+
+    pub fn unpack(&self) -> Array {
+        match self {
+            PackedOutput::Unsigned(output) => unpack_array(output.view()),
+            PackedOutput::Signed(output) => unpack_array_with_negative(output.view()),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuantizedWeight {
+    weight: Array2<i8>,  // (output, input)
+    scales: Array1<f32>,  // Per output
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuantizedLayer {
+    weight: QuantizedWeight,
+    bias: Bias,
+}
+
+/// Created with Model::quantize
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuantizedModel {
+    settings: ModelSettings,
+    input_weights: Vec<QuantizedWeight>,
+    input_bias: Bias,
+    hidden_layers: Vec<QuantizedLayer>,
+    output_layers: Vec<QuantizedLayer>,
+}
+
+/// Difference between the outputs of the f32 and the quantized path, in real values
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuantizationError {
+    pub max_abs: f32,
+    pub mean_abs: f32,
+}
+
+impl QuantizedWeight {
+    pub fn new(weight: &Weight) -> Self {
+        let max_abs = weight.fold_axis(Axis(0), 0.0, |max: &f32, v| max.max(v.abs()));
+        let scales = max_abs.mapv(|max| if max > 0.0 { max / 127.0 } else { 1.0 });
+        let weight = Zip::from(weight)
+            .and_broadcast(&scales)
+            .map_collect(|w, scale| (w / scale).round().clamp(-127.0, 127.0) as i8)
+            .reversed_axes()
+            .as_standard_layout()
+            .into_owned();
+        Self { weight, scales }
+    }
+
+    /// Every row of x is scaled by the same input scale
+    fn forward<T: Packed>(&self, x: ArrayView2<T>) -> Array {
+        let accumulated = matmul(x, &self.weight);
+        let scales = &self.scales * T::SCALE;
+        Zip::from(&accumulated).and_broadcast(&scales).map_collect(|acc, scale| *acc as f32 * scale)
+    }
+
+    /// Each row of x has its own scale
+    fn forward_rows(&self, x: ArrayView2<i8>, row_scales: &Array1<f32>) -> Array {
+        let accumulated = matmul(x, &self.weight);
+        let row_scales = row_scales.view().insert_axis(Axis(1));
+        Zip::from(&accumulated)
+            .and_broadcast(&row_scales)
+            .and_broadcast(&self.scales)
+            .map_collect(|acc, row_scale, scale| *acc as f32 * row_scale * scale)
+    }
+}
+
+impl QuantizedLayer {
+    pub fn new(weight: &Weight, bias: &Bias) -> Self {
+        Self { weight: QuantizedWeight::new(weight), bias: bias.clone() }
+    }
+
+    fn forward(&self, x: &Array, with_bias: bool) -> Array {
+        let (x, row_scales) = quantize_rows(x);
+        let y = self.weight.forward_rows(x.view(), &row_scales);
+        if with_bias {
+            y + &self.bias
+        } else {
+            y
+        }
+    }
+}
+
+impl QuantizedModel {
+    pub(crate) fn new(
+        settings: ModelSettings,
+        input_weights: Vec<QuantizedWeight>,
+        input_bias: Bias,
+        hidden_layers: Vec<QuantizedLayer>,
+        output_layers: Vec<QuantizedLayer>,
+    ) -> Self {
+        Self { settings, input_weights, input_bias, hidden_layers, output_layers }
+    }
+
+    /// Same as Model::forward_from_precalc, but with packed inputs and outputs. There is one format per output
+    pub fn forward_from_precalc(&self, inputs: &[(usize, PackedInput)], precalculated: &Row, formats: &[PackedFormat]) -> Vec<PackedOutput> {
+        assert_eq!(formats.len(), self.output_layers.len(), "Expected one format per output");
+        self.forward_unpacked(inputs, precalculated)
+            .iter()
+            .zip(formats)
+            .map(|(output, format)| PackedOutput::new(output, *format))
+            .collect()
+    }
+
+    /// NOTE: Bias is NOT added here!
+    pub fn precalculate<T: Packed>(&self, input_index: usize, x: ArrayView1<T>) -> Row {
+        let hidden = self.input_weights[input_index].forward(x.insert_axis(Axis(0)));
+        hidden.index_axis_move(Axis(0), 0)
+    }
+
+    fn forward_unpacked(&self, inputs: &[(usize, PackedInput)], precalculated: &Row) -> Vec<Array> {
+        let batch_size = inputs.first().unwrap().1.batch_size();
+        let mut x: Array = Array2::zeros((batch_size, self.input_bias.len()));
+        x += precalculated;
+        for (i, input) in inputs {
+            let weight = &self.input_weights[*i];
+            x = x + match input {
+                PackedInput::Unsigned(input) => weight.forward(*input),
+                PackedInput::Signed(input) => weight.forward(*input),
+            };
+        }
+
+        x += &self.input_bias;
+        let mut hidden_activations = self.settings.hidden_activations.iter();
+        x = hidden_activations.next().unwrap().apply(x);
+        for (layer, activation) in self.hidden_layers.iter().zip(hidden_activations) {
+            x = activation.apply(layer.forward(&x, true));
+        }
+
+        self.output_layers
+            .iter()
+            .zip(self.settings.output_activations.iter())
+            .map(|(layer, activation)| activation.apply(layer.forward(&x, self.settings.output_bias)))
+            .collect()
+    }
+
+    pub fn settings(&self) -> &ModelSettings {
+        &self.settings
+    }
+}
+
+/// Runs both paths on the same packed inputs, where the f32 path gets them unpacked. Each path precalculates the
+/// precalculated input itself. The packed outputs of the quantized path are compared to the f32 outputs as they are,
+/// so the error includes the rounding of the packing
+pub fn quantization_error(
+    model: &Model,
+    quantized: &QuantizedModel,
+    precalculated_input: (usize, ArrayView1<u8>),
+    inputs: &[(usize, ArrayView2<u8>)],
+    formats: &[PackedFormat],
+) -> QuantizationError {
+    let (precalculated_index, precalculated_x) = precalculated_input;
+    let precalculated = model.precalculate(precalculated_index, unpack_array(precalculated_x).view());
+    let unpacked: Vec<(usize, Array)> = inputs.iter().map(|(i, x)| (*i, unpack_array(*x))).collect();
+    let unpacked: Vec<(usize, ArrayView2<f32>)> = unpacked.iter().map(|(i, x)| (*i, x.view())).collect();
+    let expected = model.forward_from_precalc(&unpacked, &precalculated);
+
+    let precalculated = quantized.precalculate(precalculated_index, precalculated_x);
+    let packed: Vec<(usize, PackedInput)> = inputs.iter().map(|(i, x)| (*i, PackedInput::Unsigned(*x))).collect();
+    let actual = quantized.forward_from_precalc(&packed, &precalculated, formats);
+
+    let mut max_abs = 0.0f32;
+    let mut sum_abs = 0.0;
+    let mut n_values = 0;
+    for (expected, actual) in expected.iter().zip(actual.iter()) {
+        for (e, a) in expected.iter().zip(actual.unpack().iter()) {
+            let error = (e - a).abs();
+            max_abs = max_abs.max(error);
+            sum_abs += error;
+            n_values += 1;
+        }
+    }
+    QuantizationError { max_abs, mean_abs: sum_abs / n_values.max(1) as f32 }
+}
+
+/// Same as pack_with_negative, except for values within an ulp of a half step. f32::round is a libm call
+/// without SSE4.1, which made packing slower than the whole output layer
+fn pack_output_signed(value: f32) -> i8 {
+    let value = value.clamp(-1.0, 1.0) * 127.0;
+    (value + 0.5f32.copysign(value)) as i8
+}
+
+/// Same as pack, see pack_output_signed
+fn pack_output_unsigned(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0 + 0.5) as u8
+}
+
+/// Symmetric i8 per row
+fn quantize_rows(x: &Array) -> (Array2<i8>, Array1<f32>) {
+    let max_abs = x.fold_axis(Axis(1), 0.0, |max: &f32, v| max.max(v.abs()));
+    let scales = max_abs.mapv(|max| if max > 0.0 { max / 127.0 } else { 1.0 });
+    let row_scales = scales.view().insert_axis(Axis(1));
+    let quantized = Zip::from(x)
+        .and_broadcast(&row_scales)
+        .map_collect(|v, scale| (v / scale).round().clamp(-127.0, 127.0) as i8);
+    (quantized, scales)
+}
+
+/// Integer matmul with the transposed weight
+fn matmul<T: Packed>(x: ArrayView2<T>, weight: &Array2<i8>) -> Array2<i32> {
+    let n_inputs = weight.ncols();
+    let weight = weight.as_slice().expect("Quantized weights are in standard layout");
+    let mut x_values = vec![0i16; n_inputs];
+    let mut out = Array2::zeros((x.nrows(), weight.len() / n_inputs.max(1)));
+    for (x_row, mut out_row) in x.outer_iter().zip(out.outer_iter_mut()) {
+        for (x_value, packed) in x_values.iter_mut().zip(x_row.iter()) {
+            *x_value = packed.to_i16();
+        }
+        let out_row = out_row.as_slice_mut().unwrap();
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: avx2 is available
+            unsafe { dot_rows_avx2(&x_values, weight, out_row) };
+            continue;
+        }
+        dot_rows(&x_values, weight, out_row);
+    }
+    out
+}
+
+/// Without avx2 the i32 multiplies are barely vectorized, about 4x slower
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn dot_rows_avx2(x: &[i16], weight: &[i8], out: &mut [i32]) {
+    dot_rows(x, weight, out)
+}
+
+#[inline(always)]
+fn dot_rows(x: &[i16], weight: &[i8], out: &mut [i32]) {
+    for (acc, weight_row) in out.iter_mut().zip(weight.chunks_exact(x.len())) {
+        *acc = x.iter().zip(weight_row).map(|(x, w)| *x as i32 * *w as i32).sum();
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use ndarray_rand::RandomExt;
+    use ndarray_rand::rand_distr::Uniform;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use crate::GuardianSettings;
+    use crate::cpu::interface::Genome;
+    use crate::cpu::{pack, pack_with_negative};
+    use super::*;
+
+    /// Same as PackedOutput::new, with the exact pack and pack_with_negative
+    fn pack_exact(output: &Array, format: PackedFormat) -> PackedOutput {
+        match format {
+            PackedFormat::Unsigned => PackedOutput::Unsigned(output.mapv(pack)),
+            PackedFormat::Signed => PackedOutput::Signed(output.mapv(pack_with_negative)),
+        }
+    }
+
+    #[test]
+    pub fn test_quantized() {
+        let g_settings = GuardianSettings::default();
+        let mut rng = StdRng::seed_from_u64(1);
+        let genome = Genome::new(&g_settings, Some(rng.clone()));
+        let neuron_state = Array1::random_using(g_settings.neuron_state_size, Uniform::new_inclusive(0, 255), &mut rng);
+        let neuron_states = Array2::random_using((64, g_settings.neuron_state_size), Uniform::new_inclusive(0, 255), &mut rng);
+        let nodes = Array2::random_using((64, g_settings.node_size), Uniform::new_inclusive(0, 255), &mut rng);
+        let nodes_other = Array2::random_using((64, g_settings.node_size), Uniform::new_inclusive(0, 255), &mut rng);
+
+        // Outputs are deltas of the node state and of the force
+        let model = &genome.interconnected_node_state_update;
+        let quantized = model.quantize();
+        let inputs = [(1, neuron_states.view()), (2, nodes.view()), (3, nodes_other.view())];
+        let formats = [PackedFormat::Signed, PackedFormat::Signed];
+        let error = quantization_error(model, &quantized, (0, neuron_state.view()), &inputs, &formats);
+        // Against the unpacked f32 outputs, which are within ±0.1. One step of pack_with_negative is 0.008, so the
+        // rounding alone is up to 0.004. Most outputs are within a step, a few are off by 3 steps
+        assert!(error.max_abs < 0.035, "{error:?}");
+        assert!(error.mean_abs < 0.004, "{error:?}");
+
+        let precalculated = quantized.precalculate(0, neuron_state.view());
+        assert!(precalculated.iter().any(|v| *v != 0.0));
+        let packed = inputs.map(|(i, x)| (i, PackedInput::Unsigned(x)));
+        let outputs = quantized.forward_from_precalc(&packed, &precalculated, &formats);
+        assert!(outputs.iter().all(|output| matches!(output, PackedOutput::Signed(_))));
+        // Negative deltas are kept
+        assert!(outputs[0].unpack().iter().any(|v| *v < 0.0));
+
+        // Unsigned would clamp the negative deltas to 0, which is a larger error
+        let unsigned = [PackedFormat::Unsigned, PackedFormat::Unsigned];
+        let unsigned_error = quantization_error(model, &quantized, (0, neuron_state.view()), &inputs, &unsigned);
+        assert!(unsigned_error.mean_abs > error.mean_abs, "{unsigned_error:?} {error:?}");
+
+        // The packing of the outputs matches pack and pack_with_negative
+        let values = Array2::from_shape_fn((1, 401), |(_, i)| i as f32 / 200.0 - 1.0);
+        assert_eq!(PackedOutput::new(&values, PackedFormat::Unsigned), pack_exact(&values, PackedFormat::Unsigned));
+        assert_eq!(PackedOutput::new(&values, PackedFormat::Signed), pack_exact(&values, PackedFormat::Signed));
+    }
+}
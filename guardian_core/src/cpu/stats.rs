@@ -1,10 +1,105 @@
 //! Notes: Each core can have a stat struct that sums up all types of things, such as number of connections and such
 //! Should be atomic. Should not cost that much in performance
 
-use std::sync::atomic::AtomicU32;
+use std::sync::atomic::{AtomicU32, Ordering};
 
+use serde::{Deserialize, Serialize};
+
+use crate::GuardianSettings;
+use crate::cpu::interface::{NodeState, State};
+use crate::cpu::node_global_to_local_index;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StatCounter(AtomicU32);
+
+/// Stats for one step. Counters are incremented by the stages, aggregates are calculated after the last stage
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Stats {
-    new_connections: AtomicU32,
-    failed_connections: AtomicU32,
-    reset_pending_index: AtomicU32,
-}
\ No newline at end of file
+    // IO
+    pub io_port_updates: StatCounter,
+    pub nexus_updates: StatCounter,
+
+    // States
+    pub connected_node_updates: StatCounter,
+    pub disconnected_node_updates: StatCounter,
+    pub intra_node_updates: StatCounter,
+    pub neuron_updates: StatCounter,
+
+    // Interconnections plasticity
+    pub searching_nodes: StatCounter,
+    pub connecting_nodes: StatCounter,
+    pub takeover_attempts: StatCounter,
+    pub new_connections: StatCounter,
+    pub failed_connections: StatCounter,
+    pub reset_pending_index: StatCounter,
+
+    // Intraconnections plasticity
+    pub new_intra_connections: StatCounter,
+    pub failed_intra_connections: StatCounter,
+    pub reset_intra_pending_index: StatCounter,
+
+    // Precalc cache
+    pub precalc_hits: StatCounter,
+    pub precalc_misses: StatCounter,
+
+    // Aggregates
+    pub mutual_connections: u32,  // Pairs of nodes connected to each other
+    pub mean_net_force: f32,  // Mean net force of the mutually connected nodes
+    pub failed_nodes: u32,  // Interconnections in NodeState::Failed
+    pub failed_intra_nodes: u32,  // Intraconnections in NodeState::Failed
+}
+
+impl Clone for StatCounter {
+    fn clone(&self) -> Self {
+        Self(self.get().into())
+    }
+}
+
+impl StatCounter {
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, value: usize) {
+        self.0.fetch_add(value as u32, Ordering::Relaxed);
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Calculates the aggregates from the state after the step
+    pub fn aggregate(&mut self, state: &State, g_settings: &GuardianSettings) {
+        let mut mutual_connections = 0;
+        let mut total_net_force = 0.0;
+        for ((neuron_index, node_local_index), connection) in state.inter_connections.indexed_iter() {
+            let node_global_index_self = neuron_index * g_settings.n_nodes_per_neuron + node_local_index;
+            let node_global_index_other = connection.get_index();
+            if node_global_index_other <= node_global_index_self {
+                continue;  // Count each pair once. Connections to self are not counted
+            }
+            let (neuron_index_other, node_local_index_other) = node_global_to_local_index(node_global_index_other, g_settings);
+            let connection_other = &state.inter_connections[[neuron_index_other, node_local_index_other]];
+            if connection_other.get_index() == node_global_index_self {
+                mutual_connections += 1;
+                total_net_force += connection.get_net_force();
+            }
+        }
+        self.mutual_connections = mutual_connections;
+        self.mean_net_force = if mutual_connections > 0 { total_net_force / mutual_connections as f32 } else { 0.0 };
+        self.failed_nodes = state.inter_connection_counters
+            .iter()
+            .filter(|counter| counter.get_state(g_settings) == NodeState::Failed)
+            .count() as u32;
+        self.failed_intra_nodes = state.intra_connection_counters
+            .iter()
+            .filter(|counter| counter.get_state(g_settings) == NodeState::Failed)
+            .count() as u32;
+    }
+}
@@ -0,0 +1,86 @@
+//! Multi-armed bandit: Each arm pays a reward with its own probability, drawn at reset. The arm is the
+//! highest of the first n_arms values of the response. The reward of the previous step is written to all values
+//! of REWARD_PORT and to the modulators, the stimulus port is not used. Scored as the mean reward relative to always
+//! pulling the best arm
+
+use ndarray::Array1;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use crate::cpu::interface::Network;
+use crate::cpu::stats::Stats;
+use crate::evolution::Fitness;
+use super::*;
+
+pub struct Bandit {
+    n_arms: usize,
+    seed: u64,
+    rng: StdRng,
+    probabilities: Vec<f64>,
+    reward: f32,  // Of the previous step
+    score: Score,
+}
+
+impl Bandit {
+    pub fn new(n_arms: usize, seed: u64) -> Self {
+        assert!(n_arms > 0);
+        Self {
+            n_arms,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            probabilities: vec![],
+            reward: 0.0,
+            score: Score::default(),
+        }
+    }
+}
+
+impl Fitness for Bandit {
+    fn reset(&mut self, network: &mut Network) -> Result<()> {
+        check_io_ports(network, self.n_io_ports())?;
+        let io_size = network.g_settings.io_size;
+        ensure!(self.n_arms <= io_size, "{} arms do not fit in an io port of {io_size} values", self.n_arms);
+        self.rng = StdRng::seed_from_u64(self.seed);
+        self.probabilities = (0..self.n_arms).map(|_| self.rng.gen()).collect();
+        self.reward = 0.0;
+        self.score = Score::default();
+        Ok(())
+    }
+
+    fn before_step(&mut self, _step: usize, network: &mut Network) {
+        let reward = Array1::from_elem(network.g_settings.io_size, self.reward);
+        network.state.write_io_input(REWARD_PORT, reward.view());
+        let modulators = Array1::from_elem(network.g_settings.modulator_size, self.reward);
+        network.state.write_modulators(modulators.view());
+    }
+
+    fn after_step(&mut self, _step: usize, network: &mut Network, _stats: &Stats) {
+        let response = read_response(network);
+        let arm = (0..self.n_arms).max_by(|a, b| response[*a].total_cmp(&response[*b])).unwrap();
+        self.reward = if self.rng.gen_bool(self.probabilities[arm]) { 1.0 } else { 0.0 };
+        self.score.add(self.reward);
+    }
+
+    fn score(&mut self, _network: &Network) -> f32 {
+        let best = self.probabilities.iter().copied().fold(0.0, f64::max) as f32;
+        if best > 0.0 {
+            (self.score.mean() / best).min(1.0)
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Environment for Bandit {
+    fn n_io_ports(&self) -> usize {
+        3
+    }
+
+    /// Always the best arm, although it only pays with its probability
+    fn target(&self, _step: usize, io_size: usize) -> Option<Array1<f32>> {
+        let best_arm = (0..self.n_arms).max_by(|a, b| self.probabilities[*a].total_cmp(&self.probabilities[*b]))?;
+        let mut target = Array1::zeros(io_size);
+        target[best_arm] = 1.0;
+        Some(target)
+    }
+}
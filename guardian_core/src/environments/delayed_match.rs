@@ -0,0 +1,89 @@
+//! Delayed match-to-sample: A sample pattern is shown, followed by delay steps without input and then a test
+//! pattern. Half of the tests are the sample again. At the test, the response should be 1 if it matches and 0 if not
+
+use ndarray::Array1;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use crate::cpu::interface::Network;
+use crate::cpu::stats::Stats;
+use crate::evolution::Fitness;
+use super::*;
+
+pub struct DelayedMatchToSample {
+    delay: usize,
+    seed: u64,
+    rng: StdRng,
+    sample: Array1<f32>,
+    is_match: bool,
+    score: Score,
+}
+
+impl DelayedMatchToSample {
+    pub fn new(delay: usize, seed: u64) -> Self {
+        Self {
+            delay,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            sample: Array1::zeros(0),
+            is_match: false,
+            score: Score::default(),
+        }
+    }
+
+    /// Sample, delay steps, test
+    fn phase(&self, step: usize) -> usize {
+        step % (self.delay + 2)
+    }
+}
+
+impl Fitness for DelayedMatchToSample {
+    fn reset(&mut self, network: &mut Network) -> Result<()> {
+        check_io_ports(network, self.n_io_ports())?;
+        self.rng = StdRng::seed_from_u64(self.seed);
+        self.score = Score::default();
+        Ok(())
+    }
+
+    fn before_step(&mut self, step: usize, network: &mut Network) {
+        let io_size = network.g_settings.io_size;
+        let phase = self.phase(step);
+        let stimulus = if phase == 0 {
+            self.sample = random_pattern(io_size, &mut self.rng);
+            self.sample.clone()
+        } else if phase == self.delay + 1 {
+            self.is_match = self.rng.gen_bool(0.5);
+            if self.is_match {
+                self.sample.clone()
+            } else {
+                // A random pattern can be the sample by chance, then it is a match
+                let test = random_pattern(io_size, &mut self.rng);
+                self.is_match = test == self.sample;
+                test
+            }
+        } else {
+            Array1::zeros(io_size)
+        };
+        write_stimulus(network, stimulus.view());
+    }
+
+    fn after_step(&mut self, step: usize, network: &mut Network, _stats: &Stats) {
+        if let Some(target) = self.target(step, network.g_settings.io_size) {
+            self.score.add(similarity(read_response(network).view(), target.view()));
+        }
+    }
+
+    fn score(&mut self, _network: &Network) -> f32 {
+        self.score.mean()
+    }
+}
+
+impl Environment for DelayedMatchToSample {
+    fn n_io_ports(&self) -> usize {
+        2
+    }
+
+    fn target(&self, step: usize, io_size: usize) -> Option<Array1<f32>> {
+        (self.phase(step) == self.delay + 1).then(|| Array1::from_elem(io_size, if self.is_match { 1.0 } else { 0.0 }))
+    }
+}
@@ -0,0 +1,176 @@
+//! Small synthetic tasks that measure if a network can learn during its lifetime
+//!
+//! Every environment drives the network through the io ports and implements Fitness, so it can be passed
+//! directly to evolution::evaluate or Population::step. The stimulus is written to STIMULUS_PORT before each step
+//! and the response is read from RESPONSE_PORT after it. The network needs at least n_io_ports ports, otherwise
+//! reset fails and so does the evaluation.
+//!
+//! The lifetime is split into episodes that repeat until the last step. The environment is reseeded in reset,
+//! so every genome gets the same stimuli. Scores are between 0 and 1, where 1 is a perfect response.
+
+use anyhow::{ensure, Result};
+use ndarray::{Array1, ArrayView1};
+use rand::Rng;
+use rand::rngs::StdRng;
+
+use crate::cpu::interface::Network;
+use crate::evolution::Fitness;
+
+pub mod bandit;
+pub mod delayed_match;
+pub mod pattern_completion;
+pub mod sequence_recall;
+pub mod xor_over_time;
+
+pub use bandit::Bandit;
+pub use delayed_match::DelayedMatchToSample;
+pub use pattern_completion::PatternCompletion;
+pub use sequence_recall::SequenceRecall;
+pub use xor_over_time::XorOverTime;
+
+pub const STIMULUS_PORT: usize = 0;
+pub const RESPONSE_PORT: usize = 1;
+pub const REWARD_PORT: usize = 2;  // Only used by Bandit
+
+pub trait Environment: Fitness {
+    fn n_io_ports(&self) -> usize;
+
+    /// The best response to the stimulus of the step, None if the step is not scored
+    /// Only known between before_step and after_step of the step
+    fn target(&self, step: usize, io_size: usize) -> Option<Array1<f32>>;
+}
+
+/// Mean over the scored steps
+#[derive(Clone, Debug, Default)]
+struct Score {
+    sum: f32,
+    n_steps: usize,
+}
+
+impl Score {
+    fn add(&mut self, value: f32) {
+        self.sum += value;
+        self.n_steps += 1;
+    }
+
+    /// 0 if no step was scored, for example if the lifetime is shorter than an episode
+    fn mean(&self) -> f32 {
+        if self.n_steps == 0 {
+            0.0
+        } else {
+            self.sum / self.n_steps as f32
+        }
+    }
+}
+
+/// 1 - mean absolute difference
+pub fn similarity(response: ArrayView1<f32>, target: ArrayView1<f32>) -> f32 {
+    let difference: f32 = response.iter().zip(target.iter()).map(|(r, t)| (r - t).abs()).sum();
+    1.0 - difference / target.len().max(1) as f32
+}
+
+/// Values of 0 or 1
+fn random_pattern(size: usize, rng: &mut StdRng) -> Array1<f32> {
+    Array1::from_shape_fn(size, |_| if rng.gen_bool(0.5) { 1.0 } else { 0.0 })
+}
+
+fn check_io_ports(network: &Network, n_io_ports: usize) -> Result<()> {
+    let n_ports = network.state.io_ports.len();
+    ensure!(n_ports >= n_io_ports, "The environment needs {n_io_ports} io ports, the network has {n_ports}");
+    Ok(())
+}
+
+fn write_stimulus(network: &mut Network, stimulus: ArrayView1<f32>) {
+    network.state.write_io_input(STIMULUS_PORT, stimulus);
+}
+
+fn read_response(network: &Network) -> Array1<f32> {
+    network.state.read_io_output(RESPONSE_PORT)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rand::SeedableRng;
+    use rayon::ThreadPoolBuilder;
+
+    use crate::cpu::interface::Genome;
+    use crate::cpu::pack_array;
+    use crate::cpu::stats::Stats;
+    use crate::evolution::evaluate;
+    use crate::settings::Settings;
+    use super::*;
+
+    #[test]
+    pub fn test_environments() {
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let mut settings = Settings::preset("tiny").unwrap();
+        settings.n_settings.n_io_ports = 3;
+        settings.n_settings.deterministic = true;
+        let (g_settings, n_settings) = (&settings.g_settings, &settings.n_settings);
+        let genome = Genome::new(g_settings, Some(StdRng::seed_from_u64(1)));
+
+        let mut environments: Vec<Box<dyn Environment>> = vec![
+            Box::new(SequenceRecall::new(3, 1)),
+            Box::new(XorOverTime::new(2, 1)),
+            Box::new(PatternCompletion::new(2, 0.5, 1)),
+            Box::new(DelayedMatchToSample::new(2, 1)),
+            Box::new(Bandit::new(3, 1)),
+        ];
+        for environment in environments.iter_mut() {
+            assert!(environment.n_io_ports() <= n_settings.n_io_ports);
+            let score = evaluate(&genome, g_settings, n_settings, environment.as_mut(), 12, 1, &pool).unwrap();
+            assert!((0.0..=1.0).contains(&score), "{score}");
+            // Reseeded, so the same genome gets the same score
+            assert_eq!(evaluate(&genome, g_settings, n_settings, environment.as_mut(), 12, 1, &pool).unwrap(), score);
+        }
+
+        // Too few io ports, or more arms than values in a port
+        let mut too_few = n_settings.clone();
+        too_few.n_io_ports = 1;
+        assert!(evaluate(&genome, g_settings, &too_few, &mut SequenceRecall::new(3, 1), 12, 1, &pool).is_err());
+        let mut bandit = Bandit::new(g_settings.io_size + 1, 1);
+        assert!(evaluate(&genome, g_settings, n_settings, &mut bandit, 12, 1, &pool).is_err());
+
+        assert_eq!(similarity(ndarray::arr1(&[1.0, 0.0]).view(), ndarray::arr1(&[1.0, 1.0]).view()), 0.5);
+    }
+
+    /// Responds with the target, or the inverted target, instead of updating the network
+    fn oracle_score(environment: &mut dyn Environment, network: &mut Network, n_steps: usize, inverted: bool) -> f32 {
+        let io_size = network.g_settings.io_size;
+        environment.reset(network).unwrap();
+        for step in 0..n_steps {
+            environment.before_step(step, network);
+            if let Some(target) = environment.target(step, io_size) {
+                let response = if inverted { target.mapv(|v| 1.0 - v) } else { target };
+                network.state.io_outputs.row_mut(RESPONSE_PORT).assign(&pack_array(response));
+            }
+            environment.after_step(step, network, &Stats::new());
+        }
+        environment.score(network)
+    }
+
+    #[test]
+    pub fn test_environment_oracles() {
+        let mut settings = Settings::preset("tiny").unwrap();
+        settings.n_settings.n_io_ports = 3;
+        let mut network = Network::new(&settings.g_settings, &settings.n_settings, 1);
+
+        let mut environments: Vec<Box<dyn Environment>> = vec![
+            Box::new(SequenceRecall::new(3, 1)),
+            Box::new(XorOverTime::new(2, 1)),
+            Box::new(PatternCompletion::new(2, 0.5, 1)),
+            Box::new(DelayedMatchToSample::new(2, 1)),
+        ];
+        for environment in environments.iter_mut() {
+            assert_eq!(oracle_score(environment.as_mut(), &mut network, 24, false), 1.0);
+            assert_eq!(oracle_score(environment.as_mut(), &mut network, 24, true), 0.0);
+        }
+
+        // The reward is random, and even the other arms pay sometimes
+        let mut bandit = Bandit::new(3, 1);
+        let perfect = oracle_score(&mut bandit, &mut network, 400, false);
+        let inverted = oracle_score(&mut bandit, &mut network, 400, true);
+        assert!(perfect > 0.9, "{perfect}");
+        assert!(inverted < perfect, "{inverted}");
+    }
+}
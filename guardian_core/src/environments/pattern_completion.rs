@@ -0,0 +1,99 @@
+//! A few random patterns are shown alternately, complete and with a share of the values masked out.
+//! Masked values are 0.5, and the response should fill them in with the values of the complete pattern
+
+use ndarray::{Array1, Axis};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use crate::cpu::interface::Network;
+use crate::cpu::stats::Stats;
+use crate::evolution::Fitness;
+use super::*;
+
+const MASKED: f32 = 0.5;
+
+pub struct PatternCompletion {
+    n_patterns: usize,
+    masked_fraction: f64,
+    seed: u64,
+    rng: StdRng,
+    patterns: Vec<Array1<f32>>,
+    current: Option<(usize, Vec<bool>)>,  // Pattern index and mask of the step being scored
+    score: Score,
+}
+
+impl PatternCompletion {
+    pub fn new(n_patterns: usize, masked_fraction: f64, seed: u64) -> Self {
+        assert!(n_patterns > 0);
+        assert!((0.0..=1.0).contains(&masked_fraction));
+        Self {
+            n_patterns,
+            masked_fraction,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            patterns: vec![],
+            current: None,
+            score: Score::default(),
+        }
+    }
+}
+
+impl Fitness for PatternCompletion {
+    fn reset(&mut self, network: &mut Network) -> Result<()> {
+        check_io_ports(network, self.n_io_ports())?;
+        self.rng = StdRng::seed_from_u64(self.seed);
+        let io_size = network.g_settings.io_size;
+        self.patterns = (0..self.n_patterns).map(|_| random_pattern(io_size, &mut self.rng)).collect();
+        self.current = None;
+        self.score = Score::default();
+        Ok(())
+    }
+
+    /// Even steps show a complete pattern, odd steps a masked one
+    fn before_step(&mut self, step: usize, network: &mut Network) {
+        let pattern_index = self.rng.gen_range(0..self.n_patterns);
+        let mut stimulus = self.patterns[pattern_index].clone();
+        if step.is_multiple_of(2) {
+            self.current = None;
+        } else {
+            let mask: Vec<bool> = (0..stimulus.len()).map(|_| self.rng.gen_bool(self.masked_fraction)).collect();
+            for (value, masked) in stimulus.iter_mut().zip(mask.iter()) {
+                if *masked {
+                    *value = MASKED;
+                }
+            }
+            self.current = Some((pattern_index, mask));
+        }
+        write_stimulus(network, stimulus.view());
+    }
+
+    /// Only the masked values are scored
+    fn after_step(&mut self, step: usize, network: &mut Network, _stats: &Stats) {
+        let Some(target) = self.target(step, network.g_settings.io_size) else {
+            return;
+        };
+        let Some((_, mask)) = self.current.take() else {
+            return;
+        };
+        let masked: Vec<usize> = (0..mask.len()).filter(|index| mask[*index]).collect();
+        if masked.is_empty() {
+            return;
+        }
+        let response = read_response(network).select(Axis(0), &masked);
+        self.score.add(similarity(response.view(), target.select(Axis(0), &masked).view()));
+    }
+
+    fn score(&mut self, _network: &Network) -> f32 {
+        self.score.mean()
+    }
+}
+
+impl Environment for PatternCompletion {
+    fn n_io_ports(&self) -> usize {
+        2
+    }
+
+    fn target(&self, _step: usize, _io_size: usize) -> Option<Array1<f32>> {
+        self.current.as_ref().map(|(pattern_index, _)| self.patterns[*pattern_index].clone())
+    }
+}
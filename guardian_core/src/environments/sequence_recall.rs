@@ -0,0 +1,70 @@
+//! A sequence of random patterns is shown, followed by as many steps without input where the network should
+//! respond with the sequence in the same order. The same sequence is repeated every episode
+
+use ndarray::Array1;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+use crate::cpu::interface::Network;
+use crate::cpu::stats::Stats;
+use crate::evolution::Fitness;
+use super::*;
+
+pub struct SequenceRecall {
+    length: usize,
+    seed: u64,
+    sequence: Vec<Array1<f32>>,
+    score: Score,
+}
+
+impl SequenceRecall {
+    pub fn new(length: usize, seed: u64) -> Self {
+        assert!(length > 0);
+        Self { length, seed, sequence: vec![], score: Score::default() }
+    }
+
+    /// Position in the episode, the first half shows the sequence and the second half is recalled
+    fn phase(&self, step: usize) -> usize {
+        step % (2 * self.length)
+    }
+}
+
+impl Fitness for SequenceRecall {
+    fn reset(&mut self, network: &mut Network) -> Result<()> {
+        check_io_ports(network, self.n_io_ports())?;
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let io_size = network.g_settings.io_size;
+        self.sequence = (0..self.length).map(|_| random_pattern(io_size, &mut rng)).collect();
+        self.score = Score::default();
+        Ok(())
+    }
+
+    fn before_step(&mut self, step: usize, network: &mut Network) {
+        let stimulus = match self.sequence.get(self.phase(step)) {
+            Some(pattern) => pattern.clone(),
+            None => Array1::zeros(network.g_settings.io_size),
+        };
+        write_stimulus(network, stimulus.view());
+    }
+
+    fn after_step(&mut self, step: usize, network: &mut Network, _stats: &Stats) {
+        if let Some(target) = self.target(step, network.g_settings.io_size) {
+            self.score.add(similarity(read_response(network).view(), target.view()));
+        }
+    }
+
+    fn score(&mut self, _network: &Network) -> f32 {
+        self.score.mean()
+    }
+}
+
+impl Environment for SequenceRecall {
+    fn n_io_ports(&self) -> usize {
+        2
+    }
+
+    fn target(&self, step: usize, _io_size: usize) -> Option<Array1<f32>> {
+        let phase = self.phase(step);
+        (phase >= self.length).then(|| self.sequence[phase - self.length].clone())
+    }
+}
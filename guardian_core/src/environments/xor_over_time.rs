@@ -0,0 +1,67 @@
+//! A random bit is shown every step. The response should be the XOR of the current bit and the bit shown
+//! delay steps before, which needs both memory and a nonlinear combination
+
+use ndarray::Array1;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use crate::cpu::interface::Network;
+use crate::cpu::stats::Stats;
+use crate::evolution::Fitness;
+use super::*;
+
+pub struct XorOverTime {
+    delay: usize,
+    seed: u64,
+    rng: StdRng,
+    bits: Vec<bool>,
+    score: Score,
+}
+
+impl XorOverTime {
+    pub fn new(delay: usize, seed: u64) -> Self {
+        assert!(delay > 0);
+        Self { delay, seed, rng: StdRng::seed_from_u64(seed), bits: vec![], score: Score::default() }
+    }
+}
+
+/// All values of the port are set to the bit
+fn bit_pattern(bit: bool, io_size: usize) -> Array1<f32> {
+    Array1::from_elem(io_size, if bit { 1.0 } else { 0.0 })
+}
+
+impl Fitness for XorOverTime {
+    fn reset(&mut self, network: &mut Network) -> Result<()> {
+        check_io_ports(network, self.n_io_ports())?;
+        self.rng = StdRng::seed_from_u64(self.seed);
+        self.bits.clear();
+        self.score = Score::default();
+        Ok(())
+    }
+
+    fn before_step(&mut self, _step: usize, network: &mut Network) {
+        let bit = self.rng.gen_bool(0.5);
+        self.bits.push(bit);
+        write_stimulus(network, bit_pattern(bit, network.g_settings.io_size).view());
+    }
+
+    fn after_step(&mut self, step: usize, network: &mut Network, _stats: &Stats) {
+        if let Some(target) = self.target(step, network.g_settings.io_size) {
+            self.score.add(similarity(read_response(network).view(), target.view()));
+        }
+    }
+
+    fn score(&mut self, _network: &Network) -> f32 {
+        self.score.mean()
+    }
+}
+
+impl Environment for XorOverTime {
+    fn n_io_ports(&self) -> usize {
+        2
+    }
+
+    fn target(&self, step: usize, io_size: usize) -> Option<Array1<f32>> {
+        (step >= self.delay).then(|| bit_pattern(self.bits[step] ^ self.bits[step - self.delay], io_size))
+    }
+}
@@ -0,0 +1,359 @@
+//! Genetic search over genomes
+//!
+//! All neurons in a network share the same genome, so the search is done over whole networks:
+//! every genome in the population gets a fresh (randomized) state, is run for a lifetime of steps
+//! and is then scored by a fitness function. The next generation is built with elitism,
+//! tournament selection, crossover and mutation.
+//!
+//! Everything is driven by a seeded StdRng, so the same seed gives the same run.
+
+use anyhow::Result;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::ThreadPool;
+use tracing::{debug, info};
+
+use crate::{GuardianSettings, NetworkSettings};
+use crate::cpu::interface::{Genome, Network, State};
+use crate::cpu::process::update;
+use crate::cpu::stats::Stats;
+use crate::settings::InvalidSettings;
+use crate::topology::NeighbourhoodCache;
+
+pub mod selection;
+
+#[derive(Clone, Debug)]
+pub struct EvolutionSettings {
+    pub population_size: usize,
+    pub n_elites: usize,  // Genomes copied unchanged to the next generation, where they are evaluated again
+    pub tournament_size: usize,
+    pub crossover_rate: f64,  // Probability that a child has two parents
+    pub mutation_rate: f64,  // Probability that a weight is mutated
+    pub mutation_strength: f32,  // Standard deviation of the mutation
+    pub architecture_mutation_rate: f64,  // Probability that an activation or the output bias of a model is changed
+    pub n_steps: usize,  // Lifetime of each network during evaluation
+}
+
+impl Default for EvolutionSettings {
+    fn default() -> Self {
+        Self {
+            population_size: 16,
+            n_elites: 2,
+            tournament_size: 3,
+            crossover_rate: 0.5,
+            mutation_rate: 0.05,
+            mutation_strength: 0.02,
+            architecture_mutation_rate: 0.01,
+            n_steps: 16,
+        }
+    }
+}
+
+impl EvolutionSettings {
+    pub fn validate(&self) -> Result<(), InvalidSettings> {
+        let mut violations = vec![];
+        if self.population_size == 0 {
+            violations.push("population_size is 0".to_string());
+        }
+        if self.n_elites > self.population_size {
+            violations.push(format!("n_elites is {}, more than population_size ({})", self.n_elites, self.population_size));
+        }
+        if self.tournament_size == 0 {
+            violations.push("tournament_size is 0".to_string());
+        }
+        let rates = [
+            ("crossover_rate", self.crossover_rate),
+            ("mutation_rate", self.mutation_rate),
+            ("architecture_mutation_rate", self.architecture_mutation_rate),
+        ];
+        for (name, rate) in rates {
+            if !(0.0..=1.0).contains(&rate) {
+                violations.push(format!("{name} is {rate}, must be between 0 and 1"));
+            }
+        }
+        if !self.mutation_strength.is_finite() || self.mutation_strength < 0.0 {
+            violations.push(format!("mutation_strength is {}, must be finite and not negative", self.mutation_strength));
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidSettings(violations))
+        }
+    }
+}
+
+/// Scores a network during and after its lifetime. Higher is better
+/// The hooks allow the fitness to feed the network and read from it every step
+pub trait Fitness {
+    /// Called once with the fresh network, before the first step. An error stops the evaluation, such as a network
+    /// that does not have the io ports the fitness needs
+    fn reset(&mut self, _network: &mut Network) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called before every step
+    fn before_step(&mut self, _step: usize, _network: &mut Network) {}
+
+    /// Called after every step, with the stats of the step
+    fn after_step(&mut self, _step: usize, _network: &mut Network, _stats: &Stats) {}
+
+    /// Called once at the end of the lifetime
+    fn score(&mut self, network: &Network) -> f32;
+}
+
+/// Any closure can be used to score the final network
+impl<F> Fitness for F
+    where F: FnMut(&Network) -> f32
+{
+    fn score(&mut self, network: &Network) -> f32 {
+        self(network)
+    }
+}
+
+#[derive(Clone)]
+pub struct Individual {
+    pub genome: Genome,
+    pub fitness: Option<f32>,
+}
+
+pub struct Population {
+    pub individuals: Vec<Individual>,
+    pub generation: usize,
+    pub e_settings: EvolutionSettings,
+    pub g_settings: GuardianSettings,
+    pub n_settings: NetworkSettings,
+    rng: StdRng,
+}
+
+/// Runs the genome on a fresh state for n_steps and returns the fitness
+/// The state is randomized with the seed, so genomes evaluated with the same seed start from the same state
+pub fn evaluate<F: Fitness + ?Sized>(
+    genome: &Genome,
+    g_settings: &GuardianSettings,
+    n_settings: &NetworkSettings,
+    fitness: &mut F,
+    n_steps: usize,
+    seed: u64,
+    pool: &ThreadPool
+) -> Result<f32> {
+    let mut state = State::new(g_settings, n_settings);
+    state.randomize(g_settings, n_settings, Some(StdRng::seed_from_u64(seed)));
+    let mut network = Network {
+        state,
+        genome: genome.clone(),
+        g_settings: g_settings.clone(),
+        n_settings: n_settings.clone(),
+        neighbourhood: NeighbourhoodCache::default(),
+    };
+    fitness.reset(&mut network)?;
+    for step in 0..n_steps {
+        fitness.before_step(step, &mut network);
+        let stats = update(&mut network, pool);
+        fitness.after_step(step, &mut network, &stats);
+    }
+    Ok(fitness.score(&network))
+}
+
+/// Mutates every model in the genome
+pub fn mutate_genome(genome: &mut Genome, e_settings: &EvolutionSettings, rng: &mut StdRng) {
+    let models = [
+        &mut genome.interconnected_node_state_update,
+        &mut genome.intraconnected_node_state_update,
+        &mut genome.neuron_state_update,
+        &mut genome.interconnections_plasticity_update,
+        &mut genome.intraconnections_plasticity_update,
+    ];
+    for model in models.into_iter().chain(genome.io_models.values_mut()) {
+        model.mutate(e_settings.mutation_rate, e_settings.mutation_strength, rng);
+        model.mutate_architecture(e_settings.architecture_mutation_rate, rng);
+    }
+}
+
+/// Crossover is done per model, so each model is a mix of both parents
+pub fn crossover_genome(genome_a: &Genome, genome_b: &Genome, rng: &mut StdRng) -> Result<Genome> {
+    let mut io_models = genome_a.io_models.clone();
+    for (name, model) in io_models.iter_mut() {
+        if let Some(model_b) = genome_b.io_models.get(name) {
+            *model = model.crossover(model_b, rng)?;
+        }
+    }
+    Ok(Genome {
+        interconnected_node_state_update: genome_a.interconnected_node_state_update.crossover(&genome_b.interconnected_node_state_update, rng)?,
+        intraconnected_node_state_update: genome_a.intraconnected_node_state_update.crossover(&genome_b.intraconnected_node_state_update, rng)?,
+        neuron_state_update: genome_a.neuron_state_update.crossover(&genome_b.neuron_state_update, rng)?,
+        interconnections_plasticity_update: genome_a.interconnections_plasticity_update.crossover(&genome_b.interconnections_plasticity_update, rng)?,
+        intraconnections_plasticity_update: genome_a.intraconnections_plasticity_update.crossover(&genome_b.intraconnections_plasticity_update, rng)?,
+        io_models,
+    })
+}
+
+impl Population {
+    pub fn new(
+        g_settings: &GuardianSettings,
+        n_settings: &NetworkSettings,
+        e_settings: &EvolutionSettings,
+        seed: u64
+    ) -> Result<Self> {
+        e_settings.validate()?;
+        let mut rng = StdRng::seed_from_u64(seed);
+        let individuals = (0..e_settings.population_size).map(|_| {
+            let genome_rng = StdRng::seed_from_u64(rng.gen());
+            Individual {
+                genome: Genome::new(g_settings, Some(genome_rng)),
+                fitness: None
+            }
+        })
+        .collect();
+        Ok(Self {
+            individuals,
+            generation: 0,
+            e_settings: e_settings.clone(),
+            g_settings: g_settings.clone(),
+            n_settings: n_settings.clone(),
+            rng,
+        })
+    }
+
+    /// Starts from an existing genome: the first individual is the genome, the rest are mutated copies of it
+    pub fn seed_with(&mut self, genome: &Genome) {
+        for (index, individual) in self.individuals.iter_mut().enumerate() {
+            individual.genome = genome.clone();
+            individual.fitness = None;
+            if index > 0 {
+                mutate_genome(&mut individual.genome, &self.e_settings, &mut self.rng);
+            }
+        }
+    }
+
+    /// Evaluates all individuals that have not been evaluated yet
+    /// All individuals in a generation start from the same state, which is drawn anew for every generation
+    pub fn evaluate<F: Fitness + ?Sized>(&mut self, fitness: &mut F, pool: &ThreadPool) -> Result<()> {
+        let seed = self.rng.gen();
+        for (index, individual) in self.individuals.iter_mut().enumerate() {
+            if individual.fitness.is_some() {
+                continue;
+            }
+            let score = evaluate(
+                &individual.genome,
+                &self.g_settings,
+                &self.n_settings,
+                fitness,
+                self.e_settings.n_steps,
+                seed,
+                pool
+            )?;
+            debug!("Generation {} individual {index}: fitness {score}", self.generation);
+            individual.fitness = Some(score);
+        }
+        Ok(())
+    }
+
+    pub fn best(&self) -> Option<&Individual> {
+        self.individuals
+            .iter()
+            .filter(|individual| individual.fitness.is_some())
+            .max_by(|a, b| a.fitness.unwrap().total_cmp(&b.fitness.unwrap()))
+    }
+
+    pub fn mean_fitness(&self) -> Option<f32> {
+        let scores: Vec<f32> = self.individuals.iter().filter_map(|individual| individual.fitness).collect();
+        if scores.is_empty() {
+            return None;
+        }
+        Some(scores.iter().sum::<f32>() / scores.len() as f32)
+    }
+
+    /// Replaces the population with the next generation. Must be evaluated before
+    pub fn next_generation(&mut self) -> Result<()> {
+        let fitness = selection::fitness_values(&self.individuals)?;
+        let mut individuals = vec![];
+
+        // Elites are kept as they are. They are evaluated again, since the next generation starts from another state
+        for index in selection::elites(&fitness, self.e_settings.n_elites) {
+            individuals.push(Individual { genome: self.individuals[index].genome.clone(), fitness: None });
+        }
+
+        while individuals.len() < self.e_settings.population_size {
+            let parent_a = selection::tournament(&fitness, self.e_settings.tournament_size, &mut self.rng);
+            let mut genome = if self.rng.gen_bool(self.e_settings.crossover_rate) {
+                let parent_b = selection::tournament(&fitness, self.e_settings.tournament_size, &mut self.rng);
+                crossover_genome(&self.individuals[parent_a].genome, &self.individuals[parent_b].genome, &mut self.rng)?
+            } else {
+                self.individuals[parent_a].genome.clone()
+            };
+            mutate_genome(&mut genome, &self.e_settings, &mut self.rng);
+            individuals.push(Individual { genome, fitness: None });
+        }
+        self.individuals = individuals;
+        self.generation += 1;
+        Ok(())
+    }
+
+    /// Evaluates the current generation and creates the next one. Returns the best fitness
+    pub fn step<F: Fitness + ?Sized>(&mut self, fitness: &mut F, pool: &ThreadPool) -> Result<f32> {
+        self.evaluate(fitness, pool)?;
+        let best = self.best().and_then(|individual| individual.fitness).unwrap_or(f32::MIN);
+        info!(
+            "Generation {}: best fitness {best}, mean fitness {}",
+            self.generation,
+            self.mean_fitness().unwrap_or(f32::MIN)
+        );
+        self.next_generation()?;
+        Ok(best)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rayon::ThreadPoolBuilder;
+
+    use super::*;
+
+    #[test]
+    pub fn test_evolution_is_reproducible() {
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let mut g_settings = GuardianSettings::downlevel_default();
+        g_settings.hidden_sizes = vec![8];
+        let mut n_settings = NetworkSettings::downlevel_default();
+        n_settings.n_neurons = 4;
+        let e_settings = EvolutionSettings {
+            population_size: 4,
+            n_elites: 1,
+            tournament_size: 2,
+            n_steps: 2,
+            ..Default::default()
+        };
+        n_settings.deterministic = true;
+
+        // Depends on the state after the lifetime, so evaluate must be reproducible as well
+        let mut fitness = |network: &Network| {
+            network.state.neuron_states.iter().map(|value| *value as f32).sum::<f32>()
+        };
+
+        let mut history = vec![];
+        for _ in 0..2 {
+            let mut population = Population::new(&g_settings, &n_settings, &e_settings, 1).unwrap();
+            let mut best = vec![];
+            for _ in 0..2 {
+                best.push(population.step(&mut fitness, &pool).unwrap());
+            }
+            assert_eq!(population.individuals.len(), e_settings.population_size);
+            history.push(best);
+        }
+        assert_eq!(history[0], history[1]);
+
+        // Elites are evaluated again with the state of the next generation
+        let mut population = Population::new(&g_settings, &n_settings, &e_settings, 1).unwrap();
+        population.step(&mut fitness, &pool).unwrap();
+        assert!(population.individuals.iter().all(|individual| individual.fitness.is_none()));
+
+        // Seeded from a genome
+        let genome = population.individuals[0].genome.clone();
+        population.seed_with(&genome);
+        assert!(population.individuals[0].genome == genome);
+        assert!(population.individuals[1].genome != genome);
+
+        let invalid = EvolutionSettings { mutation_rate: 1.5, mutation_strength: -1.0, ..e_settings };
+        assert_eq!(Population::new(&g_settings, &n_settings, &invalid, 1).err().unwrap().downcast::<InvalidSettings>().unwrap().0.len(), 2);
+    }
+}
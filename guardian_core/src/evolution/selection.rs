@@ -0,0 +1,35 @@
+use anyhow::{ensure, Context, Result};
+use rand::rngs::StdRng;
+use rand::Rng;
+
+use super::Individual;
+
+/// All individuals must have been evaluated before selection
+pub fn fitness_values(individuals: &[Individual]) -> Result<Vec<f32>> {
+    ensure!(!individuals.is_empty(), "Population is empty");
+    individuals
+        .iter()
+        .enumerate()
+        .map(|(index, individual)| individual.fitness.with_context(|| format!("Individual {index} has not been evaluated")))
+        .collect()
+}
+
+/// Indices of the n best individuals, best first. Ties are broken by the lowest index
+pub fn elites(fitness: &[f32], n_elites: usize) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..fitness.len()).collect();
+    indices.sort_by(|a, b| fitness[*b].total_cmp(&fitness[*a]).then(a.cmp(b)));
+    indices.truncate(n_elites);
+    indices
+}
+
+/// Picks tournament_size individuals at random (with replacement) and returns the index of the best one
+pub fn tournament(fitness: &[f32], tournament_size: usize, rng: &mut StdRng) -> usize {
+    let mut winner = rng.gen_range(0..fitness.len());
+    for _ in 1..tournament_size {
+        let candidate = rng.gen_range(0..fitness.len());
+        if fitness[candidate] > fitness[winner] {
+            winner = candidate;
+        }
+    }
+    winner
+}
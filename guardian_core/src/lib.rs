@@ -12,15 +12,17 @@
 //! A neuron has the following components:
 //!
 //! * Nodes (N):
-//!     Nodes are intraconnected inside a neuron with multiple connections (dendrites) as well as to other neurons (terminals)
-//!     Another way to do this would be to split the nodes up to dendrite nodes (only interconnected) and terminal nodes (interconnected)
-//!     There are some benifits with this, but makes the program and the model much more complex
+//!   Nodes are intraconnected inside a neuron with multiple connections (dendrites) as well as to other neurons (terminals)
+//!   Another way to do this would be to split the nodes up to dendrite nodes (only interconnected) and terminal nodes (interconnected)
+//!   There are some benifits with this, but makes the program and the model much more complex
 //! * NeuronState (S):
-//!     Models the sum of the activity of the nodes. It also functions as the state of the DNA
+//!   Models the sum of the activity of the nodes. It also functions as the state of the DNA
 //! * InterConnections:
-//!     Models connection between neurons (terminals)
+//!   Models connection between neurons (terminals)
 //! * IntraConnections:
-//!     Models dendrites and connections inside neurons
+//!   Models dendrites and connections inside neurons
+//! * Modulators (M):
+//!   A small vector shared by all neurons, such as a reward. Input to the neuron state and plasticity models, unless modulator_size is 0
 //!
 //! The components should fulfill the following:,
 //!
@@ -79,20 +81,24 @@
 //! Pushback is the other node rejecting the connecting node
 //!
 //! 6: Update network ports
-//! * Model: S & P -> ΔP
-//! * Description: TODO
+//! * Model: S & P(read) & P(write) -> ΔP(write) & ΔS
+//! * Description: Each network port (nexus) is attached to a neuron. The neuron reads what the other side has sent
+//!   and updates what it sends to the other side
 //!
 //! 7: Read from network ports
-//! * Model: S & P -> ΔC
-//! * Description: Will be 1 back in time!
+//! * Model: None, P(received) -> P(read)
+//! * Description: Will be 1 back in time! What is received during a step is read by the network in the next step,
+//!   then cleared
 //!
 //! 8: Read input ports
-//! * Model: TODO
-//! * Description: TODO
+//! * Model: S & N & I -> ΔS & ΔN
+//! * Description: Sensors write to the input I of the port, which is applied to the node and neuron the port is attached to.
+//!   Done at the start of the step.
 //!
 //! 9: Apply output ports
-//! * Model: TODO
-//! * Description: TODO
+//! * Model: S & N & O -> ΔO
+//! * Description: The output O of the port is updated from the node and neuron, and then read by the actuators.
+//!   Done at the end of the step.
 
 
 // TODO: List
@@ -103,34 +109,46 @@
 
 use std::default::Default;
 
+use serde::{Deserialize, Serialize};
+
 // Modules
 pub mod gpu;
 pub mod cpu;
 // TODO: Add more? TPU? FPGA?
 
 pub mod visualization;
+pub mod evolution;
+pub mod environments;
+pub mod settings;
+pub mod topology;
 
 
 use crate::cpu::interface::{InterConnection, IntraConnection};
+use crate::topology::SearchTopology;
 
 // NOTE: Is this needed? -> #[repr(C)]
 
-/// Length of array MUST be divisible by 4
+/// Length of array MUST be divisible by 4. Use validate() to check all constraints
 /// Settings for the neurons.
 /// Any change of the size makes it incompatible with other genomes
 /// Any change in connections is compatible, but "might" be behaving weird
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct GuardianSettings {
     // Model
     pub node_size: usize,
     pub neuron_state_size: usize,
     pub n_nodes_per_neuron: usize,
     pub n_intraconnections_per_node: usize,
+    #[serde(default)]
+    pub modulator_size: usize,  // 0 disables the modulators, also the value if missing
 
     // Searching
     pub n_interconnected_nodes_search: usize,  // TODO: Better name, -offset..offset
     pub n_interconnected_neuron_search: usize,
     pub n_intraconnected_nodes_search: usize,
+    #[serde(default)]
+    pub topology: SearchTopology,  // Where the interconnections search, see topology
 
     // Plasticity
     pub interconnection_max_connection_time: usize,
@@ -142,17 +160,24 @@ pub struct GuardianSettings {
     pub nexus_size: usize,
 
     // IO
-    // TODO: Add io sizes? Dynamic?
+    pub io_size: usize,  // Number of values per io port
 
     // Genome
-    hidden_sizes: Vec<usize>
+    pub hidden_sizes: Vec<usize>
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct NetworkSettings {
     pub n_neurons: usize,
     pub n_io_ports: usize,
     pub n_network_ports: usize,
+
+    // Processing
+    /// Same seed gives bit-identical states, independent of the number of threads. Costs an extra copy of the
+    /// interconnections per step
+    #[serde(default)]
+    pub deterministic: bool,
 }
 
 impl Default for NetworkSettings {
@@ -161,6 +186,7 @@ impl Default for NetworkSettings {
             n_neurons: 64,
             n_io_ports: 0,
             n_network_ports: 0,
+            deterministic: false,
         }
     }
 }
@@ -171,6 +197,7 @@ impl NetworkSettings {
             n_neurons: 16,
             n_io_ports: 0,
             n_network_ports: 0,
+            deterministic: false,
         }
     }
 }
@@ -182,14 +209,17 @@ impl Default for GuardianSettings {
             neuron_state_size: 2048,
             n_nodes_per_neuron: 16,
             n_intraconnections_per_node: 4,
+            modulator_size: 4,
             n_interconnected_nodes_search: 4,
             n_interconnected_neuron_search: 1,
             n_intraconnected_nodes_search: 1,
+            topology: SearchTopology::Ring,
             interconnection_max_connection_time: 8,
             intraconnection_max_connection_time: 8,
             interconnection_max_search_time: 8,
             intraconnection_max_search_time: 8,
             nexus_size: 16,
+            io_size: 16,
             hidden_sizes: vec![64, 64]
         }
     }
@@ -202,14 +232,17 @@ impl GuardianSettings {
             neuron_state_size: 32,
             n_nodes_per_neuron: 8,
             n_intraconnections_per_node: 4,
+            modulator_size: 4,
             n_interconnected_nodes_search: 4,
             n_interconnected_neuron_search: 1,
             n_intraconnected_nodes_search: 1,
+            topology: SearchTopology::Ring,
             interconnection_max_connection_time: 8,
             intraconnection_max_connection_time: 8,
             interconnection_max_search_time: 8,
             intraconnection_max_search_time: 8,
             nexus_size: 16,
+            io_size: 4,
             hidden_sizes: vec![64, 64]
         }
     }
@@ -237,8 +270,13 @@ impl GuardianSettings {
 pub fn get_network_size(g_settings: &GuardianSettings, n_settings: &NetworkSettings) {
     let mut size = 0;
     size += g_settings.bytes_per_neuron() * n_settings.n_neurons;
+    let io_ports = n_settings.n_io_ports * (2 * g_settings.io_size + std::mem::size_of::<usize>());  // input + output + node index
+    println!("Size for io ports: {:?}", humansize::format_size(io_ports, humansize::DECIMAL));
+    size += io_ports;
+    let network_ports = n_settings.n_network_ports * (3 * g_settings.nexus_size + std::mem::size_of::<usize>());  // read + write + received + neuron index
+    println!("Size for network ports: {:?}", humansize::format_size(network_ports, humansize::DECIMAL));
+    size += network_ports;
     println!("Size of network: {:?}", humansize::format_size(size, humansize::DECIMAL));
-    // TODO: Add ports!
 }
 
 pub fn get_genome_size() {
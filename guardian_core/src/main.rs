@@ -1,58 +1,364 @@
-use tokio::main;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{ensure, Context, Result};
+use clap::{Args, Parser, Subcommand};
 use tracing::{debug, info, Level};
 use tracing_subscriber::FmtSubscriber;
-use rayon::ThreadPoolBuilder;
-use rand::SeedableRng;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use indicatif::{ProgressBar, ProgressStyle};
 
-use glib::cpu::interface::{Genome, State, Network};
+use glib::cpu::checkpoint::read_header;
+use glib::cpu::compatibility::{adapt_genome, check_compatibility};
+use glib::cpu::interface::{Genome, Network};
 use glib::cpu::process::update;
-use glib::{get_network_size, GuardianSettings, NetworkSettings};
+use glib::cpu::stats::Stats;
+use glib::evolution::{EvolutionSettings, Fitness, Population};
+use glib::get_network_size;
+use glib::settings::Settings;
 use glib::visualization;
+use glib::visualization::export::TopologyGraph;
+use glib::visualization::recorder::{Recorder, RecorderSettings, Recording};
 
-#[main]
-async fn main() {
-    let subscriber = FmtSubscriber::builder()
-    .with_max_level(Level::DEBUG)
-    .finish();
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
-    info!("RUNNING");
-    //let _ = test_webgpu().await;
-
-    let thread_count = std::thread::available_parallelism().unwrap().get();
-    let pool = ThreadPoolBuilder::new().num_threads(thread_count).build().unwrap();
-    let g_settings = GuardianSettings::downlevel_default();
-    let n_settings = NetworkSettings::downlevel_default();
-    get_network_size(&g_settings, &n_settings);
-    let rng = rand::rngs::StdRng::seed_from_u64(1);
-    let genome = Genome::new(&g_settings, Some(rng.clone()));
-    let mut state = State::new(&g_settings, &n_settings);
-    debug!("Randomizing");
-    state.randomize(&g_settings, &n_settings, Some(rng));
-    debug!("Randomizing done");
-    //debug!("\n{:#?}", state.nodes);
-    //debug!("\n{:#?}", state.neuron_states);
-    //debug!("\n{:#?}", state.inter_connections);
-    //debug!("\n{:#?}", state.inter_connections_flags);
-    //debug!("\n{:#?}", state.intra_connections);
-    let mut network = Network {
-        state,
-        genome,
-        g_settings: g_settings.clone(),
-        n_settings: n_settings.clone(),
-    };
+#[derive(Parser, Debug)]
+#[command(version, about = "Genetic Unsupervised Adaptive Recurrent Distributed Interconnected Artificial Neurons")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    /// trace, debug, info, warn or error
+    #[arg(long, global = true, default_value = "info")]
+    log_level: Level,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Runs a network and saves it as a checkpoint
+    Run(Common),
+    /// Evolves genomes and saves the best one as a checkpoint. Starts from the genome of --checkpoint-in or --genome if given
+    Evolve {
+        #[command(flatten)]
+        common: Common,
+        #[arg(long, default_value_t = 8)]
+        generations: usize,
+        #[arg(long, default_value_t = EvolutionSettings::default().population_size)]
+        population_size: usize,
+    },
+    /// Prints the settings and stats of a checkpoint
+    Inspect {
+        checkpoint: PathBuf,
+        /// Also export the topology. The format is decided by the extension (.graphml, .gexf or .dot)
+        #[arg(long)]
+        export: Option<PathBuf>,
+    },
+    /// Runs a network and records the connections to the recording dir in the output dir
+    Visualize {
+        #[command(flatten)]
+        common: Common,
+        #[command(flatten)]
+        recorder: RecorderArgs,
+    },
+    /// Measures the time per step
+    Bench(Common),
+    /// Saves the genome of a checkpoint on its own. JSON if the output ends with .json, otherwise binary
+    ExportGenome {
+        checkpoint: PathBuf,
+        output: PathBuf,
+    },
+}
 
-    let mut state_history = vec![network.state.clone()];
-    let total = 64;
-    let pb = ProgressBar::new(total);
+#[derive(Args, Debug)]
+struct RecorderArgs {
+    /// Record every n:th step
+    #[arg(long, default_value_t = 1)]
+    interval: usize,
+    /// Comma separated neuron indices. All neurons if not given
+    #[arg(long, value_delimiter = ',')]
+    neurons: Option<Vec<usize>>,
+    /// Also record nodes and neuron states
+    #[arg(long)]
+    states: bool,
+    /// Also write data.js and a script per step for graph.html, which loads the steps when they are shown
+    #[arg(long, conflicts_with = "neurons")]
+    html: bool,
+}
+
+#[derive(Args, Debug)]
+struct Common {
+    /// Settings file (.toml or .json). The preset is used if not given. With --checkpoint-in, the network settings
+    /// must be the same as in the checkpoint
+    #[arg(long)]
+    settings: Option<PathBuf>,
+    #[arg(long, default_value = "downlevel", conflicts_with = "checkpoint_in")]
+    preset: String,
+    #[arg(long, default_value_t = 1, conflicts_with = "checkpoint_in")]
+    seed: u64,
+    #[arg(long, default_value_t = 64)]
+    steps: usize,
+    /// Defaults to the available parallelism
+    #[arg(long)]
+    threads: Option<usize>,
+    /// Continue from a checkpoint instead of a new network
+    #[arg(long)]
+    checkpoint_in: Option<PathBuf>,
+    /// Use a saved genome instead of a random one
+    #[arg(long, conflicts_with = "checkpoint_in")]
+    genome: Option<PathBuf>,
+    /// Pad or truncate the genome if it was built for other sizes
+    #[arg(long, requires = "genome")]
+    adapt_genome: bool,
+    /// Defaults to network.grdn in the output dir
+    #[arg(long)]
+    checkpoint_out: Option<PathBuf>,
+    #[arg(long, default_value = ".")]
+    output_dir: PathBuf,
+}
+
+impl Common {
+    fn settings(&self) -> Result<Settings> {
+        match &self.settings {
+            Some(path) => Settings::load(path),
+            None => {
+                let settings = Settings::preset(&self.preset)?;
+                settings.validate()?;
+                Ok(settings)
+            }
+        }
+    }
+
+    /// From the checkpoint if given, otherwise a new one from the settings and seed
+    fn network(&self) -> Result<Network> {
+        if let Some(path) = &self.checkpoint_in {
+            let settings = match &self.settings {
+                Some(path) => Some(Settings::load(path)?),
+                None => None
+            };
+            info!("Loading checkpoint {path:?}");
+            let network = Network::load_checkpoint(path, settings.as_ref().map(|settings| &settings.g_settings))?;
+            if let Some(settings) = &settings {
+                ensure!(
+                    settings.n_settings == network.n_settings,
+                    "The network settings {:?} are not the same as in checkpoint {path:?}: {:?}",
+                    settings.n_settings, network.n_settings
+                );
+            }
+            return Ok(network);
+        }
+        let settings = self.settings()?;
+        let mut network = Network::new(&settings.g_settings, &settings.n_settings, self.seed);
+        if let Some(path) = &self.genome {
+            info!("Loading genome {path:?}");
+            let (genome, _) = Genome::load(path)?;
+            network.genome = if self.adapt_genome {
+                adapt_genome(&genome, &settings.g_settings).with_context(|| format!("Unable to adapt genome {path:?}"))?
+            } else {
+                check_compatibility(&genome, &settings.g_settings).with_context(|| format!("Genome {path:?} does not fit the settings"))?;
+                genome
+            };
+        }
+        Ok(network)
+    }
+
+    fn pool(&self) -> Result<ThreadPool> {
+        let thread_count = match self.threads {
+            Some(thread_count) => thread_count,
+            None => std::thread::available_parallelism()?.get()
+        };
+        ensure!(thread_count > 0, "At least one thread is needed");
+        Ok(ThreadPoolBuilder::new().num_threads(thread_count).build()?)
+    }
+
+    fn checkpoint_out(&self) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.output_dir)
+            .with_context(|| format!("Unable to create output dir {:?}", self.output_dir))?;
+        Ok(self.checkpoint_out.clone().unwrap_or_else(|| self.output_dir.join("network.grdn")))
+    }
+}
+
+fn progress_bar(total: usize) -> ProgressBar {
+    let pb = ProgressBar::new(total as u64);
     pb.set_style(
         ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{bar}] {per_sec} {pos}/{len} eta ({eta_precise})").unwrap()
     );
-    for _ in 0..total {
-        update(&mut network, &pool);
-        state_history.push(network.state.clone());
+    pb
+}
+
+/// Runs the steps. The callback is called after every step
+fn run_steps<F: FnMut(&Network)>(network: &mut Network, pool: &ThreadPool, steps: usize, mut callback: F) -> Option<Stats> {
+    let pb = progress_bar(steps);
+    let mut stats = None;
+    for _ in 0..steps {
+        stats = Some(update(network, pool));
+        callback(network);
         pb.inc(1);
     }
     pb.finish();
-    visualization::graph::visualize_network(state_history, &g_settings, &n_settings);
-}
\ No newline at end of file
+    stats
+}
+
+fn run(common: &Common) -> Result<()> {
+    let mut network = common.network()?;
+    let pool = common.pool()?;
+    get_network_size(&network.g_settings, &network.n_settings);
+    let stats = run_steps(&mut network, &pool, common.steps, |_| {});
+    info!("Stats of the last step: {:#?}", stats);
+    let path = common.checkpoint_out()?;
+    network.save_checkpoint(&path)?;
+    info!("Saved checkpoint {path:?}");
+    Ok(())
+}
+
+/// Rewards networks that are well connected at the end of their lifetime
+#[derive(Default)]
+struct ConnectionFitness {
+    last_stats: Option<Stats>,
+}
+
+impl Fitness for ConnectionFitness {
+    fn reset(&mut self, _network: &mut Network) -> Result<()> {
+        self.last_stats = None;
+        Ok(())
+    }
+
+    fn after_step(&mut self, _step: usize, _network: &mut Network, stats: &Stats) {
+        self.last_stats = Some(stats.clone());
+    }
+
+    fn score(&mut self, network: &Network) -> f32 {
+        match &self.last_stats {
+            Some(stats) => stats.mutual_connections as f32 / (network.n_settings.n_neurons * network.g_settings.n_nodes_per_neuron) as f32,
+            None => 0.0
+        }
+    }
+}
+
+/// Starts from the genome of checkpoint_in or genome if given, otherwise from random genomes
+fn evolve(common: &Common, generations: usize, population_size: usize) -> Result<()> {
+    let (settings, genome) = if common.checkpoint_in.is_some() || common.genome.is_some() {
+        let network = common.network()?;
+        (Settings { g_settings: network.g_settings, n_settings: network.n_settings }, Some(network.genome))
+    } else {
+        (common.settings()?, None)
+    };
+    let pool = common.pool()?;
+    let e_settings = EvolutionSettings {
+        population_size,
+        n_steps: common.steps,
+        ..Default::default()
+    };
+    let mut population = Population::new(&settings.g_settings, &settings.n_settings, &e_settings, common.seed)?;
+    if let Some(genome) = &genome {
+        population.seed_with(genome);
+    }
+    let mut fitness = ConnectionFitness::default();
+    for _ in 0..generations {
+        population.step(&mut fitness, &pool)?;
+    }
+    population.evaluate(&mut fitness, &pool)?;
+    let best = population.best().context("The population is empty")?;
+    info!("Best fitness {:?}", best.fitness);
+
+    let mut network = Network::new(&settings.g_settings, &settings.n_settings, common.seed);
+    network.genome = best.genome.clone();
+    let path = common.checkpoint_out()?;
+    network.save_checkpoint(&path)?;
+    info!("Saved best genome as checkpoint {path:?}");
+    Ok(())
+}
+
+fn inspect(path: &Path, export: Option<&Path>) -> Result<()> {
+    let file = std::fs::File::open(path).with_context(|| format!("Unable to open checkpoint {path:?}"))?;
+    let header = read_header(&mut std::io::BufReader::new(file))?;
+    println!("Checkpoint version {} (written by {})", header.version, header.crate_version);
+    println!("{}", toml::to_string_pretty(&Settings { g_settings: header.g_settings, n_settings: header.n_settings })?);
+
+    let network = Network::load_checkpoint(path, None)?;
+    get_network_size(&network.g_settings, &network.n_settings);
+    let mut stats = Stats::new();
+    stats.aggregate(&network.state, &network.g_settings);
+    println!("Mutual connections: {}", stats.mutual_connections);
+    println!("Mean net force: {}", stats.mean_net_force);
+    println!("Failed nodes: {}", stats.failed_nodes);
+    println!("Failed intra nodes: {}", stats.failed_intra_nodes);
+
+    if let Some(export) = export {
+        TopologyGraph::from_state(&network.state, &network.g_settings).save(export)?;
+        info!("Exported topology to {export:?}");
+    }
+    Ok(())
+}
+
+fn export_genome(checkpoint: &Path, output: &Path) -> Result<()> {
+    let network = Network::load_checkpoint(checkpoint, None)?;
+    network.genome.save(output, &network.g_settings)?;
+    info!("Saved genome to {output:?}");
+    Ok(())
+}
+
+fn visualize(common: &Common, recorder_args: &RecorderArgs) -> Result<()> {
+    let mut network = common.network()?;
+    let pool = common.pool()?;
+    let r_settings = RecorderSettings {
+        interval: recorder_args.interval,
+        neurons: recorder_args.neurons.clone(),
+        include_states: recorder_args.states,
+    };
+    let path = common.output_dir.join("recording");
+    let mut recorder = Recorder::create(&path, &r_settings, &network.g_settings, &network.n_settings)?;
+    recorder.record(0, &network.state)?;
+
+    let mut step = 0;
+    let mut result = Ok(());
+    run_steps(&mut network, &pool, common.steps, |network| {
+        step += 1;
+        if result.is_ok() {
+            result = recorder.record(step, &network.state).map(|_| ());
+        }
+    });
+    result?;
+    info!("Recorded {} steps to {path:?}", recorder.n_recorded());
+    recorder.finish()?;
+
+    if recorder_args.html {
+        visualization::graph::visualize_recording(&Recording::open(&path)?, &common.output_dir)?;
+        info!("Wrote data.js and steps to {:?}", common.output_dir);
+    }
+    Ok(())
+}
+
+fn bench(common: &Common) -> Result<()> {
+    let mut network = common.network()?;
+    let pool = common.pool()?;
+    let now = Instant::now();
+    for step in 0..common.steps {
+        let step_start = Instant::now();
+        update(&mut network, &pool);
+        debug!("Step {step} took {:?}", step_start.elapsed());
+    }
+    let elapsed = now.elapsed();
+    info!(
+        "{} steps with {} threads took {:?}, {:?} per step",
+        common.steps,
+        pool.current_num_threads(),
+        elapsed,
+        elapsed / common.steps.max(1) as u32
+    );
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let subscriber = FmtSubscriber::builder()
+    .with_max_level(cli.log_level)
+    .finish();
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+
+    match &cli.command {
+        Command::Run(common) => run(common),
+        Command::Evolve { common, generations, population_size } => evolve(common, *generations, *population_size),
+        Command::Inspect { checkpoint, export } => inspect(checkpoint, export.as_deref()),
+        Command::Visualize { common, recorder } => visualize(common, recorder),
+        Command::Bench(common) => bench(common),
+        Command::ExportGenome { checkpoint, output } => export_genome(checkpoint, output),
+    }
+}
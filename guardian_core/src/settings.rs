@@ -0,0 +1,297 @@
+//! Loading, validating and presets of the settings
+//!
+//! A settings file (TOML or JSON) can start from a named preset and override single values:
+//!
+//! ```toml
+//! preset = "downlevel"
+//!
+//! [g_settings]
+//! hidden_sizes = [32]
+//!
+//! [n_settings]
+//! n_neurons = 64
+//! deterministic = true
+//! ```
+
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{GuardianSettings, NetworkSettings};
+
+pub const PRESETS: [&str; 3] = ["default", "downlevel", "tiny"];
+
+/// Everything needed to create a network. Unknown keys are refused, so a misspelled key is not silently ignored
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub g_settings: GuardianSettings,
+    pub n_settings: NetworkSettings,
+}
+
+/// Every constraint the settings violate
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidSettings(pub Vec<String>);
+
+impl fmt::Display for InvalidSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid settings:")?;
+        for violation in self.0.iter() {
+            writeln!(f, "* {violation}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InvalidSettings {}
+
+impl Settings {
+    pub fn preset(name: &str) -> Result<Self> {
+        let settings = match name {
+            "default" => Self {
+                g_settings: GuardianSettings::default(),
+                n_settings: NetworkSettings::default(),
+            },
+            "downlevel" => Self {
+                g_settings: GuardianSettings::downlevel_default(),
+                n_settings: NetworkSettings::downlevel_default(),
+            },
+            // Small enough for tests
+            "tiny" => {
+                let mut g_settings = GuardianSettings::downlevel_default();
+                g_settings.hidden_sizes = vec![8];
+                let mut n_settings = NetworkSettings::downlevel_default();
+                n_settings.n_neurons = 4;
+                Self { g_settings, n_settings }
+            },
+            _ => bail!("Unknown preset {name:?}, expected one of {PRESETS:?}"),
+        };
+        Ok(settings)
+    }
+
+    /// TOML or JSON, decided by the extension. The settings are validated
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("Unable to read settings {path:?}"))?;
+        let settings = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Self::from_toml(&text),
+            Some("json") => Self::from_json(&text),
+            _ => bail!("Settings {path:?} should be .toml or .json"),
+        }.with_context(|| format!("Unable to load settings {path:?}"))?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let text = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => toml::to_string_pretty(self)?,
+            Some("json") => serde_json::to_string_pretty(self)?,
+            _ => bail!("Settings {path:?} should be .toml or .json"),
+        };
+        std::fs::write(path, text).with_context(|| format!("Unable to write settings {path:?}"))
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let value: Value = toml::from_str(text)?;
+        Self::from_value(value)
+    }
+
+    pub fn from_json(text: &str) -> Result<Self> {
+        let value: Value = serde_json::from_str(text)?;
+        Self::from_value(value)
+    }
+
+    /// Values missing in the file are taken from the preset, "default" if none is given
+    fn from_value(mut value: Value) -> Result<Self> {
+        let preset = match value.as_object_mut().and_then(|object| object.remove("preset")) {
+            Some(Value::String(name)) => name,
+            Some(other) => bail!("Preset should be a name, got {other}"),
+            None => "default".to_string(),
+        };
+        let mut merged = serde_json::to_value(Self::preset(&preset)?)?;
+        merge(&mut merged, value);
+        Ok(serde_json::from_value(merged)?)
+    }
+
+    pub fn validate(&self) -> Result<(), InvalidSettings> {
+        let mut violations = self.g_settings.violations();
+        violations.extend(self.n_settings.violations(&self.g_settings));
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidSettings(violations))
+        }
+    }
+}
+
+impl GuardianSettings {
+    pub fn validate(&self) -> Result<(), InvalidSettings> {
+        let violations = self.violations();
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidSettings(violations))
+        }
+    }
+
+    fn violations(&self) -> Vec<String> {
+        let mut violations = vec![];
+
+        // Arrays are sent to the GPU, which needs 4 byte alignment
+        let sizes = [
+            ("node_size", self.node_size),
+            ("neuron_state_size", self.neuron_state_size),
+            ("nexus_size", self.nexus_size),
+            ("io_size", self.io_size),
+        ];
+        for (name, size) in sizes {
+            if size == 0 || !size.is_multiple_of(4) {
+                violations.push(format!("{name} is {size}, must be above 0 and divisible by 4"));
+            }
+        }
+        if !self.modulator_size.is_multiple_of(4) {
+            violations.push(format!("modulator_size is {}, must be divisible by 4, or 0 to disable them", self.modulator_size));
+        }
+
+        // IntraConnection stores the local index as u16, opposite_index needs an even number
+        if self.n_nodes_per_neuron < 2 || !self.n_nodes_per_neuron.is_multiple_of(2) {
+            violations.push(format!("n_nodes_per_neuron is {}, must be even and at least 2", self.n_nodes_per_neuron));
+        }
+        if self.n_nodes_per_neuron > u16::MAX as usize + 1 {
+            violations.push(format!("n_nodes_per_neuron is {}, must be at most {}", self.n_nodes_per_neuron, u16::MAX as usize + 1));
+        }
+        if self.n_intraconnections_per_node == 0 || self.n_intraconnections_per_node >= self.n_nodes_per_neuron {
+            violations.push(format!(
+                "n_intraconnections_per_node is {}, must be between 1 and n_nodes_per_neuron - 1",
+                self.n_intraconnections_per_node
+            ));
+        }
+        if self.n_intraconnected_nodes_search == 0 {
+            violations.push("n_intraconnected_nodes_search is 0, the intraconnections can never move".to_string());
+        }
+
+        // The counters are u8, where 0xFE and 0xFF are used as flags
+        let times = [
+            ("interconnection_max_connection_time", self.interconnection_max_connection_time),
+            ("intraconnection_max_connection_time", self.intraconnection_max_connection_time),
+            ("interconnection_max_search_time", self.interconnection_max_search_time),
+            ("intraconnection_max_search_time", self.intraconnection_max_search_time),
+        ];
+        for (name, time) in times {
+            if !(2..0xFE).contains(&time) {
+                violations.push(format!("{name} is {time}, must be between 2 and {}", 0xFE - 1));
+            }
+        }
+
+        if self.hidden_sizes.is_empty() {
+            violations.push("hidden_sizes is empty, at least one hidden layer is needed".to_string());
+        } else if self.hidden_sizes.contains(&0) {
+            violations.push(format!("hidden_sizes is {:?}, all sizes must be above 0", self.hidden_sizes));
+        }
+        violations
+    }
+}
+
+impl NetworkSettings {
+    pub fn validate(&self, g_settings: &GuardianSettings) -> Result<(), InvalidSettings> {
+        let violations = self.violations(g_settings);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidSettings(violations))
+        }
+    }
+
+    fn violations(&self, g_settings: &GuardianSettings) -> Vec<String> {
+        let mut violations = vec![];
+        if self.n_neurons == 0 {
+            violations.push("n_neurons is 0".to_string());
+        }
+
+        // InterConnection stores the global index as u32
+        let n_nodes_total = self.n_neurons * g_settings.n_nodes_per_neuron;
+        if n_nodes_total > u32::MAX as usize + 1 {
+            violations.push(format!("The network has {n_nodes_total} nodes, must be at most {}", u32::MAX as usize + 1));
+        }
+        if self.n_io_ports > n_nodes_total {
+            violations.push(format!("n_io_ports is {}, more than the {n_nodes_total} nodes", self.n_io_ports));
+        }
+        if self.n_network_ports > self.n_neurons {
+            violations.push(format!("n_network_ports is {}, more than the {} neurons", self.n_network_ports, self.n_neurons));
+        }
+        violations.extend(g_settings.topology.violations(self.n_neurons));
+        violations
+    }
+}
+
+/// Recursively overwrites base with the values in other
+fn merge(base: &mut Value, other: Value) {
+    match (base, other) {
+        (Value::Object(base), Value::Object(other)) => {
+            for (key, value) in other {
+                merge(base.entry(key).or_insert(Value::Null), value);
+            }
+        },
+        (base, other) => *base = other,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::topology::SearchTopology;
+    use super::*;
+
+    #[test]
+    pub fn test_settings() {
+        for name in PRESETS {
+            Settings::preset(name).unwrap().validate().unwrap();
+        }
+
+        let text = r#"
+            preset = "downlevel"
+
+            [g_settings]
+            hidden_sizes = [32]
+
+            [n_settings]
+            n_neurons = 64
+        "#;
+        let settings = Settings::from_toml(text).unwrap();
+        assert_eq!(settings.g_settings.hidden_sizes, vec![32]);
+        assert_eq!(settings.g_settings.node_size, GuardianSettings::downlevel_default().node_size);
+        assert_eq!(settings.n_settings.n_neurons, 64);
+        let json = serde_json::to_string(&settings).unwrap();
+        assert_eq!(Settings::from_json(&json).unwrap(), settings);
+
+        let text = r#"
+            [g_settings.topology]
+            kind = "grid2d"
+            width = 8
+            height = 8
+        "#;
+        let settings = Settings::from_toml(text).unwrap();
+        assert_eq!(settings.g_settings.topology, SearchTopology::Grid2d { width: 8, height: 8 });
+        settings.validate().unwrap();
+        let toml = toml::to_string_pretty(&settings).unwrap();
+        assert_eq!(Settings::from_toml(&toml).unwrap(), settings);
+
+        // Every violation is reported
+        let mut settings = Settings::preset("tiny").unwrap();
+        settings.g_settings.node_size = 6;
+        settings.g_settings.n_nodes_per_neuron = 7;
+        settings.n_settings.n_neurons = 0;
+        settings.g_settings.topology = SearchTopology::Grid2d { width: 2, height: 2 };
+        let violations = settings.validate().unwrap_err().0;
+        assert_eq!(violations.len(), 4, "{violations:?}");
+
+        // Misspelled keys are refused
+        let error = Settings::from_toml("[n_settings]\nn_nuerons = 8").unwrap_err();
+        assert!(error.to_string().contains("n_nuerons"), "{error}");
+        assert!(Settings::from_toml("[g_settings]\nnode_sise = 8").is_err());
+        assert!(Settings::from_toml("presets = \"tiny\"").is_err());
+    }
+}
@@ -0,0 +1,252 @@
+//! Which neurons are close to each other when the interconnections search
+//!
+//! The topology is selected with GuardianSettings::topology. The search of interconnection plasticity starts at
+//! a neuron and looks at its neighbours, where n_interconnected_neuron_search is the distance in steps:
+//!
+//! * Ring: -n..=n neurons around, wrapping at the ends
+//! * Grid2d/Grid3d: Neurons laid out row by row, every neuron within n steps along each axis, wrapping at the edges
+//! * SmallWorld: The ring, plus random long-range links drawn from a seed. Links go both ways
+//! * Coordinates: Every neuron within the radius of the neuron, n is not used
+//!
+//! Only the neurons are placed, the nodes of a neuron are always searched as a ring.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::cpu::wrap_index;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SearchTopology {
+    #[default]
+    Ring,
+    Grid2d { width: usize, height: usize },
+    Grid3d { width: usize, height: usize, depth: usize },
+    SmallWorld { n_links: usize, seed: u64 },
+    Coordinates { coordinates: Vec<[f32; 3]>, radius: f32 },
+}
+
+impl SearchTopology {
+    pub(crate) fn violations(&self, n_neurons: usize) -> Vec<String> {
+        let mut violations = vec![];
+        match self {
+            SearchTopology::Ring => {},
+            SearchTopology::Grid2d { width, height } => {
+                if width * height != n_neurons {
+                    violations.push(format!("Grid2d is {width}x{height}, must have n_neurons ({n_neurons}) cells"));
+                }
+            },
+            SearchTopology::Grid3d { width, height, depth } => {
+                if width * height * depth != n_neurons {
+                    violations.push(format!("Grid3d is {width}x{height}x{depth}, must have n_neurons ({n_neurons}) cells"));
+                }
+            },
+            SearchTopology::SmallWorld { n_links, .. } => {
+                if *n_links >= n_neurons {
+                    violations.push(format!("SmallWorld has {n_links} links per neuron, must be less than n_neurons ({n_neurons})"));
+                }
+            },
+            SearchTopology::Coordinates { coordinates, radius } => {
+                if coordinates.len() != n_neurons {
+                    violations.push(format!("Coordinates has {} coordinates, must have one per neuron ({n_neurons})", coordinates.len()));
+                }
+                if radius.is_nan() || *radius <= 0.0 {
+                    violations.push(format!("Coordinates has radius {radius}, must be above 0"));
+                }
+            },
+        }
+        violations
+    }
+}
+
+/// The neighbours of every neuron, including itself
+/// Created once and kept in a NeighbourhoodCache, since SmallWorld and Coordinates are too expensive to find per search
+pub struct Neighbourhood {
+    neighbours: Vec<Vec<usize>>,
+}
+
+impl Neighbourhood {
+    pub fn new(topology: &SearchTopology, n_neurons: usize, distance: usize) -> Self {
+        let distance = distance as isize;
+        let offsets: Vec<isize> = (-distance..=distance).collect();
+        let mut neighbours: Vec<Vec<usize>> = match topology {
+            SearchTopology::Ring | SearchTopology::SmallWorld { .. } => (0..n_neurons)
+                .map(|neuron_index| offsets.iter().map(|offset| wrap_index(neuron_index, *offset, n_neurons)).collect())
+                .collect(),
+            SearchTopology::Grid2d { width, height } => grid_neighbours(&[*width, *height], &offsets),
+            SearchTopology::Grid3d { width, height, depth } => grid_neighbours(&[*width, *height, *depth], &offsets),
+            SearchTopology::Coordinates { coordinates, radius } => coordinate_neighbours(coordinates, *radius),
+        };
+
+        if let SearchTopology::SmallWorld { n_links, seed } = topology {
+            let mut rng = StdRng::seed_from_u64(*seed);
+            for neuron_index in 0..n_neurons {
+                for _ in 0..*n_links {
+                    let other = rng.gen_range(0..n_neurons);
+                    neighbours[neuron_index].push(other);
+                    neighbours[other].push(neuron_index);
+                }
+            }
+        }
+
+        // Small rings and grids wrap onto the same neuron. Keeps the first, so the order is the same as the offsets
+        for neuron_neighbours in neighbours.iter_mut() {
+            let mut seen = HashSet::with_capacity(neuron_neighbours.len());
+            neuron_neighbours.retain(|neuron_index| seen.insert(*neuron_index));
+        }
+        Self { neighbours }
+    }
+
+    pub fn neighbours(&self, neuron_index: usize) -> &[usize] {
+        &self.neighbours[neuron_index]
+    }
+}
+
+/// What a Neighbourhood is built from
+#[derive(Clone, PartialEq)]
+struct NeighbourhoodKey {
+    topology: SearchTopology,
+    n_neurons: usize,
+    distance: usize,
+}
+
+/// Keeps the Neighbourhood between steps. It is built again only when the topology, the number of neurons or the
+/// distance changed, such as after adding or removing neurons
+#[derive(Default)]
+pub struct NeighbourhoodCache(Mutex<Option<(NeighbourhoodKey, Arc<Neighbourhood>)>>);
+
+impl NeighbourhoodCache {
+    pub fn get(&self, topology: &SearchTopology, n_neurons: usize, distance: usize) -> Arc<Neighbourhood> {
+        let mut cached = self.0.lock().unwrap();
+        match cached.as_ref() {
+            Some((key, neighbourhood)) if key.n_neurons == n_neurons && key.distance == distance && key.topology == *topology => {
+                neighbourhood.clone()
+            },
+            _ => {
+                let neighbourhood = Arc::new(Neighbourhood::new(topology, n_neurons, distance));
+                let key = NeighbourhoodKey { topology: topology.clone(), n_neurons, distance };
+                *cached = Some((key, neighbourhood.clone()));
+                neighbourhood
+            }
+        }
+    }
+}
+
+/// A clone shares the built Neighbourhood, it is rebuilt if the settings of the clone change
+impl Clone for NeighbourhoodCache {
+    fn clone(&self) -> Self {
+        Self(Mutex::new(self.0.lock().unwrap().clone()))
+    }
+}
+
+/// Row-major, the first dimension is the fastest
+fn grid_neighbours(dimensions: &[usize], offsets: &[isize]) -> Vec<Vec<usize>> {
+    let n_neurons = dimensions.iter().product();
+    (0..n_neurons)
+        .map(|neuron_index| {
+            let mut neighbours = vec![0];
+            let mut stride = 1;
+            for dimension in dimensions {
+                let position = (neuron_index / stride) % dimension;
+                neighbours = neighbours
+                    .iter()
+                    .flat_map(|index| offsets.iter().map(move |offset| index + wrap_index(position, *offset, *dimension) * stride))
+                    .collect();
+                stride *= dimension;
+            }
+            neighbours
+        })
+        .collect()
+}
+
+/// Neurons are put in cells as large as the radius, so only the 27 cells around are compared
+fn coordinate_neighbours(coordinates: &[[f32; 3]], radius: f32) -> Vec<Vec<usize>> {
+    let cell = |coordinate: &[f32; 3]| coordinate.map(|value| (value / radius).floor() as i64);
+    let mut cells: HashMap<[i64; 3], Vec<usize>> = HashMap::new();
+    for (neuron_index, coordinate) in coordinates.iter().enumerate() {
+        cells.entry(cell(coordinate)).or_default().push(neuron_index);
+    }
+
+    let radius_squared = radius * radius;
+    coordinates
+        .iter()
+        .enumerate()
+        .map(|(neuron_index, coordinate)| {
+            let [x, y, z] = cell(coordinate);
+            let mut neighbours = vec![neuron_index];
+            for dx in -1..=1 {
+                for dy in -1..=1 {
+                    for dz in -1..=1 {
+                        let Some(others) = cells.get(&[x + dx, y + dy, z + dz]) else { continue };
+                        for other in others {
+                            let distance_squared: f32 = coordinate
+                                .iter()
+                                .zip(coordinates[*other].iter())
+                                .map(|(a, b)| (a - b) * (a - b))
+                                .sum();
+                            if *other != neuron_index && distance_squared <= radius_squared {
+                                neighbours.push(*other);
+                            }
+                        }
+                    }
+                }
+            }
+            neighbours
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_neighbourhood() {
+        let ring = Neighbourhood::new(&SearchTopology::Ring, 8, 1);
+        assert_eq!(ring.neighbours(0), &[7, 0, 1]);
+
+        // 4x3 grid, neuron 5 is at (1, 1)
+        let grid = Neighbourhood::new(&SearchTopology::Grid2d { width: 4, height: 3 }, 12, 1);
+        let mut neighbours = grid.neighbours(5).to_vec();
+        neighbours.sort();
+        assert_eq!(neighbours, vec![0, 1, 2, 4, 5, 6, 8, 9, 10]);
+        // Corner wraps around
+        assert!(grid.neighbours(0).contains(&11));
+
+        let grid = Neighbourhood::new(&SearchTopology::Grid3d { width: 2, height: 2, depth: 2 }, 8, 1);
+        assert_eq!(grid.neighbours(0).len(), 8);
+
+        let small_world = Neighbourhood::new(&SearchTopology::SmallWorld { n_links: 2, seed: 1 }, 64, 1);
+        assert!((0..64).any(|neuron_index| small_world.neighbours(neuron_index).len() > 3));
+        for neuron_index in 0..64 {
+            for other in small_world.neighbours(neuron_index) {
+                assert!(small_world.neighbours(*other).contains(&neuron_index));
+            }
+        }
+
+        let coordinates = vec![[0.0, 0.0, 0.0], [0.5, 0.0, 0.0], [0.0, 2.0, 0.0], [0.0, 2.5, 0.8]];
+        let topology = SearchTopology::Coordinates { coordinates, radius: 1.0 };
+        let neighbours = Neighbourhood::new(&topology, 4, 1);
+        assert_eq!(neighbours.neighbours(0), &[0, 1]);
+        assert_eq!(neighbours.neighbours(2), &[2, 3]);
+        assert_eq!(topology.violations(5).len(), 1);
+
+        // Duplicates of a small ring are removed, keeping the order of the offsets
+        let small_ring = Neighbourhood::new(&SearchTopology::Ring, 3, 2);
+        assert_eq!(small_ring.neighbours(0), &[1, 2, 0]);
+
+        // Built once, and again when the number of neurons or the topology changes
+        let cache = NeighbourhoodCache::default();
+        let first = cache.get(&SearchTopology::Ring, 8, 1);
+        assert!(Arc::ptr_eq(&first, &cache.get(&SearchTopology::Ring, 8, 1)));
+        assert!(Arc::ptr_eq(&first, &cache.clone().get(&SearchTopology::Ring, 8, 1)));
+        let grown = cache.get(&SearchTopology::Ring, 9, 1);
+        assert_eq!(grown.neighbours(0), &[8, 0, 1]);
+        let grid = cache.get(&SearchTopology::Grid2d { width: 3, height: 3 }, 9, 1);
+        assert_eq!(grid.neighbours(4).len(), 9);
+    }
+}
@@ -0,0 +1,291 @@
+//! Exports the topology of a network to GraphML, GEXF or DOT
+//!
+//! Every node in the network is a vertex. Every interconnection and intraconnection gives two edges:
+//! the main connection and the pending connection.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::GuardianSettings;
+use crate::cpu::interface::{NodeState, State};
+use crate::cpu::{check_is_connected, node_global_to_local_index, node_local_to_global_index};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GraphFormat {
+    GraphMl,
+    Gexf,
+    Dot,
+}
+
+impl GraphFormat {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("graphml") => Ok(Self::GraphMl),
+            Some("gexf") => Ok(Self::Gexf),
+            Some("dot" | "gv") => Ok(Self::Dot),
+            _ => bail!("Unknown graph format for {path:?}, expected .graphml, .gexf or .dot"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdgeKind {
+    Inter,
+    PendingInter,
+    Intra,
+    PendingIntra,
+}
+
+impl EdgeKind {
+    fn name(&self) -> &'static str {
+        match self {
+            EdgeKind::Inter => "inter",
+            EdgeKind::PendingInter => "pending_inter",
+            EdgeKind::Intra => "intra",
+            EdgeKind::PendingIntra => "pending_intra",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GraphNode {
+    pub neuron: usize,
+    pub node: usize,
+    pub counter: u8,  // Of the interconnection
+    pub state: NodeState,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GraphEdge {
+    pub source: usize,  // Global node index
+    pub target: usize,
+    pub kind: EdgeKind,
+    pub force_self: f32,
+    pub force_other: f32,
+    pub mutual: bool,  // Both nodes have a main connection to each other
+    pub state: Option<NodeState>,  // Only intraconnections have their own counter
+}
+
+/// Nodes are ordered by global index
+pub struct TopologyGraph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+impl TopologyGraph {
+    pub fn from_state(state: &State, g_settings: &GuardianSettings) -> Self {
+        let mut nodes = vec![];
+        let mut edges = vec![];
+        let counters = state.inter_connection_counters.iter();
+        for (((neuron, node), connection), counter) in state.inter_connections.indexed_iter().zip(counters) {
+            nodes.push(GraphNode {
+                neuron,
+                node,
+                counter: counter.get_value(),
+                state: counter.get_state(g_settings),
+            });
+
+            let source = node_local_to_global_index(neuron, node, g_settings);
+            let target = connection.get_index();
+            let (neuron_other, node_other) = node_global_to_local_index(target, g_settings);
+            let mutual = check_is_connected(source, target, &state.inter_connections[[neuron_other, node_other]]);
+            let (force_self, force_other) = connection.get_forces();
+            edges.push(GraphEdge { source, target, kind: EdgeKind::Inter, force_self, force_other, mutual, state: None });
+            let (force_self, force_other) = connection.get_pending_forces();
+            let target = connection.get_pending_index();
+            edges.push(GraphEdge { source, target, kind: EdgeKind::PendingInter, force_self, force_other, mutual: false, state: None });
+        }
+
+        let counters = state.intra_connection_counters.iter();
+        for (((neuron, node, _), connection), counter) in state.intra_connections.indexed_iter().zip(counters) {
+            let source = node_local_to_global_index(neuron, node, g_settings);
+            let target = node_local_to_global_index(neuron, connection.get_index(), g_settings);
+            let (force_self, force_other) = connection.get_forces();
+            let node_state = Some(counter.get_state(g_settings));
+            edges.push(GraphEdge { source, target, kind: EdgeKind::Intra, force_self, force_other, mutual: false, state: node_state });
+            let target = node_local_to_global_index(neuron, connection.get_pending_index(), g_settings);
+            let (force_self, force_other) = connection.get_pending_forces();
+            edges.push(GraphEdge { source, target, kind: EdgeKind::PendingIntra, force_self, force_other, mutual: false, state: node_state });
+        }
+        Self { nodes, edges }
+    }
+
+    /// The format is decided by the extension
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let format = GraphFormat::from_path(path)?;
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer, format)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W, format: GraphFormat) -> Result<()> {
+        match format {
+            GraphFormat::GraphMl => self.write_graphml(writer),
+            GraphFormat::Gexf => self.write_gexf(writer),
+            GraphFormat::Dot => self.write_dot(writer),
+        }
+    }
+
+    pub fn write_graphml<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(writer, r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#)?;
+        let keys = [
+            ("neuron", "node", "int"),
+            ("node", "node", "int"),
+            ("counter", "node", "int"),
+            ("state", "all", "string"),
+            ("kind", "edge", "string"),
+            ("force_self", "edge", "double"),
+            ("force_other", "edge", "double"),
+            ("mutual", "edge", "boolean"),
+        ];
+        for (name, domain, data_type) in keys {
+            writeln!(writer, r#"  <key id="{name}" for="{domain}" attr.name="{name}" attr.type="{data_type}"/>"#)?;
+        }
+        writeln!(writer, r#"  <graph id="network" edgedefault="directed">"#)?;
+        for (index, node) in self.nodes.iter().enumerate() {
+            writeln!(writer, r#"    <node id="n{index}">"#)?;
+            writeln!(writer, r#"      <data key="neuron">{}</data>"#, node.neuron)?;
+            writeln!(writer, r#"      <data key="node">{}</data>"#, node.node)?;
+            writeln!(writer, r#"      <data key="counter">{}</data>"#, node.counter)?;
+            writeln!(writer, r#"      <data key="state">{:?}</data>"#, node.state)?;
+            writeln!(writer, "    </node>")?;
+        }
+        for (index, edge) in self.edges.iter().enumerate() {
+            writeln!(writer, r#"    <edge id="e{index}" source="n{}" target="n{}">"#, edge.source, edge.target)?;
+            writeln!(writer, r#"      <data key="kind">{}</data>"#, edge.kind.name())?;
+            writeln!(writer, r#"      <data key="force_self">{}</data>"#, edge.force_self)?;
+            writeln!(writer, r#"      <data key="force_other">{}</data>"#, edge.force_other)?;
+            writeln!(writer, r#"      <data key="mutual">{}</data>"#, edge.mutual)?;
+            if let Some(state) = &edge.state {
+                writeln!(writer, r#"      <data key="state">{state:?}</data>"#)?;
+            }
+            writeln!(writer, "    </edge>")?;
+        }
+        writeln!(writer, "  </graph>")?;
+        writeln!(writer, "</graphml>")?;
+        Ok(())
+    }
+
+    pub fn write_gexf<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(writer, r#"<gexf xmlns="http://gexf.net/1.3" version="1.3">"#)?;
+        writeln!(writer, r#"  <graph defaultedgetype="directed">"#)?;
+        writeln!(writer, r#"    <attributes class="node">"#)?;
+        writeln!(writer, r#"      <attribute id="0" title="neuron" type="integer"/>"#)?;
+        writeln!(writer, r#"      <attribute id="1" title="node" type="integer"/>"#)?;
+        writeln!(writer, r#"      <attribute id="2" title="counter" type="integer"/>"#)?;
+        writeln!(writer, r#"      <attribute id="3" title="state" type="string"/>"#)?;
+        writeln!(writer, "    </attributes>")?;
+        writeln!(writer, r#"    <attributes class="edge">"#)?;
+        writeln!(writer, r#"      <attribute id="0" title="kind" type="string"/>"#)?;
+        writeln!(writer, r#"      <attribute id="1" title="force_self" type="double"/>"#)?;
+        writeln!(writer, r#"      <attribute id="2" title="force_other" type="double"/>"#)?;
+        writeln!(writer, r#"      <attribute id="3" title="mutual" type="boolean"/>"#)?;
+        writeln!(writer, r#"      <attribute id="4" title="state" type="string"/>"#)?;
+        writeln!(writer, "    </attributes>")?;
+        writeln!(writer, "    <nodes>")?;
+        for (index, node) in self.nodes.iter().enumerate() {
+            writeln!(writer, r#"      <node id="{index}" label="{}:{}">"#, node.neuron, node.node)?;
+            writeln!(
+                writer,
+                r#"        <attvalues><attvalue for="0" value="{}"/><attvalue for="1" value="{}"/><attvalue for="2" value="{}"/><attvalue for="3" value="{:?}"/></attvalues>"#,
+                node.neuron, node.node, node.counter, node.state
+            )?;
+            writeln!(writer, "      </node>")?;
+        }
+        writeln!(writer, "    </nodes>")?;
+        writeln!(writer, "    <edges>")?;
+        for (index, edge) in self.edges.iter().enumerate() {
+            // Weight must be positive in most tools
+            let weight = (edge.force_self + edge.force_other + 2.0) / 4.0;
+            writeln!(writer, r#"      <edge id="{index}" source="{}" target="{}" weight="{weight}">"#, edge.source, edge.target)?;
+            let state = edge.state.as_ref().map(|state| format!("{state:?}")).unwrap_or_default();
+            writeln!(
+                writer,
+                r#"        <attvalues><attvalue for="0" value="{}"/><attvalue for="1" value="{}"/><attvalue for="2" value="{}"/><attvalue for="3" value="{}"/><attvalue for="4" value="{state}"/></attvalues>"#,
+                edge.kind.name(), edge.force_self, edge.force_other, edge.mutual
+            )?;
+            writeln!(writer, "      </edge>")?;
+        }
+        writeln!(writer, "    </edges>")?;
+        writeln!(writer, "  </graph>")?;
+        writeln!(writer, "</gexf>")?;
+        Ok(())
+    }
+
+    /// Nodes are clustered per neuron
+    pub fn write_dot<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writeln!(writer, "digraph network {{")?;
+        let mut current_neuron = None;
+        for (index, node) in self.nodes.iter().enumerate() {
+            if current_neuron != Some(node.neuron) {
+                if current_neuron.is_some() {
+                    writeln!(writer, "  }}")?;
+                }
+                writeln!(writer, "  subgraph cluster_{} {{", node.neuron)?;
+                writeln!(writer, "    label=\"neuron {}\";", node.neuron)?;
+                current_neuron = Some(node.neuron);
+            }
+            writeln!(
+                writer,
+                "    n{index} [label=\"{}:{}\", counter={}, state=\"{:?}\"];",
+                node.neuron, node.node, node.counter, node.state
+            )?;
+        }
+        if current_neuron.is_some() {
+            writeln!(writer, "  }}")?;
+        }
+        for edge in self.edges.iter() {
+            let style = match edge.kind {
+                EdgeKind::Inter | EdgeKind::Intra => "solid",
+                EdgeKind::PendingInter | EdgeKind::PendingIntra => "dashed",
+            };
+            write!(
+                writer,
+                "  n{} -> n{} [kind=\"{}\", force_self={}, force_other={}, mutual={}, style={style}",
+                edge.source, edge.target, edge.kind.name(), edge.force_self, edge.force_other, edge.mutual
+            )?;
+            if let Some(state) = &edge.state {
+                write!(writer, ", state=\"{state:?}\"")?;
+            }
+            writeln!(writer, "];")?;
+        }
+        writeln!(writer, "}}")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::cpu::interface::Network;
+    use crate::settings::Settings;
+    use super::*;
+
+    #[test]
+    pub fn test_export() {
+        let settings = Settings::preset("tiny").unwrap();
+        let network = Network::new(&settings.g_settings, &settings.n_settings, 1);
+        let graph = TopologyGraph::from_state(&network.state, &network.g_settings);
+        let g_settings = &settings.g_settings;
+        let n_nodes = settings.n_settings.n_neurons * g_settings.n_nodes_per_neuron;
+        assert_eq!(graph.nodes.len(), n_nodes);
+        assert_eq!(graph.edges.len(), 2 * n_nodes + 2 * n_nodes * g_settings.n_intraconnections_per_node);
+
+        for format in [GraphFormat::GraphMl, GraphFormat::Gexf, GraphFormat::Dot] {
+            let mut buffer = vec![];
+            graph.write(&mut buffer, format).unwrap();
+            let text = String::from_utf8(buffer).unwrap();
+            let n_edges = match format {
+                GraphFormat::GraphMl | GraphFormat::Gexf => text.matches("<edge ").count(),
+                GraphFormat::Dot => text.matches(" -> ").count(),
+            };
+            assert_eq!(n_edges, graph.edges.len(), "{format:?}");
+        }
+    }
+}
@@ -1,11 +1,14 @@
 use std::fmt::Debug;
+use std::path::Path;
 
+use anyhow::{ensure, Context, Result};
 use regex::Regex;
 use ndarray::{Array, Dimension};
 
-use crate::{GuardianSettings, NetworkSettings};
-use crate::cpu::interface::State;
+use crate::GuardianSettings;
+use crate::cpu::interface::{CounterInterConnection, CounterIntraConnection, NodeState};
 use crate::cpu::node_global_to_local_index;
+use crate::visualization::recorder::{Recording, StepRecord};
 
 pub fn add_param<T: Debug>(js_string: &mut String, variable: &str, data: T) {
     js_string.push_str(&format!("const {variable} = {:?};\n", data).replace('"', "").replace('\\', ""));
@@ -24,118 +27,151 @@ where
     arr
 }
 
-pub fn visualize_network(state_history: Vec<State>, g_settings: &GuardianSettings, n_settings: &NetworkSettings) {
+/// Writes data.js and one script per recorded step to output_dir, which are read by graph.html
+///
+/// data.js only has the sizes and the timeline. graph.html loads steps/<index>.js when a step is shown, and the
+/// records are read from the recording one at a time, so neither side has the whole recording in memory
+pub fn visualize_recording(recording: &Recording, output_dir: &Path) -> Result<()> {
+    let header = &recording.header;
+    let g_settings = &header.g_settings;
+    let n_neurons = header.n_settings.n_neurons;
+    ensure!(header.neurons.iter().copied().eq(0..n_neurons), "graph.html needs a recording of every neuron");
+
     let mut data = String::new();
     // Params
-    add_param(&mut data, "n_neurons", n_settings.n_neurons);
+    add_param(&mut data, "n_neurons", n_neurons);
     add_param(&mut data, "n_nodes_per_neuron", g_settings.n_nodes_per_neuron);
     add_param(&mut data, "node_size", g_settings.node_size);
     add_param(&mut data, "neuron_state_size", g_settings.neuron_state_size);
+    add_param(&mut data, "include_states", header.include_states);
 
     // Timeline
-    let timeline: Vec<usize> = (0..state_history.len()).into_iter().collect();
+    let timeline: Vec<usize> = (0..recording.len()).collect();
     add_param(&mut data, "timesteps", timeline);
+    std::fs::write(output_dir.join("data.js"), data).with_context(|| format!("Unable to write data.js to {output_dir:?}"))?;
 
-    // Add links
-    let mut interconnections = vec![];
-    let mut pending_interconnections = vec![];
-    let mut intraconnections = vec![];
-    let mut pending_intraconnections = vec![];
-    let mut interconnection_counters = vec![];
-    let mut intraconnection_counters = vec![];
-    for state in state_history.iter() {
-
-        // Add interconnections
-        let connections = state.inter_connections.indexed_iter().map(|((neuron, node), connection)| {
-            let node_b_global_index = connection.get_index();
-            let (target_neuron, target_node) = node_global_to_local_index(node_b_global_index, g_settings);
-            let (force_self, force_other) = connection.get_raw_force_values();
-            format!("{{ source: [{neuron}, {node}], target: [{target_neuron}, {target_node}], force_self: {force_self}, force_other: {force_other} }}")
-        })
-        .collect();
-        let connections = Array::from_shape_vec(state.inter_connections.shape(), connections).unwrap();
-        let connections = arr_to_string(&connections);
-        interconnections.push(connections);
-
-        // Add pending interconnections
-        let connections = state.inter_connections.indexed_iter().map(|((neuron, node), connection)| {
-            let node_b_global_index = connection.get_pending_index();
-            let (target_neuron, target_node) = node_global_to_local_index(node_b_global_index, g_settings);
-            let (force_self, force_other) = connection.get_raw_pending_force_values();
-            format!("{{ source: [{neuron}, {node}], target: [{target_neuron}, {target_node}], force_self: {force_self}, force_other: {force_other} }}")
-        })
-        .collect();
-        let connections = Array::from_shape_vec(state.inter_connections.shape(), connections).unwrap();
-        let connections = arr_to_string(&connections);
-        pending_interconnections.push(connections);
-
-        // Add intraconnections
-        let connections = state.intra_connections.indexed_iter().map(|((neuron, node, _), connection)| {
-            let target_node = connection.get_index();
-            let (force_self, force_other) = connection.get_raw_force_values();
-            format!("{{ source: [{neuron}, {node}], target: [{neuron}, {target_node}], force_self: {force_self}, force_other: {force_other} }}")
+    let steps_dir = output_dir.join("steps");
+    std::fs::create_dir_all(&steps_dir)?;
+    for (index, record) in recording.iter().enumerate() {
+        let step = step_to_js(index, &record?, g_settings, n_neurons)?;
+        std::fs::write(steps_dir.join(format!("{index}.js")), step)?;
+    }
+    Ok(())
+}
+
+/// A script that hands the step to add_step in graph.html
+fn step_to_js(index: usize, record: &StepRecord, g_settings: &GuardianSettings, n_neurons: usize) -> Result<String> {
+    let n_nodes = g_settings.n_nodes_per_neuron;
+    let n_intra = g_settings.n_intraconnections_per_node;
+    let link = |(neuron, node): (usize, usize), (target_neuron, target_node): (usize, usize), (force_self, force_other): (i8, i8)| {
+        format!("{{ source: [{neuron}, {node}], target: [{target_neuron}, {target_node}], force_self: {force_self}, force_other: {force_other} }}")
+    };
+
+    // Interconnections, main and pending
+    let inter_links = |pending: bool| -> Result<String> {
+        let connections = record.inter_connections.iter().enumerate().map(|(node_global_index, connection)| {
+            let (target, forces) = match pending {
+                false => (connection.index, connection.forces),
+                true => (connection.pending_index, connection.pending_forces),
+            };
+            let source = node_global_to_local_index(node_global_index, g_settings);
+            link(source, node_global_to_local_index(target as usize, g_settings), forces)
         })
         .collect();
-        let connections = Array::from_shape_vec(state.intra_connections.shape(), connections).unwrap();
-        let connections = arr_to_string(&connections);
-        intraconnections.push(connections);
-
-        // Add pending interconnections
-        let connections = state.intra_connections.indexed_iter().map(|((neuron, node, _), connection)| {
-            let target_node = connection.get_pending_index();
-            let (force_self, force_other) = connection.get_raw_pending_force_values();
-            format!("{{ source: [{neuron}, {node}], target: [{neuron}, {target_node}], force_self: {force_self}, force_other: {force_other} }}")
+        Ok(arr_to_string(&Array::from_shape_vec((n_neurons, n_nodes), connections)?))
+    };
+
+    // Intraconnections, main and pending
+    let intra_links = |pending: bool| -> Result<String> {
+        let connections = record.intra_connections.iter().enumerate().map(|(index, connection)| {
+            let (target, forces) = match pending {
+                false => (connection.get_index(), connection.get_raw_force_values()),
+                true => (connection.get_pending_index(), connection.get_raw_pending_force_values()),
+            };
+            let (neuron, node) = node_global_to_local_index(index / n_intra, g_settings);
+            link((neuron, node), (neuron, target), forces)
         })
         .collect();
-        let connections = Array::from_shape_vec(state.intra_connections.shape(), connections).unwrap();
-        let connections = arr_to_string(&connections);
-        pending_intraconnections.push(connections);
-
-        // Add interconnections counters
-        let counters = state.inter_connection_counters.indexed_iter().map(|((_, _), counter)| {
-            let value = counter.get_value();
-            let state = counter.get_state(g_settings);
-            format!("{{ value: {value}, state: '{state:?}' }}")
-        })
+        Ok(arr_to_string(&Array::from_shape_vec((n_neurons, n_nodes, n_intra), connections)?))
+    };
+
+    // Counters
+    let counter = |value: u8, state: NodeState| format!("{{ value: {value}, state: '{state:?}' }}");
+    let interconnection_counters = record.inter_connections.iter()
+        .map(|connection| counter(connection.counter, CounterInterConnection::from_value(connection.counter).get_state(g_settings)))
         .collect();
-        let counters = Array::from_shape_vec(state.inter_connection_counters.shape(), counters).unwrap();
-        let counters = arr_to_string(&counters);
-        interconnection_counters.push(counters);
-
-        // Add intraconnections counters
-        let counters = state.intra_connection_counters.indexed_iter().map(|((_, _, _), counter)| {
-            let value = counter.get_value();
-            let state = counter.get_state(g_settings);
-            format!("{{ value: {value}, state: '{state:?}' }}")
-        })
+    let interconnection_counters = Array::from_shape_vec((n_neurons, n_nodes), interconnection_counters)?;
+    let intraconnection_counters = record.intra_connection_counters.iter()
+        .map(|value| counter(*value, CounterIntraConnection::from_value(*value).get_state(g_settings)))
         .collect();
-        let counters = Array::from_shape_vec(state.intra_connection_counters.shape(), counters).unwrap();
-        let counters = arr_to_string(&counters);
-        intraconnection_counters.push(counters);
+    let intraconnection_counters = Array::from_shape_vec((n_neurons, n_nodes, n_intra), intraconnection_counters)?;
+
+    // States, null if not recorded
+    let node_states = match &record.nodes {
+        Some(nodes) => arr_to_string(&Array::from_shape_vec((n_neurons, n_nodes, g_settings.node_size), nodes.clone())?),
+        None => "null".to_string(),
+    };
+    let neuron_states = match &record.neuron_states {
+        Some(states) => arr_to_string(&Array::from_shape_vec((n_neurons, g_settings.neuron_state_size), states.clone())?),
+        None => "null".to_string(),
+    };
+
+    let mut step = format!("add_step({index}, {{\n");
+    for (name, value) in [
+        ("interconnections", inter_links(false)?),
+        ("pending_interconnections", inter_links(true)?),
+        ("intraconnections", intra_links(false)?),
+        ("pending_intraconnections", intra_links(true)?),
+        ("interconnection_counters", arr_to_string(&interconnection_counters)),
+        ("intraconnection_counters", arr_to_string(&intraconnection_counters)),
+        ("node_states", node_states),
+        ("neuron_states", neuron_states),
+    ] {
+        step.push_str(&format!("    {name}: {value},\n"));
     }
+    step.push_str("});\n");
+    Ok(step.replace(['"', '\\'], ""))
+}
 
-    add_param(&mut data, "interconnections", interconnections);
-    add_param(&mut data, "pending_interconnections", pending_interconnections);
-    add_param(&mut data, "intraconnections", intraconnections);
-    add_param(&mut data, "pending_intraconnections", pending_intraconnections);
-    add_param(&mut data, "interconnection_counters", interconnection_counters);
-    add_param(&mut data, "intraconnection_counters", intraconnection_counters);
-
-    // Add states
-    let mut node_states = vec![];
-    let mut neuron_states = vec![];
-    for state in state_history.iter() {
-        let js_arr = arr_to_string(&state.nodes);
-        node_states.push(js_arr);
-
-        let js_arr = arr_to_string(&state.neuron_states);
-        neuron_states.push(js_arr);
+#[cfg(test)]
+pub mod tests {
+    use rayon::ThreadPoolBuilder;
+
+    use crate::cpu::interface::Network;
+    use crate::cpu::process::update;
+    use crate::settings::Settings;
+    use crate::visualization::recorder::{Recorder, RecorderSettings};
+    use crate::visualization::recorder::tests::unique_temp_dir;
+    use super::*;
+
+    #[test]
+    pub fn test_visualize_recording() {
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let settings = Settings::preset("tiny").unwrap();
+        let mut network = Network::new(&settings.g_settings, &settings.n_settings, 1);
+        let path = unique_temp_dir("graph");
+        let r_settings = RecorderSettings { include_states: true, ..Default::default() };
+        let mut recorder = Recorder::create(path.join("recording"), &r_settings, &network.g_settings, &network.n_settings).unwrap();
+        for step in 0..3 {
+            update(&mut network, &pool);
+            recorder.record(step, &network.state).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        visualize_recording(&Recording::open(path.join("recording")).unwrap(), &path).unwrap();
+        let data = std::fs::read_to_string(path.join("data.js")).unwrap();
+        assert!(data.contains("const timesteps = [0, 1, 2];"), "{data}");
+        assert!(!data.contains("source"));  // The steps are only in their own scripts
+        let step = std::fs::read_to_string(path.join("steps").join("2.js")).unwrap();
+        assert!(step.starts_with("add_step(2, {"));
+        assert!(step.contains("node_states: [[["));
+        assert!(!step.contains('"'));
+
+        // graph.html indexes the connections by neuron, so a part of the network can not be shown
+        let r_settings = RecorderSettings { neurons: Some(vec![1]), ..Default::default() };
+        let recorder = Recorder::create(path.join("partial"), &r_settings, &network.g_settings, &network.n_settings).unwrap();
+        recorder.finish().unwrap();
+        assert!(visualize_recording(&Recording::open(path.join("partial")).unwrap(), &path).is_err());
+        std::fs::remove_dir_all(&path).unwrap();
     }
-
-    add_param(&mut data, "node_states", node_states);
-    add_param(&mut data, "neuron_states", neuron_states);
-
-    // Finalize
-    std::fs::write("data.js", data).expect("Unable to write file");
 }
-
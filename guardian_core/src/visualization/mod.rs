@@ -1,4 +1,6 @@
+pub mod export;
 pub mod graph;
+pub mod recorder;
 
 
 // TODO: https://docs.rs/ipgeolocate/latest/ipgeolocate/ with https://echarts.apache.org/examples/en/editor.html?c=lines3d-flights-gl&gl=1
\ No newline at end of file
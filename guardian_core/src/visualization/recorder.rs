@@ -0,0 +1,270 @@
+//! Records the connections of a network to disk while it runs
+//!
+//! Files in the recording directory:
+//! * header.json: Settings, sampling interval and the recorded neurons
+//! * steps.bin: One record per sampled step. Each record is a u32 (little endian) length followed by the record (bincode)
+//!
+//! Only one record is in memory at a time, both when recording and viewing.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context, Result};
+use ndarray::Axis;
+use serde::{Deserialize, Serialize};
+
+use crate::{GuardianSettings, NetworkSettings};
+use crate::cpu::interface::{IntraConnection, State};
+
+const HEADER_FILE: &str = "header.json";
+const STEPS_FILE: &str = "steps.bin";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecorderSettings {
+    pub interval: usize,  // Record every n:th step
+    pub neurons: Option<Vec<usize>>,  // None records all neurons
+    pub include_states: bool,  // Also record nodes and neuron states
+}
+
+impl Default for RecorderSettings {
+    fn default() -> Self {
+        Self {
+            interval: 1,
+            neurons: None,
+            include_states: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordingHeader {
+    pub g_settings: GuardianSettings,
+    pub n_settings: NetworkSettings,
+    pub interval: usize,
+    pub neurons: Vec<usize>,
+    pub include_states: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InterConnectionRecord {
+    pub index: u32,
+    pub pending_index: u32,
+    pub forces: (i8, i8),
+    pub pending_forces: (i8, i8),
+    pub counter: u8,
+}
+
+/// Everything is ordered as the recorded neurons, then by node
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StepRecord {
+    pub step: usize,
+    pub inter_connections: Vec<InterConnectionRecord>,  // Per node
+    pub intra_connections: Vec<IntraConnection>,  // Per node and intraconnection
+    pub intra_connection_counters: Vec<u8>,
+    pub nodes: Option<Vec<u8>>,
+    pub neuron_states: Option<Vec<u8>>,
+}
+
+pub struct Recorder {
+    header: RecordingHeader,
+    writer: BufWriter<File>,
+    n_recorded: usize,
+}
+
+impl Recorder {
+    /// Creates the directory if needed. An existing recording in it is replaced
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        r_settings: &RecorderSettings,
+        g_settings: &GuardianSettings,
+        n_settings: &NetworkSettings
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        ensure!(r_settings.interval > 0, "The interval must be at least 1");
+        let neurons = r_settings.neurons.clone().unwrap_or_else(|| (0..n_settings.n_neurons).collect());
+        ensure!(
+            neurons.iter().all(|neuron_index| *neuron_index < n_settings.n_neurons),
+            "Recorded neurons must be below {}", n_settings.n_neurons
+        );
+
+        std::fs::create_dir_all(path).with_context(|| format!("Unable to create recording {path:?}"))?;
+        let header = RecordingHeader {
+            g_settings: g_settings.clone(),
+            n_settings: n_settings.clone(),
+            interval: r_settings.interval,
+            neurons,
+            include_states: r_settings.include_states,
+        };
+        std::fs::write(path.join(HEADER_FILE), serde_json::to_vec_pretty(&header)?)?;
+        let file = File::create(path.join(STEPS_FILE))?;
+        Ok(Self { header, writer: BufWriter::new(file), n_recorded: 0 })
+    }
+
+    /// Records the state if the step is sampled. Returns true if recorded
+    pub fn record(&mut self, step: usize, state: &State) -> Result<bool> {
+        if !step.is_multiple_of(self.header.interval) {
+            return Ok(false);
+        }
+        let record = StepRecord::new(step, state, &self.header);
+        let bytes = bincode::serialize(&record)?;
+        self.writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&bytes)?;
+        self.n_recorded += 1;
+        Ok(true)
+    }
+
+    pub fn n_recorded(&self) -> usize {
+        self.n_recorded
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl StepRecord {
+    fn new(step: usize, state: &State, header: &RecordingHeader) -> Self {
+        let mut inter_connections = vec![];
+        let mut intra_connections = vec![];
+        let mut intra_connection_counters = vec![];
+        let mut nodes = vec![];
+        let mut neuron_states = vec![];
+        for neuron_index in header.neurons.iter().copied() {
+            let counters = state.inter_connection_counters.row(neuron_index);
+            for (connection, counter) in state.inter_connections.row(neuron_index).iter().zip(counters) {
+                inter_connections.push(InterConnectionRecord {
+                    index: connection.get_index() as u32,
+                    pending_index: connection.get_pending_index() as u32,
+                    forces: connection.get_raw_force_values(),
+                    pending_forces: connection.get_raw_pending_force_values(),
+                    counter: counter.get_value(),
+                });
+            }
+            let neuron_intra_connections = state.intra_connections.index_axis(Axis(0), neuron_index);
+            intra_connections.extend(neuron_intra_connections.iter().cloned());
+            let neuron_intra_counters = state.intra_connection_counters.index_axis(Axis(0), neuron_index);
+            intra_connection_counters.extend(neuron_intra_counters.iter().map(|counter| counter.get_value()));
+            if header.include_states {
+                nodes.extend(state.nodes.index_axis(Axis(0), neuron_index).iter());
+                neuron_states.extend(state.neuron_states.row(neuron_index).iter());
+            }
+        }
+        Self {
+            step,
+            inter_connections,
+            intra_connections,
+            intra_connection_counters,
+            nodes: header.include_states.then_some(nodes),
+            neuron_states: header.include_states.then_some(neuron_states),
+        }
+    }
+}
+
+/// Reads a recording lazily. Opening only reads the header and the lengths of the records
+pub struct Recording {
+    pub header: RecordingHeader,
+    path: PathBuf,
+    offsets: Vec<(u64, u32)>,  // Position and length of each record
+}
+
+impl Recording {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let header = std::fs::read(path.join(HEADER_FILE)).with_context(|| format!("No recording in {path:?}"))?;
+        let header: RecordingHeader = serde_json::from_slice(&header).context("Invalid recording header")?;
+
+        let mut reader = BufReader::new(File::open(path.join(STEPS_FILE))?);
+        let file_length = reader.get_ref().metadata()?.len();
+        let mut offsets = vec![];
+        let mut position = 0;
+        let mut buffer = [0u8; 4];
+        while position + 4 <= file_length {
+            reader.read_exact(&mut buffer)?;
+            let length = u32::from_le_bytes(buffer);
+            position += 4;
+            if position + length as u64 > file_length {
+                break;  // Last record was not fully written
+            }
+            offsets.push((position, length));
+            reader.seek_relative(length as i64)?;
+            position += length as u64;
+        }
+        Ok(Self { header, path, offsets })
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Reads the index:th record from disk
+    pub fn get(&self, index: usize) -> Result<StepRecord> {
+        let (position, length) = *self.offsets.get(index).with_context(|| format!("Record {index} out of range"))?;
+        let mut file = File::open(self.path.join(STEPS_FILE))?;
+        file.seek(SeekFrom::Start(position))?;
+        let mut bytes = vec![0u8; length as usize];
+        file.read_exact(&mut bytes)?;
+        Ok(bincode::deserialize(&bytes)?)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Result<StepRecord>> + '_ {
+        (0..self.len()).map(|index| self.get(index))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use rayon::ThreadPoolBuilder;
+
+    use crate::cpu::interface::Network;
+    use crate::cpu::process::update;
+    use crate::settings::Settings;
+    use super::*;
+
+    /// A new directory in the temp dir, which no other test or test run uses
+    pub fn unique_temp_dir(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("guardian_test_{name}_{}_{count}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    pub fn test_recorder() {
+        let pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let settings = Settings::preset("tiny").unwrap();
+        let mut network = Network::new(&settings.g_settings, &settings.n_settings, 1);
+        let path = unique_temp_dir("recorder");
+        let r_settings = RecorderSettings {
+            interval: 2,
+            neurons: Some(vec![1, 3]),
+            include_states: true,
+        };
+
+        let mut recorder = Recorder::create(&path, &r_settings, &network.g_settings, &network.n_settings).unwrap();
+        for step in 0..5 {
+            update(&mut network, &pool);
+            recorder.record(step, &network.state).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let recording = Recording::open(&path).unwrap();
+        assert_eq!(recording.len(), 3);
+        let record = recording.get(2).unwrap();
+        assert_eq!(record.step, 4);
+        let g_settings = &settings.g_settings;
+        assert_eq!(record.inter_connections.len(), 2 * g_settings.n_nodes_per_neuron);
+        assert_eq!(record.intra_connections.len(), 2 * g_settings.n_nodes_per_neuron * g_settings.n_intraconnections_per_node);
+        assert_eq!(record.inter_connections[0].index as usize, network.state.inter_connections[[1, 0]].get_index());
+        assert_eq!(record.neuron_states.unwrap().len(), 2 * g_settings.neuron_state_size);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}